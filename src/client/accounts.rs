@@ -0,0 +1,207 @@
+use crate::client::Client;
+use crate::config::DnsblAction;
+use crate::proto::command::Command;
+use crate::proto::reply::Reply;
+use crate::server::accounts::AccountError;
+use log::error;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Attempts at finding a free guest nick before giving up on renaming a client.
+const GUEST_ATTEMPTS: usize = 10;
+/// Wrong passwords a connection may give before it is disconnected.
+const MAX_LOGIN_FAILURES: u32 = 5;
+
+impl Client {
+    /// How RPL_LOGGEDIN and RPL_LOGGEDOUT show the client.
+    async fn account_mask(&self) -> String {
+        match self.server.state().prefix(&self.uuid).await {
+            Some(prefix) => prefix.to_string().trim_start_matches(':').to_owned(),
+            None => {
+                let nick = if self.nick.is_empty() { "*" } else { &self.nick };
+                let user = if self.username.is_empty() { "*" } else { &self.username };
+                format!("{}!{}@{}", nick, user, self.hostname)
+            }
+        }
+    }
+
+    pub(super) async fn log_in(&mut self, account: String) {
+        let mask = self.account_mask().await;
+        self.server.state().set_account(&self.uuid, Some(account.clone()));
+        let _ = self.send(Reply::LoggedIn(mask, account.clone()));
+        self.account = Some(account);
+        self.check_nick();
+    }
+
+    pub(super) async fn log_out(&mut self) {
+        let mask = self.account_mask().await;
+        self.server.state().set_account(&self.uuid, None);
+        let _ = self.send(Reply::LoggedOut(mask));
+        self.account = None;
+        self.check_nick();
+    }
+
+    /// Picks up being logged out by another client, which dropped the account.
+    pub(super) fn sync_account(&mut self) {
+        if self.uuid.is_nil() || self.account.is_none() {
+            return;
+        }
+        if self.server.state().account(&self.uuid).is_none() {
+            self.account = None;
+            self.check_nick();
+        }
+    }
+
+    /// The account `name` if `password` is its password. Deriving the key takes a while,
+    /// so it happens on a blocking thread.
+    pub(super) async fn check_password(&mut self, name: &str, password: &str) -> Option<String> {
+        let server = self.server.clone();
+        let (name, password) = (name.to_owned(), password.to_owned());
+        let check = move || server.accounts().authenticate(&name, &password);
+        let account = tokio::task::spawn_blocking(check).await.unwrap_or(None);
+        if account.is_none() {
+            self.login_failed();
+        }
+        account
+    }
+
+    /// Counts a failed login, the client is disconnected once there were too many.
+    pub(super) fn login_failed(&mut self) {
+        self.login_failures += 1;
+        if self.login_failures >= MAX_LOGIN_FAILURES {
+            self.rejected = Some("Too many failed login attempts".to_owned());
+        }
+    }
+
+    /// Tells a client what went wrong with an account, without details of the storage.
+    pub(super) fn account_failure(e: AccountError) -> String {
+        match e {
+            AccountError::Storage { .. } => {
+                error!("Failed to store accounts: {}", e);
+                "Accounts can't be changed right now, try again later".to_owned()
+            }
+            e => e.to_string(),
+        }
+    }
+
+    /// Starts the grace period when a registered client uses a nick that belongs to an
+    /// account it isn't logged in to, and ends it once that is no longer the case.
+    pub(super) fn check_nick(&mut self) {
+        if self.uuid.is_nil() {
+            return;
+        }
+        let owner = match self.server.accounts().owner(&self.nick) {
+            Some(owner) if self.account.as_ref() != Some(&owner) => owner,
+            _ => {
+                self.nick_deadline = None;
+                return;
+            }
+        };
+        if self.nick_deadline.is_some() {
+            return;
+        }
+        let grace = self.server.registration().grace_period();
+        self.nick_deadline = Some(Instant::now() + grace);
+        self.nickserv_notice(format!(
+            "{} is registered to {}. Identify with /msg NickServ IDENTIFY <password> within {} seconds or your nick will be changed.",
+            self.nick,
+            owner,
+            grace.as_secs()
+        ));
+    }
+
+    /// Renames a client whose grace period ran out without it identifying.
+    pub(super) async fn enforce_nick(&mut self) {
+        self.nick_deadline = None;
+        let old = self.nick.clone();
+        for _ in 0..GUEST_ATTEMPTS {
+            let guest = format!("Guest{}", Uuid::new_v4().as_u128() % 100000);
+            if self.server.accounts().owner(&guest).is_some() {
+                continue;
+            }
+            if self.change_nick(guest.clone()).await.is_ok() {
+                self.nickserv_notice(format!(
+                    "You didn't identify for {}, your nick has been changed to {}.",
+                    old, guest
+                ));
+                return;
+            }
+        }
+    }
+
+    /// NICK once registered.
+    pub(super) async fn change_nick(&mut self, nick: String) -> Result<(), Reply> {
+        self.server.state().change_nick(&self.uuid, &nick).await?;
+        self.nick = nick;
+        self.nick_deadline = None;
+        self.check_nick();
+        Ok(())
+    }
+
+    fn send_fail(&self, command: &str, code: &str, context: &str, description: &str) {
+        let _ = self.send(Command::Fail(command, code, vec![context], description));
+    }
+
+    /// REGISTER from draft/account-registration, which logs the client in to the new
+    /// account. Connecting first isn't required, there's no email verification.
+    pub async fn handle_register_message(
+        &mut self,
+        account: String,
+        email: String,
+        password: String,
+    ) -> Result<(), Reply> {
+        let registration = self.server.registration();
+        let listed = self
+            .dnsbl
+            .as_ref()
+            .is_some_and(|hit| hit.action == DnsblAction::RequireSasl);
+        if !registration.enabled || listed {
+            let reason = "Account registration is not available";
+            self.send_fail("REGISTER", "TEMPORARILY_UNAVAILABLE", &account, reason);
+            return Ok(());
+        }
+        if let Some(current) = &self.account {
+            let reason = format!("You are already logged in as {}", current);
+            self.send_fail("REGISTER", "ALREADY_AUTHENTICATED", &account, &reason);
+            return Ok(());
+        }
+        let name = match account.as_str() {
+            "*" if self.nick.is_empty() => {
+                self.send_fail("REGISTER", "NEED_NICK", "*", "Send NICK first or name the account");
+                return Ok(());
+            }
+            "*" => self.nick.clone(),
+            _ => account,
+        };
+        if password.len() < registration.min_password {
+            let reason = format!("Passwords need at least {} characters", registration.min_password);
+            self.send_fail("REGISTER", "WEAK_PASSWORD", &name, &reason);
+            return Ok(());
+        }
+        let email = Some(email).filter(|email| email != "*");
+        match self.server.accounts().register(&name, &password, email).await {
+            Ok(name) => {
+                self.log_in(name.clone()).await;
+                let _ = self.send(Command::Register("SUCCESS", &name, "Account created"));
+            }
+            Err(e @ AccountError::Exists(_)) => {
+                self.send_fail("REGISTER", "ACCOUNT_EXISTS", &name, &e.to_string());
+            }
+            Err(e @ AccountError::InvalidName(_)) => {
+                self.send_fail("REGISTER", "BAD_ACCOUNT_NAME", &name, &e.to_string());
+            }
+            Err(e) => {
+                let reason = Self::account_failure(e);
+                self.send_fail("REGISTER", "TEMPORARILY_UNAVAILABLE", &name, &reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Registrations never wait for verification, so there's no code to accept.
+    pub async fn handle_verify_message(&mut self, account: String) -> Result<(), Reply> {
+        let reason = "No registration is waiting for verification";
+        self.send_fail("VERIFY", "INVALID_CODE", &account, reason);
+        Ok(())
+    }
+}
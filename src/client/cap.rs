@@ -0,0 +1,163 @@
+use crate::client::Client;
+use crate::proto::command::Command;
+use crate::proto::reply::Reply;
+use crate::server::caps::{self, Capability, CAP_302};
+
+/// Longest line the server sends, without the trailing CRLF.
+const MAX_LINE: usize = 510;
+
+/// What a connection negotiated with CAP.
+#[derive(Debug, Default)]
+pub struct ClientCaps {
+    /// The version given with CAP LS, 0 if there was none.
+    version: u32,
+    /// Set by CAP LS or REQ before registration, which then waits for CAP END.
+    negotiating: bool,
+    enabled: Vec<&'static str>,
+}
+
+impl ClientCaps {
+    pub fn negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(&cap)
+    }
+
+    fn enable(&mut self, cap: &'static str) {
+        if !self.has(cap) {
+            self.enabled.push(cap);
+        }
+    }
+}
+
+impl Client {
+    /// Capabilities on offer to this connection.
+    fn capabilities(&self) -> Vec<Capability> {
+        let starttls = self.starttls.is_some() && self.tls.is_none();
+        self.server
+            .caps()
+            .list()
+            .into_iter()
+            .filter(|cap| cap.name != caps::TLS || starttls)
+            .collect()
+    }
+
+    fn cap_target(&self) -> String {
+        if self.nick.is_empty() {
+            "*".to_owned()
+        } else {
+            self.nick.clone()
+        }
+    }
+
+    /// Copies the enabled capabilities to the server state, where other clients' output
+    /// is formatted.
+    pub(super) fn sync_caps(&self) {
+        if !self.uuid.is_nil() {
+            let state = self.server.state();
+            state.set_caps(&self.uuid, &self.caps.enabled, self.caps.version);
+        }
+    }
+
+    /// Sends `caps` as the reply to `subcommand`. Clients that negotiated 302 get long
+    /// lists split over several lines, all but the last marked with `*`.
+    fn send_cap_list(&self, subcommand: &str, caps: Vec<String>) {
+        let target = self.cap_target();
+        if self.caps.version < CAP_302 {
+            let _ = self.send(Command::Cap(Some(target), subcommand.to_owned(), Some(caps.join(" "))));
+            return;
+        }
+        let overhead = format!(":{} CAP {} {} * :", self.server.name(), target, subcommand).len();
+        let mut lines = caps::split_list(&caps, MAX_LINE - overhead);
+        let last = lines.pop().unwrap_or_default();
+        for line in lines {
+            let _ = self.send(Command::CapContinued(target.clone(), subcommand.to_owned(), line));
+        }
+        let _ = self.send(Command::Cap(Some(target), subcommand.to_owned(), Some(last)));
+    }
+
+    /// Enables or, prefixed with `-`, disables every capability in `request`. Nothing is
+    /// changed unless all of them can be.
+    fn request_caps(&mut self, request: &str) -> bool {
+        let offered = self.capabilities();
+        let mut changes = Vec::new();
+        for cap in request.split_whitespace() {
+            let (enable, name) = match cap.strip_prefix('-') {
+                Some(name) => (false, name),
+                None => (true, cap),
+            };
+            // 302 implies cap-notify, it can't be turned off.
+            let implied = self.caps.version >= CAP_302;
+            if !enable && implied && name.eq_ignore_ascii_case(caps::CAP_NOTIFY) {
+                return false;
+            }
+            match offered.iter().find(|known| known.name.eq_ignore_ascii_case(name)) {
+                Some(known) => changes.push((enable, known.name)),
+                None => return false,
+            }
+        }
+        for (enable, cap) in changes {
+            self.caps.enabled.retain(|enabled| *enabled != cap);
+            if enable {
+                self.caps.enabled.push(cap);
+            }
+        }
+        self.sync_caps();
+        true
+    }
+
+    pub async fn handle_cap_message(
+        &mut self,
+        subcommand: String,
+        param: Option<String>,
+    ) -> Result<(), Reply> {
+        let subcommand = subcommand.to_uppercase();
+        let registered = !self.uuid.is_nil();
+        match subcommand.as_str() {
+            "LS" => {
+                self.caps.negotiating |= !registered;
+                if let Some(version) = param.and_then(|param| param.parse().ok()) {
+                    self.caps.version = self.caps.version.max(version);
+                }
+                if self.caps.version >= CAP_302 {
+                    self.caps.enable(caps::CAP_NOTIFY);
+                    self.sync_caps();
+                }
+                let version = self.caps.version;
+                let list = self.capabilities().iter().map(|cap| cap.format(version)).collect();
+                self.send_cap_list(&subcommand, list);
+            }
+            "LIST" => {
+                let list = self.caps.enabled.iter().map(|cap| cap.to_string()).collect();
+                self.send_cap_list(&subcommand, list);
+            }
+            "REQ" => {
+                self.caps.negotiating |= !registered;
+                let param = param.unwrap_or_default();
+                let valid = !param.trim().is_empty() && self.request_caps(&param);
+                let answer = if valid { "ACK" } else { "NAK" };
+                let _ = self.send(Command::Cap(Some(self.cap_target()), answer.to_owned(), Some(param)));
+            }
+            "END" => {
+                if self.caps.negotiating {
+                    self.caps.negotiating = false;
+                    self.abort_sasl();
+                    return self.try_register().await;
+                }
+            }
+            _ => return Err(Reply::ErrInvalidCapCmd(subcommand)),
+        }
+        Ok(())
+    }
+
+    /// Once STARTTLS succeeded the tls capability is no longer on offer.
+    pub(super) fn withdraw_tls_cap(&mut self) {
+        self.caps.enabled.retain(|cap| *cap != caps::TLS);
+        if self.caps.has(caps::CAP_NOTIFY) {
+            let del = Command::Cap(Some(self.cap_target()), "DEL".to_owned(), Some(caps::TLS.to_owned()));
+            let _ = self.send(del);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::client::Client;
+use crate::details::modes::FLAG_MODES;
+use crate::proto::reply::Reply;
+use crate::server::channels::{AccessLevel, RegisteredChannel, RegistrationError};
+use log::error;
+
+/// The pseudo-client users message to manage registered channels. Nobody may use the nick.
+pub const CHANSERV: &str = "ChanServ";
+
+const HELP: &[&str] = &[
+    "REGISTER <#channel> - Registers a channel you are an operator in",
+    "DROP <#channel> - Deletes the registration of a channel you founded",
+    "ACCESS <#channel> LIST - Lists who gets status on joining",
+    "ACCESS <#channel> ADD <account> <op|voice> - Gives an account status on joining",
+    "ACCESS <#channel> DEL <account> - Takes that status away again",
+    "SET <#channel> MLOCK <modes> - Keeps modes like +nt-s set or unset",
+    "INFO <#channel> - Shows how a channel is registered",
+];
+
+/// What ChanServ answers, a failure or not.
+type Answer = Result<String, String>;
+
+impl Client {
+    fn chanserv_notice<S: Into<String>>(&self, text: S) {
+        self.service_notice(CHANSERV, text);
+    }
+
+    /// PRIVMSG to ChanServ. Its answers come back as notices.
+    pub async fn handle_chanserv_message(&mut self, text: String) -> Result<(), Reply> {
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default().to_uppercase();
+        let args: Vec<&str> = words.collect();
+        let is = |word: &str, expected: &str| word.eq_ignore_ascii_case(expected);
+        let answer = match (command.as_str(), args.as_slice()) {
+            ("REGISTER", [channel]) => self.chanserv_register(channel).await,
+            ("DROP", [channel]) => self.chanserv_drop(channel).await,
+            ("ACCESS", [channel, list]) if is(list, "LIST") => self.chanserv_access_list(channel),
+            ("ACCESS", [channel, add, account, level]) if is(add, "ADD") => {
+                self.chanserv_access_add(channel, account, level).await
+            }
+            ("ACCESS", [channel, del, account]) if is(del, "DEL") => {
+                self.chanserv_access_del(channel, account).await
+            }
+            ("SET", [channel, mlock, modes]) if is(mlock, "MLOCK") => {
+                self.chanserv_mlock(channel, modes).await
+            }
+            ("INFO", [channel]) => self.chanserv_info(channel),
+            ("HELP", _) => {
+                for line in HELP {
+                    self.chanserv_notice(*line);
+                }
+                return Ok(());
+            }
+            _ => Err(format!("Unknown command or wrong arguments, /msg {} HELP lists them.", CHANSERV)),
+        };
+        match answer {
+            Ok(text) | Err(text) => self.chanserv_notice(text),
+        }
+        Ok(())
+    }
+
+    /// Tells a client what went wrong with a registration, without details of the storage.
+    fn registration_failure(e: RegistrationError) -> String {
+        match e {
+            RegistrationError::Storage { .. } => {
+                error!("Failed to store channels: {}", e);
+                "Channels can't be changed right now, try again later.".to_owned()
+            }
+            e => format!("{}.", e),
+        }
+    }
+
+    fn registered_channel(&self, channel: &str) -> Result<RegisteredChannel, String> {
+        self.server
+            .state()
+            .registered_channels()
+            .get(channel)
+            .ok_or_else(|| format!("{} is not registered.", channel))
+    }
+
+    /// The registration of `channel` if the client is logged in to its founder.
+    fn founded_channel(&self, channel: &str) -> Result<RegisteredChannel, String> {
+        let account = self.logged_in()?;
+        let registration = self.registered_channel(channel)?;
+        if registration.access(Some(&account)) != Some(AccessLevel::Founder) {
+            return Err(format!("Access denied for {}.", registration.name));
+        }
+        Ok(registration)
+    }
+
+    async fn chanserv_register(&mut self, channel: &str) -> Answer {
+        let account = self.logged_in()?;
+        let state = self.server.state();
+        if let Some(registration) = state.registered_channels().get(channel) {
+            return Err(format!(
+                "{} is already registered to {}.",
+                registration.name, registration.founder
+            ));
+        }
+        let registration = state
+            .registration_for(&self.uuid, channel, &account)
+            .ok_or_else(|| format!("You need to be an operator in {} to register it.", channel))?;
+        let name = registration.name.clone();
+        state
+            .registered_channels()
+            .register(registration)
+            .await
+            .map_err(Self::registration_failure)?;
+        Ok(format!("{} is now registered to {}.", name, account))
+    }
+
+    async fn chanserv_drop(&mut self, channel: &str) -> Answer {
+        let registration = self.founded_channel(channel)?;
+        self.server
+            .state()
+            .registered_channels()
+            .drop_channel(&registration.name)
+            .await
+            .map_err(Self::registration_failure)?;
+        Ok(format!("{} has been dropped.", registration.name))
+    }
+
+    fn chanserv_access_list(&mut self, channel: &str) -> Answer {
+        let account = self.logged_in()?;
+        let registration = self.registered_channel(channel)?;
+        if registration.access(Some(&account)).is_none() {
+            return Err(format!("Access denied for {}.", registration.name));
+        }
+        self.chanserv_notice(format!("{} {}", registration.founder, AccessLevel::Founder.name()));
+        for access in &registration.access {
+            self.chanserv_notice(format!("{} {}", access.account, access.level.name()));
+        }
+        Ok(format!("End of the access list of {}.", registration.name))
+    }
+
+    async fn chanserv_access_add(&mut self, channel: &str, account: &str, level: &str) -> Answer {
+        let registration = self.founded_channel(channel)?;
+        let level = AccessLevel::parse(level).ok_or("Access levels are op and voice.")?;
+        let account = self
+            .server
+            .accounts()
+            .owner(account)
+            .ok_or_else(|| format!("{} is not registered.", account))?;
+        if registration.access(Some(&account)) == Some(AccessLevel::Founder) {
+            return Err(format!("{} founded {}.", account, registration.name));
+        }
+        self.server
+            .state()
+            .registered_channels()
+            .update(&registration.name, |r| {
+                r.set_access(&account, Some(level));
+            })
+            .await
+            .map_err(Self::registration_failure)?;
+        Ok(format!("{} now gets {} in {}.", account, level.name(), registration.name))
+    }
+
+    async fn chanserv_access_del(&mut self, channel: &str, account: &str) -> Answer {
+        let registration = self.founded_channel(channel)?;
+        // A grouped nick stands for its account, as with ADD. Names nobody owns are taken
+        // as given.
+        let account = self
+            .server
+            .accounts()
+            .owner(account)
+            .unwrap_or_else(|| account.to_owned());
+        let mut removed = false;
+        self.server
+            .state()
+            .registered_channels()
+            .update(&registration.name, |r| removed = r.set_access(&account, None))
+            .await
+            .map_err(Self::registration_failure)?;
+        if !removed {
+            return Err(format!("{} is not on the access list of {}.", account, registration.name));
+        }
+        Ok(format!("{} no longer gets status in {}.", account, registration.name))
+    }
+
+    async fn chanserv_mlock(&mut self, channel: &str, modes: &str) -> Answer {
+        let registration = self.founded_channel(channel)?;
+        if !modes.chars().all(|c| c == '+' || c == '-' || FLAG_MODES.contains(c)) {
+            return Err(format!("Only the modes {} can be locked.", FLAG_MODES));
+        }
+        let state = self.server.state();
+        let registration = state
+            .registered_channels()
+            .update(&registration.name, |r| r.mlock = modes.to_owned())
+            .await
+            .map_err(Self::registration_failure)?;
+        state.apply_mode_lock(&registration).await;
+        Ok(format!("The mode lock of {} is now {}.", registration.name, modes))
+    }
+
+    fn chanserv_info(&mut self, channel: &str) -> Answer {
+        let registration = self.registered_channel(channel)?;
+        self.chanserv_notice(format!("{} is registered to {}.", registration.name, registration.founder));
+        if let Some(topic) = &registration.topic {
+            self.chanserv_notice(format!("Topic: {}", topic));
+        }
+        if !registration.mlock.is_empty() {
+            self.chanserv_notice(format!("Mode lock: {}", registration.mlock));
+        }
+        Ok(format!("End of the information on {}.", registration.name))
+    }
+}
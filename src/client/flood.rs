@@ -0,0 +1,110 @@
+use crate::config::ClassConfig;
+use crate::proto::{Message, MessageContents, ProtocolError};
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Fakelag flood control.
+///
+/// Every command moves a virtual clock forward by its cost. While the clock is less than
+/// `flood_burst` commands ahead of real time commands are processed straight away, after
+/// that they are held back until real time catches up. A client that gets more than
+/// `flood_limit` commands ahead is disconnected.
+#[derive(Debug)]
+pub struct FloodControl {
+    class: Arc<ClassConfig>,
+    clock: Instant,
+    exempt: bool,
+}
+
+impl FloodControl {
+    pub fn new(class: Arc<ClassConfig>) -> Self {
+        Self {
+            exempt: class.flood_exempt,
+            class,
+            clock: Instant::now(),
+        }
+    }
+
+    pub fn set_exempt(&mut self, exempt: bool) {
+        self.exempt = exempt;
+    }
+
+    /// Charges a message against the client's budget and returns when it may be processed.
+    pub fn charge(&mut self, message: &Message, now: Instant) -> Result<Instant, ProtocolError> {
+        if self.exempt {
+            return Ok(now);
+        }
+        let cost = match &message.contents {
+            MessageContents::Command(cmd) => self.class.flood_cost(&cmd.name()),
+            _ => 1,
+        };
+        let interval = self.class.flood_interval();
+        self.clock = cmp::max(self.clock, now) + interval * cost;
+        let lag = self.clock - now;
+        if lag > interval * self.class.flood_limit {
+            return Err(ProtocolError::ExcessFlood);
+        }
+        let burst: Duration = interval * self.class.flood_burst;
+        if lag > burst {
+            Ok(self.clock - burst)
+        } else {
+            Ok(now)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Command;
+
+    fn flood(burst: u32, limit: u32) -> FloodControl {
+        let mut costs = std::collections::HashMap::new();
+        costs.insert("JOIN".to_string(), 3);
+        FloodControl::new(Arc::new(ClassConfig {
+            flood_burst: burst,
+            flood_limit: limit,
+            flood_interval: 1000,
+            flood_costs: costs,
+            ..ClassConfig::default()
+        }))
+    }
+
+    #[test]
+    pub fn burst_is_not_delayed() {
+        let mut flood = flood(5, 10);
+        let now = Instant::now();
+        let msg: Message = Command::Notice("*", "hi").into();
+        for _ in 0..5 {
+            assert_eq!(flood.charge(&msg, now).unwrap(), now);
+        }
+        assert_eq!(flood.charge(&msg, now).unwrap(), now + Duration::from_secs(1));
+    }
+
+    #[test]
+    pub fn excess_flood() {
+        let mut flood = flood(5, 10);
+        let now = Instant::now();
+        let msg: Message = Command::Join(vec!["#a"], None).into();
+        for _ in 0..3 {
+            assert!(flood.charge(&msg, now).is_ok());
+        }
+        assert!(matches!(
+            flood.charge(&msg, now),
+            Err(ProtocolError::ExcessFlood)
+        ));
+    }
+
+    #[test]
+    pub fn exempt_never_floods() {
+        let mut flood = flood(1, 2);
+        flood.set_exempt(true);
+        let now = Instant::now();
+        let msg: Message = Command::Notice("*", "hi").into();
+        for _ in 0..100 {
+            assert_eq!(flood.charge(&msg, now).unwrap(), now);
+        }
+    }
+}
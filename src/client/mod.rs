@@ -1,33 +1,86 @@
+use crate::client::cap::ClientCaps;
+use crate::client::chanserv::CHANSERV;
+use crate::client::flood::FloodControl;
+use crate::client::nickserv::NICKSERV;
+use crate::client::sasl::SaslSession;
+use crate::config::DnsblAction;
 use crate::proto::codec::message::MessageCodec;
 use crate::proto::command::Command;
 use crate::proto::error::ProtocolError;
 use crate::proto::message::{Message, MessageContents};
 use crate::proto::reply::Reply;
-use crate::server::socket::Socket;
-use crate::server::transport::{Sender, Transport};
+use crate::proto::Prefix;
+use crate::config::OperPrivilege;
+use crate::server::dns::{self, ip_hostname};
+use crate::server::dnsbl::DnsblHit;
+use crate::server::ident::USERLEN;
+use crate::server::listener::{Accepted, ListenerError, StartTls};
+use crate::server::socket::{Socket, TlsInfo};
+use crate::server::throttle::ConnectionGuard;
+use crate::server::transport::{SendQueue, Sender, Transport};
 use crate::server::{Server, ServerError};
 use futures::future::FusedFuture;
 use futures::stream::{FusedStream, SplitSink, SplitStream};
-use futures::{ready, FutureExt, Sink, Stream, StreamExt};
-use log::{debug, error};
+use futures::{ready, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, info};
+use std::collections::VecDeque;
 use std::error::Error;
 use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio::time::{Instant, Sleep};
 use tokio_util::codec::Framed;
 use uuid::Uuid;
 
+mod accounts;
+mod cap;
+mod chanserv;
+mod nickserv;
+mod sasl;
+pub mod flood;
 pub mod handle;
 
+
+/// How long a disconnecting client gets to receive its ERROR line.
+const QUIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `nick` is taken by one of the pseudo-clients.
+fn is_service(nick: &str) -> bool {
+    [NICKSERV, CHANSERV].iter().any(|service| service.eq_ignore_ascii_case(nick))
+}
+
+/// Whether `command` may carry a password or some other secret, which keeps its arguments
+/// out of the log.
+fn is_secret(command: &Command) -> bool {
+    match command {
+        Command::PASS(_)
+        | Command::WEBIRC(..)
+        | Command::AUTHENTICATE(_)
+        | Command::REGISTER(..)
+        | Command::VERIFY(..)
+        | Command::OPER(..) => true,
+        Command::PRIVMSG(target, _, _) => is_service(target),
+        _ => false,
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientStream {
-    stream: SplitStream<Transport<Socket<TcpStream>>>,
+    /// Only missing after a failed STARTTLS, which ends the stream.
+    stream: Option<SplitStream<Transport<Socket<TcpStream>>>>,
     outgoing: Option<Outgoing>,
+    flood: FloodControl,
+    /// Messages held back by fakelag along with when they may be processed.
+    delayed: VecDeque<(Instant, Message)>,
+    sleep: Pin<Box<Sleep>>,
 }
 
 impl ClientStream {
@@ -44,6 +97,48 @@ impl ClientStream {
     }
 }
 
+impl ClientStream {
+    /// Sends everything queued so far followed by `ack` in plaintext, then upgrades the
+    /// socket. Commands held back by fakelag arrived in plaintext and are dropped with
+    /// the read buffer.
+    async fn starttls(
+        &mut self,
+        starttls: StartTls,
+        ack: Message,
+    ) -> Result<Option<TlsInfo>, ClientError> {
+        let stream = self.stream.take().ok_or(ClientError::StreamClosed)?;
+        let Outgoing {
+            mut sink,
+            stream: mut rx,
+            queue,
+            buffered,
+        } = self.outgoing.take().ok_or(ClientError::StreamClosed)?;
+        if let Some(message) = buffered {
+            sink.feed(message).await?;
+        }
+        while let Ok(message) = rx.try_recv() {
+            queue.pop(SendQueue::message_len(&message));
+            sink.feed(message).await?;
+        }
+        sink.send(ack).await?;
+        let transport = stream
+            .reunite(sink)
+            .map_err(|_| ClientError::StreamClosed)?;
+        let transport = transport.starttls(starttls.handshake).await?;
+        let tls = transport.tls_info();
+        self.delayed.clear();
+        let (sink, stream) = transport.split();
+        self.stream = Some(stream);
+        self.outgoing = Some(Outgoing {
+            sink,
+            stream: rx,
+            queue,
+            buffered: None,
+        });
+        Ok(tls)
+    }
+}
+
 impl FusedStream for ClientStream {
     fn is_terminated(&self) -> bool {
         false
@@ -69,13 +164,40 @@ impl Stream for ClientStream {
             }
         }
 
-        match ready!(Pin::new(&mut self.as_mut().stream).poll_next(cx)) {
-            Some(Ok(msg)) => {
-                //self.state.handle_message(&msg)?;
-                return Poll::Ready(Some(Ok(msg)));
+        // Keep reading while messages are held back so that a flooding client is charged
+        // for everything it sends and hits the hard limit.
+        loop {
+            let polled = match self.stream.as_mut() {
+                Some(stream) => Pin::new(stream).poll_next(cx),
+                None => return Poll::Ready(None),
+            };
+            match polled {
+                Poll::Ready(Some(Ok(msg))) => {
+                    let now = Instant::now();
+                    let ready_at = match self.flood.charge(&msg, now) {
+                        Ok(ready_at) => ready_at,
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    };
+                    if self.delayed.is_empty() && ready_at <= now {
+                        //self.state.handle_message(&msg)?;
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                    self.delayed.push_back((ready_at, msg));
+                }
+                Poll::Ready(other) => return Poll::Ready(other),
+                Poll::Pending => break,
             }
-            other => Poll::Ready(other),
         }
+
+        let ready_at = match self.delayed.front() {
+            Some((ready_at, _)) => *ready_at,
+            None => return Poll::Pending,
+        };
+        if self.sleep.deadline() != ready_at {
+            self.sleep.as_mut().reset(ready_at);
+        }
+        ready!(self.sleep.as_mut().poll(cx));
+        Poll::Ready(self.delayed.pop_front().map(|(_, msg)| Ok(msg)))
     }
 }
 
@@ -88,12 +210,26 @@ pub enum ClientError {
     },
     #[error("client stream closed [disconnect?]")]
     StreamClosed,
+    #[error("{0}")]
+    Quit(String),
+    #[error("WEBIRC: {0}")]
+    WebIrc(&'static str),
+    #[error("{0}")]
+    Rejected(String),
+    #[error("Killed ({0})")]
+    Killed(String),
+    #[error("STARTTLS failed: {source}")]
+    StartTls {
+        #[from]
+        source: ListenerError,
+    },
 }
 
 #[derive(Debug)]
 pub struct Outgoing {
     sink: SplitSink<Transport<Socket<TcpStream>>, Message>,
     stream: UnboundedReceiver<Message>,
+    queue: Arc<SendQueue>,
     buffered: Option<Message>,
 }
 
@@ -125,13 +261,19 @@ impl Future for Outgoing {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = &mut *self;
+        if this.queue.poll_exceeded(cx) {
+            return Poll::Ready(Err(ProtocolError::SendQExceeded));
+        }
         if let Some(msg) = this.buffered.take() {
             ready!(this.try_start_send(cx, msg))?
         }
 
         loop {
             match this.stream.poll_recv(cx) {
-                Poll::Ready(Some(message)) => ready!(this.try_start_send(cx, message))?,
+                Poll::Ready(Some(message)) => {
+                    this.queue.pop(SendQueue::message_len(&message));
+                    ready!(this.try_start_send(cx, message))?
+                }
                 Poll::Ready(None) => {
                     ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
                     return Poll::Ready(Ok(()));
@@ -155,39 +297,88 @@ pub struct Client {
     pub username: String,
     pub realname: String,
     pub uuid: Uuid,
+    /// Our end of the connection, for ident lookups.
+    local: Option<SocketAddr>,
+    /// Connected through a local socket, which vouches for the username.
+    trusted: bool,
+    /// The user ID reported by the client's ident server.
+    ident: Option<String>,
+    tls: Option<TlsInfo>,
+    starttls: Option<StartTls>,
+    /// The WEBIRC block of the gateway this client connected through.
+    gateway: Option<String>,
+    /// The harshest DNSBL listing of the client's address that still lets it connect.
+    dnsbl: Option<DnsblHit>,
+    /// Why registration was refused or the client otherwise has to go, it is
+    /// disconnected once set.
+    rejected: Option<String>,
+    caps: ClientCaps,
+    sasl: Option<SaslSession>,
+    /// The account logged in to.
+    account: Option<String>,
+    /// Wrong passwords given so far.
+    login_failures: u32,
+    /// When the nick is changed unless the client identified for it by then.
+    nick_deadline: Option<Instant>,
+    /// Receives the reason once the client is killed, set at registration.
+    killed: Option<oneshot::Receiver<String>>,
+    cookie: Option<String>,
+    _guard: ConnectionGuard,
 }
 
 impl Client {
-    pub fn new(sock: Socket<TcpStream>, server: Arc<Server>) -> Result<Self, ClientError> {
-        let addr = match &sock {
-            Socket::Plain(ref sock) => sock.peer_addr(),
-            #[cfg(feature = "native-tls")]
-            Socket::Tls(ref sock) => sock.get_ref().get_ref().get_ref().peer_addr(),
-            #[cfg(feature = "rustls")]
-            Socket::RusTls(ref sock) => {
-                let (s, _) = sock.get_ref();
-                s.peer_addr()
-            }
-        };
+    pub fn new(accepted: Accepted, server: Arc<Server>) -> Result<Self, ClientError> {
+        let Accepted {
+            socket: sock,
+            addr,
+            local,
+            guard,
+            class,
+            starttls,
+            hostname,
+        } = accepted;
+        let tls = sock.tls_info();
         let (tx_out, rx_out) = unbounded_channel();
-        let sender = Sender::new(server.clone(), tx_out);
+        let queue = Arc::new(SendQueue::new(&class));
+        let sender = Sender::new(server.clone(), tx_out, queue.clone());
         let framed = Framed::new(sock, MessageCodec::new("utf-8")?);
-        let conn = Transport::new(framed, sender.clone());
+        let conn = Transport::new(framed, sender.clone(), &class)?;
+        let cookie = conn.cookie();
         let (outgoing, incoming) = conn.split();
         Ok(Client {
             server: server,
-            addr: addr.expect("Failed to find peer address"),
+            addr,
             sender,
             nick: String::new(),
-            hostname: String::new(),
+            trusted: hostname.is_some(),
+            hostname: hostname.unwrap_or_default(),
             username: String::new(),
             realname: String::new(),
             uuid: Uuid::nil(),
+            local,
+            ident: None,
+            tls,
+            starttls,
+            gateway: None,
+            dnsbl: None,
+            rejected: None,
+            caps: ClientCaps::default(),
+            sasl: None,
+            account: None,
+            login_failures: 0,
+            nick_deadline: None,
+            killed: None,
+            cookie,
+            _guard: guard,
             stream: ClientStream {
-                stream: incoming,
+                stream: Some(incoming),
+                flood: FloodControl::new(class),
+                delayed: VecDeque::new(),
+                sleep: Box::pin(time::sleep(Duration::ZERO)),
                 outgoing: Option::from(Outgoing {
                     sink: outgoing,
                     stream: rx_out,
+                    queue,
                     buffered: None,
                 }),
             },
@@ -202,6 +393,42 @@ impl Client {
         self.hostname = hostname;
     }
 
+    /// The client's address and ours for an ident lookup, if one makes sense.
+    pub fn ident_query(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.local.map(|local| (self.addr, local))
+    }
+
+    pub fn set_ident(&mut self, ident: String) {
+        self.ident = Some(ident);
+    }
+
+    /// Checks the client's current address against the DNS blocklists. Opers hear about
+    /// every listing, but only the harshest one applies and it replaces any earlier result.
+    pub async fn check_dnsbl(&mut self) -> Result<(), ClientError> {
+        let ip = self.address();
+        let listings = self
+            .server
+            .dnsbl()
+            .check(self.server.dns().resolver(), ip)
+            .await;
+        for hit in &listings {
+            let action = match hit.action {
+                DnsblAction::Mark => "marking",
+                DnsblAction::RequireSasl => "requiring SASL",
+                DnsblAction::Reject => "rejecting",
+            };
+            let notice = format!("DNSBL: {} is listed in {} ({}), {}", ip, hit.name, hit.reply, action);
+            self.server.state().notice_opers(&notice).await;
+        }
+        match listings.into_iter().max_by_key(|hit| hit.action) {
+            Some(hit) if hit.action == DnsblAction::Reject => Err(ClientError::Rejected(hit.reason)),
+            hit => {
+                self.dnsbl = hit;
+                Ok(())
+            }
+        }
+    }
+
     pub fn send<T: Into<Message>>(&self, m: T) -> Result<(), ClientError> {
         self.sender.send(m).map_err(|e| e.into())
     }
@@ -214,6 +441,18 @@ impl Client {
         }
     }
 
+    /// A notice from `service`, one of the pseudo-clients like NickServ.
+    fn service_notice<S: Into<String>>(&self, service: &str, text: S) {
+        let target = if self.nick.is_empty() { "*" } else { &self.nick };
+        let mut message: Message = Command::Notice(target.to_owned(), text.into()).into();
+        message.set_prefix(Prefix::Usermask(
+            service.to_owned(),
+            service.to_owned(),
+            self.server.name(),
+        ));
+        let _ = self.send(message);
+    }
+
     pub fn send_motd(&self) -> Result<(), ClientError> {
         let motd = self.server.get_motd();
         self.send(Reply::MotdStart(self.server.name()))?;
@@ -224,25 +463,67 @@ impl Client {
         Ok(())
     }
 
-    pub async fn handle_message(&mut self, message: Message) {
+    pub async fn handle_message(&mut self, mut message: Message) -> Result<(), ClientError> {
+        // Only client-only tags are the client's to set.
+        message.tags.retain_client_only();
         if let MessageContents::Command(cmd) = message.contents {
-            debug!("Handling message: {}", cmd);
+            if is_secret(&cmd) {
+                debug!("Handling message: {} (arguments hidden)", cmd.name());
+            } else {
+                debug!("Handling message: {}", cmd);
+            }
+            if let Command::QUIT(reason) = cmd {
+                return Err(ClientError::Quit(reason.unwrap_or_else(|| "Client Quit".to_owned())));
+            }
+            self.sync_account();
             let reply = if self.uuid.is_nil() {
                 match cmd {
                     Command::NICK(nick, hops) => self.handle_nick_message(nick, hops).await,
                     Command::USER(un, _, _, realname) => {
                         self.handle_user_message(un, realname).await
                     }
+                    Command::PONG(data, _) => self.handle_cookie_pong(data).await,
+                    Command::STARTTLS => self.handle_starttls_message().await,
+                    Command::WEBIRC(password, gateway, hostname, ip, options) => {
+                        self.handle_webirc_message(password, gateway, hostname, ip, options)
+                            .await?;
+                        Ok(())
+                    }
+                    Command::CAP(_, sub, _, param) => self.handle_cap_message(sub, param).await,
+                    Command::AUTHENTICATE(data) => self.handle_authenticate_message(data).await,
+                    Command::REGISTER(account, email, password) => {
+                        self.handle_register_message(account, email, password).await
+                    }
+                    Command::VERIFY(account, _) => self.handle_verify_message(account).await,
                     _ => Err(Reply::ErrNotRegistered),
                 }
             } else {
                 match cmd {
-                    Command::NICK(_nick, _hops) => {
-                        //self.handle_nick_message(nick, hops).await
-                        Ok(())
-                    }
-                    Command::USER(..) => Err(Reply::ErrAlreadyRegistered),
+                    Command::NICK(nick, _) if is_service(&nick) => Err(Reply::ErrNicknameInUse(nick)),
+                    Command::NICK(nick, _) => self.change_nick(nick).await,
+                    Command::USER(..) | Command::WEBIRC(..) => Err(Reply::ErrAlreadyRegistered),
                     Command::JOIN(chans, keys) => self.handle_join_message(chans, keys).await,
+                    Command::MODE(target, params) => self.handle_mode_message(target, params).await,
+                    Command::TOPIC(channel, topic) => self.handle_topic_message(channel, topic).await,
+                    Command::OPER(name, password) => self.handle_oper_message(name, password).await,
+                    Command::SETHOST(host) => self.handle_sethost_message(host).await,
+                    Command::CHGHOST(nick, host) => self.handle_chghost_message(nick, host).await,
+                    Command::STATS(query, _) => self.handle_stats_message(query).await,
+                    Command::REHASH => self.handle_rehash_message().await,
+                    Command::WHOIS(_, nick) => self.handle_whois_message(nick).await,
+                    Command::STARTTLS => Err(Reply::ErrStartTls),
+                    Command::CAP(_, sub, _, param) => self.handle_cap_message(sub, param).await,
+                    Command::AUTHENTICATE(data) => self.handle_authenticate_message(data).await,
+                    Command::REGISTER(account, email, password) => {
+                        self.handle_register_message(account, email, password).await
+                    }
+                    Command::VERIFY(account, _) => self.handle_verify_message(account).await,
+                    Command::PRIVMSG(target, text, _) if target.eq_ignore_ascii_case(NICKSERV) => {
+                        self.handle_nickserv_message(text).await
+                    }
+                    Command::PRIVMSG(target, text, _) if target.eq_ignore_ascii_case(CHANSERV) => {
+                        self.handle_chanserv_message(text).await
+                    }
                     _ => Err(Reply::ErrGeneric(
                         cmd.name(),
                         None,
@@ -261,7 +542,11 @@ impl Client {
                     ),
                 },
             }
+            if let Some(reason) = self.rejected.take() {
+                return Err(ClientError::Rejected(reason));
+            }
         }
+        Ok(())
     }
 
     pub async fn handle_message_error(&mut self, err: ProtocolError) -> Option<ClientError> {
@@ -271,6 +556,7 @@ impl Client {
                 Ok(_) => None,
                 Err(e) => Some(e),
             },
+            ProtocolError::InputTooLong => self.send(Reply::ErrInputTooLong).err(),
             ProtocolError::NotEnoughArguments(cmd) => {
                 match self.send(Reply::ErrNeedMoreParams(cmd)) {
                     Ok(_) => None,
@@ -290,7 +576,7 @@ impl Client {
             if let Some(option) = option {
                 match option {
                     Ok(msg) => {
-                        self.handle_message(msg).await;
+                        self.handle_message(msg).await?;
                     }
                     Err(v) => return Err(v.into()),
                 }
@@ -301,12 +587,38 @@ impl Client {
         Ok(())
     }
 
+    /// Resolves with the reason once the client is killed.
+    async fn killed(killed: &mut Option<oneshot::Receiver<String>>) -> String {
+        if let Some(receiver) = killed.as_mut() {
+            let result = receiver.await;
+            *killed = None;
+            if let Ok(reason) = result {
+                return reason;
+            }
+        }
+        std::future::pending().await
+    }
+
+    async fn grace_over(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
     pub async fn poll(&mut self) -> Result<(), ClientError> {
-        let evt = self.stream.next().await;
+        let evt = tokio::select! {
+            evt = self.stream.next() => evt,
+            reason = Self::killed(&mut self.killed) => return Err(ClientError::Killed(reason)),
+            _ = Self::grace_over(self.nick_deadline) => {
+                self.enforce_nick().await;
+                return Ok(());
+            }
+        };
         if let Some(option) = evt {
             match option {
                 Ok(msg) => {
-                    self.handle_message(msg).await;
+                    self.handle_message(msg).await?;
                 }
                 Err(v) => {
                     if let Some(e) = self.handle_message_error(v).await {
@@ -320,11 +632,31 @@ impl Client {
         Ok(())
     }
 
+    /// Tells the client why it is being disconnected. The client is dropped afterwards,
+    /// which removes it from the server state.
+    pub async fn quit(&mut self, reason: &str) {
+        let host = if self.hostname.is_empty() {
+            ip_hostname(self.addr.ip())
+        } else {
+            self.hostname.clone()
+        };
+        debug!("closing link to {}: {}", host, reason);
+        if let Some(outgoing) = self.stream.outgoing.as_mut() {
+            let mut error: Message =
+                Command::Error(format!("Closing Link: {} ({})", host, reason)).into();
+            error.set_prefix(self.server.prefix());
+            let _ = time::timeout(QUIT_FLUSH_TIMEOUT, outgoing.sink.send(error)).await;
+        }
+    }
+
     pub async fn handle_nick_message(
         &mut self,
         nick: String,
         hops: Option<i32>,
     ) -> Result<(), Reply> {
+        if is_service(&nick) {
+            return Err(Reply::ErrNicknameInUse(nick));
+        }
         if self
             .server.state()
             .set_nick(nick.clone())
@@ -333,21 +665,83 @@ impl Client {
         } else {
             return Err(Reply::ErrNickCollision(nick));
         }
-        Ok(())
+        self.try_register().await
     }
 
     pub async fn handle_user_message(&mut self, un: String, realname: String) -> Result<(), Reply> {
         if !self.uuid.is_nil() {
             return Err(Reply::ErrAlreadyRegistered);
         }
-        if let Some(uuid) = self
+        self.username = un;
+        self.realname = realname;
+        self.try_register().await
+    }
+
+    /// The transport only passes up the PONG that echoed the registration cookie.
+    pub async fn handle_cookie_pong(&mut self, data: String) -> Result<(), Reply> {
+        if self.cookie.as_ref() == Some(&data) {
+            self.cookie = None;
+            return self.try_register().await;
+        }
+        Ok(())
+    }
+
+    /// Completes registration once NICK, USER and the PING cookie have all been received,
+    /// and capability negotiation is over.
+    async fn try_register(&mut self) -> Result<(), Reply> {
+        if self.nick.is_empty()
+            || self.username.is_empty()
+            || self.cookie.is_some()
+            || self.caps.negotiating()
+        {
+            return Ok(());
+        }
+        let require_sasl = self
+            .dnsbl
+            .as_ref()
+            .filter(|hit| hit.action == DnsblAction::RequireSasl && self.account.is_none());
+        if let Some(hit) = require_sasl {
+            self.rejected = Some(format!("{}, log in with SASL to connect", hit.reason));
+            return Ok(());
+        }
+        // Without an ident reply the username is only the client's word, which `~` marks
+        // within the same length limit.
+        let username = match &self.ident {
+            Some(ident) => ident.clone(),
+            None if self.trusted => self.username.clone(),
+            None => format!("~{}", self.username.chars().take(USERLEN - 1).collect::<String>()),
+        };
+        let host = self.server.cloak().host(&self.hostname, self.address());
+        if let Some((uuid, killed)) = self
             .server.state()
-            .register(self.nick.clone(), un.clone(), self.hostname.clone(), realname.clone(), self.sender.clone())
+            .register(self.nick.clone(), username.clone(), host, self.realname.clone(), self.sender.clone(), self.tls.clone())
         {
             self.uuid = uuid;
-            self.username = un;
-            self.realname = realname;
+            self.killed = Some(killed);
+            if let Some(hit) = self.dnsbl.take() {
+                self.server.state().mark(&uuid, hit.reason);
+            }
+            self.sync_caps();
+            let state = self.server.state();
+            state.set_account(&uuid, self.account.clone());
+            let cloak = match self.server.cloak().by_default() {
+                true => state.set_cloaked(&uuid, true),
+                false => None,
+            };
+            let masks: Vec<String> = [self.hostname.clone(), self.address().to_string()]
+                .iter()
+                .map(|host| format!("{}!{}@{}", self.nick, username, host))
+                .collect();
+            let vhost = match self.server.configured_vhost(&masks) {
+                Some(vhost) => state.set_vhost(&uuid, Some(vhost.to_owned())).await,
+                None => None,
+            };
+            // Setting the vhost already told the client about its new host.
+            if let (Some(cloak), None) = (cloak, vhost) {
+                let _ = self.send(Reply::HostHidden(cloak));
+            }
             self.send_motd();
+            self.check_nick();
         } else {
             return Err(Reply::ErrGeneric(
                 "USER".to_string(),
@@ -358,6 +752,253 @@ impl Client {
         Ok(())
     }
 
+    /// Upgrades the connection before registration on listeners that offer STARTTLS. A
+    /// failed handshake leaves the client without a stream, which disconnects it.
+    pub async fn handle_starttls_message(&mut self) -> Result<(), Reply> {
+        let starttls = match self.starttls.take() {
+            Some(starttls) if self.tls.is_none() => starttls,
+            _ => return Err(Reply::ErrStartTls),
+        };
+        let timeout = starttls.timeout;
+        let mut ack: Message = Reply::StartTls.into();
+        ack.set_prefix(self.server.prefix());
+        let result = match time::timeout(timeout, self.stream.starttls(starttls, ack)).await {
+            Ok(result) => result,
+            Err(_) => Err(ListenerError::HandshakeTimeout(self.addr).into()),
+        };
+        match result {
+            Ok(tls) => {
+                self.tls = tls;
+                self.withdraw_tls_cap();
+            }
+            Err(e) => {
+                debug!("{}", e);
+                self.stream.stream = None;
+                self.stream.outgoing = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the user's address and hostname from a trusted gateway. The gateway's own TLS
+    /// session says nothing about the user's, so only the `secure` option marks the client
+    /// as using TLS. Anything but a configured gateway is disconnected, and the user's
+    /// address is held to the DNS blocklists like any other.
+    pub async fn handle_webirc_message(
+        &mut self,
+        password: String,
+        gateway: String,
+        hostname: String,
+        ip: String,
+        options: Option<String>,
+    ) -> Result<(), ClientError> {
+        if self.gateway.is_some() {
+            return Err(ClientError::WebIrc("already received"));
+        }
+        let name = self
+            .server
+            .webirc_gateway(&self.addr.ip(), &password)
+            .ok_or(ClientError::WebIrc("not a trusted gateway"))?
+            .to_owned();
+        let ip: IpAddr = ip.parse().map_err(|_| ClientError::WebIrc("invalid IP"))?;
+        let secure = options
+            .as_deref()
+            .unwrap_or_default()
+            .split(' ')
+            .any(|option| option == "secure" || option.starts_with("secure="));
+        info!("WEBIRC: {} ({}) connected {} as {}", name, gateway, ip, hostname);
+        // The gateway's word is held to the same rules as a lookup, anything else could
+        // break the user's mask.
+        self.hostname = if dns::validate(&hostname).is_ok() {
+            hostname
+        } else {
+            ip_hostname(ip)
+        };
+        self.addr = SocketAddr::new(ip, self.addr.port());
+        // The ident reply, if any, came from the gateway's host.
+        self.local = None;
+        self.ident = None;
+        self.tls = secure.then_some(TlsInfo { certfp: None });
+        self.gateway = Some(name);
+        self.check_dnsbl().await
+    }
+
+    pub async fn handle_oper_message(&mut self, name: String, password: String) -> Result<(), Reply> {
+        let oper = match self.server.oper(&name) {
+            Some(oper) => oper,
+            None => return Err(Reply::ErrNoOperHost),
+        };
+        if let Some(certfp) = &oper.certfp {
+            let matched = self
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.certfp.as_ref())
+                .is_some_and(|fp| fp.matches(certfp));
+            if !matched {
+                return Err(Reply::ErrNoOperHost);
+            }
+        }
+        if oper.password != password {
+            return Err(Reply::ErrPasswdMismatch);
+        }
+        self.server.state().set_oper(&self.uuid, &oper.privileges);
+        self.stream.flood.set_exempt(true);
+        let _ = self.send(Reply::YoureOper);
+        self.server
+            .state()
+            .notice_opers(&format!("{} is now an operator ({})", self.nick, name))
+            .await;
+        Ok(())
+    }
+
+    /// Gives the client itself a vhost.
+    pub async fn handle_sethost_message(&mut self, host: String) -> Result<(), Reply> {
+        let uuid = self.uuid;
+        self.change_host("SETHOST", &uuid, host).await
+    }
+
+    /// Gives another client a vhost.
+    pub async fn handle_chghost_message(&mut self, nick: String, host: String) -> Result<(), Reply> {
+        let uuid = match self.server.state().find_client(&nick).await {
+            Some(uuid) => uuid,
+            None => return Err(Reply::ErrNoSuchNick(nick)),
+        };
+        self.change_host("CHGHOST", &uuid, host).await
+    }
+
+    async fn change_host(&mut self, cmd: &str, uuid: &Uuid, host: String) -> Result<(), Reply> {
+        let state = self.server.state();
+        if !state.has_privilege(&self.uuid, OperPrivilege::SetHost) {
+            return Err(Reply::ErrNoPrivileges);
+        }
+        if dns::validate(&host).is_err() {
+            return Err(Reply::ErrGeneric(
+                cmd.to_owned(),
+                None,
+                "Invalid hostname".to_owned(),
+            ));
+        }
+        let target = state.prefix(uuid).await;
+        if state.set_vhost(uuid, Some(host.clone())).await.is_some() {
+            if let Some(Prefix::Usermask(nick, _, _)) = target {
+                state
+                    .notice_opers(&format!("{} set the host of {} to {}", self.nick, nick, host))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn handle_stats_message(&mut self, query: Option<String>) -> Result<(), Reply> {
+        if !self.server.state().is_oper(&self.uuid) {
+            return Err(Reply::ErrNoPrivileges);
+        }
+        let query = query.unwrap_or_else(|| "*".to_owned());
+        let replies = match query.chars().next() {
+            Some('l') | Some('L') => self.server.state().sendq_stats().await,
+            _ => Vec::new(),
+        };
+        for rpl in replies {
+            let _ = self.send(rpl);
+        }
+        let _ = self.send(Reply::EndOfStats(query));
+        Ok(())
+    }
+
+    pub async fn handle_rehash_message(&mut self) -> Result<(), Reply> {
+        if !self.server.state().is_oper(&self.uuid) {
+            return Err(Reply::ErrNoPrivileges);
+        }
+        let _ = self.send(Reply::Rehashing(self.server.config_path().to_owned()));
+        self.server.rehash().await;
+        Ok(())
+    }
+
+    pub async fn handle_whois_message(&mut self, nick: String) -> Result<(), Reply> {
+        let replies = self
+            .server
+            .state()
+            .whois(&self.uuid, &self.server.name(), &nick)
+            .await;
+        for rpl in replies {
+            let _ = self.send(rpl);
+        }
+        Ok(())
+    }
+
+    pub async fn handle_mode_message(
+        &mut self,
+        target: String,
+        params: Vec<String>,
+    ) -> Result<(), Reply> {
+        if target.starts_with(['#', '&']) {
+            let replies = self
+                .server
+                .state()
+                .channel_mode(&self.uuid, &target, params)
+                .await?;
+            for rpl in replies {
+                let _ = self.send(rpl);
+            }
+            return Ok(());
+        }
+        if !target.eq_ignore_ascii_case(&self.nick) {
+            return Err(Reply::ErrUsersDontMatch);
+        }
+        let state = self.server.state();
+        let modes = match params.first() {
+            Some(modes) => modes,
+            None => {
+                let _ = self.send(Reply::UModeIs(state.user_modes(&self.uuid)));
+                return Ok(());
+            }
+        };
+        let prefix = state.prefix(&self.uuid).await;
+        let mut adding = true;
+        let mut unknown = false;
+        let mut changed = None;
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                'x' => {
+                    if let Some(host) = state.set_cloaked(&self.uuid, adding) {
+                        changed = Some((adding, host));
+                    }
+                }
+                _ => unknown = true,
+            }
+        }
+        if let (Some((cloaked, host)), Some(prefix)) = (changed, prefix) {
+            let modes = if cloaked { "+x" } else { "-x" };
+            let mut message: Message = Command::Mode(self.nick.as_str(), vec![modes]).into();
+            message.set_prefix(prefix.clone());
+            let _ = self.send(message);
+            let _ = self.send(Reply::HostHidden(host));
+            state.announce_host_change(&self.uuid, prefix).await;
+        }
+        if unknown {
+            return Err(Reply::ErrUModeUnknownFlag);
+        }
+        Ok(())
+    }
+
+    pub async fn handle_topic_message(
+        &mut self,
+        channel: String,
+        topic: Option<String>,
+    ) -> Result<(), Reply> {
+        let replies = self
+            .server
+            .state()
+            .topic(&self.uuid, &channel, topic)
+            .await?;
+        for rpl in replies {
+            let _ = self.send(rpl);
+        }
+        Ok(())
+    }
+
     pub async fn handle_join_message(
         &mut self,
         chans: Vec<String>,
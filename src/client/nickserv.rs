@@ -0,0 +1,208 @@
+use crate::client::Client;
+use crate::proto::reply::Reply;
+use log::error;
+
+/// The pseudo-client users message to manage their account. Nobody may use the nick.
+pub const NICKSERV: &str = "NickServ";
+
+const HELP: &[&str] = &[
+    "REGISTER <password> [email] - Registers your nick as an account",
+    "IDENTIFY [account] <password> - Logs you in",
+    "DROP <password> - Deletes your account, once it founds no channels",
+    "SET PASSWORD <password> - Changes your password",
+    "GROUP - Adds your nick to your account",
+    "UNGROUP [nick] - Removes a nick from your account",
+    "GHOST <nick> [password] - Disconnects someone using your nick",
+    "REGAIN <nick> [password] - Disconnects someone using your nick and takes it",
+];
+
+/// What NickServ answers, a failure or not.
+type Answer = Result<String, String>;
+
+impl Client {
+    pub(super) fn nickserv_notice<S: Into<String>>(&self, text: S) {
+        self.service_notice(NICKSERV, text);
+    }
+
+    /// PRIVMSG to NickServ. Its answers come back as notices.
+    pub async fn handle_nickserv_message(&mut self, text: String) -> Result<(), Reply> {
+        let mut words = text.split_whitespace();
+        let command = words.next().unwrap_or_default().to_uppercase();
+        let args: Vec<&str> = words.collect();
+        let answer = match (command.as_str(), args.as_slice()) {
+            ("REGISTER", [password]) => self.nickserv_register(password, None).await,
+            ("REGISTER", [password, email]) => self.nickserv_register(password, Some(email)).await,
+            ("IDENTIFY", [password]) => self.nickserv_identify(None, password).await,
+            ("IDENTIFY", [account, password]) => self.nickserv_identify(Some(account), password).await,
+            ("DROP", [password]) => self.nickserv_drop(password).await,
+            ("SET", [setting, password]) if setting.eq_ignore_ascii_case("PASSWORD") => {
+                self.nickserv_set_password(password).await
+            }
+            ("GROUP", []) => self.nickserv_group().await,
+            ("UNGROUP", []) => self.nickserv_ungroup(&self.nick.clone()).await,
+            ("UNGROUP", [nick]) => self.nickserv_ungroup(nick).await,
+            ("GHOST", [nick]) => self.nickserv_ghost(nick, None, false).await,
+            ("GHOST", [nick, password]) => self.nickserv_ghost(nick, Some(password), false).await,
+            ("REGAIN", [nick]) => self.nickserv_ghost(nick, None, true).await,
+            ("REGAIN", [nick, password]) => self.nickserv_ghost(nick, Some(password), true).await,
+            ("HELP", _) => {
+                for line in HELP {
+                    self.nickserv_notice(*line);
+                }
+                return Ok(());
+            }
+            _ => Err(format!("Unknown command or wrong arguments, /msg {} HELP lists them.", NICKSERV)),
+        };
+        match answer {
+            Ok(text) | Err(text) => self.nickserv_notice(text),
+        }
+        Ok(())
+    }
+
+    pub(super) fn logged_in(&self) -> Result<String, String> {
+        self.account
+            .clone()
+            .ok_or_else(|| "You are not logged in.".to_owned())
+    }
+
+    fn check_password_length(&self, password: &str) -> Result<(), String> {
+        let min = self.server.registration().min_password;
+        if password.len() < min {
+            return Err(format!("Passwords need at least {} characters.", min));
+        }
+        Ok(())
+    }
+
+    async fn nickserv_register(&mut self, password: &str, email: Option<&str>) -> Answer {
+        if !self.server.registration().enabled {
+            return Err("Account registration is disabled.".to_owned());
+        }
+        if let Some(account) = &self.account {
+            return Err(format!("You are already logged in as {}.", account));
+        }
+        self.check_password_length(password)?;
+        let email = email.map(str::to_owned);
+        let account = self
+            .server
+            .accounts()
+            .register(&self.nick, password, email)
+            .await
+            .map_err(Self::account_failure)?;
+        self.log_in(account.clone()).await;
+        Ok(format!("{} is now registered to you.", account))
+    }
+
+    async fn nickserv_identify(&mut self, account: Option<&str>, password: &str) -> Answer {
+        if let Some(current) = &self.account {
+            return Err(format!("You are already logged in as {}.", current));
+        }
+        let name = match account {
+            Some(account) => account.to_owned(),
+            None => self.server.accounts().owner(&self.nick).unwrap_or_else(|| self.nick.clone()),
+        };
+        match self.check_password(&name, password).await {
+            Some(account) => {
+                self.log_in(account.clone()).await;
+                Ok(format!("You are now identified for {}.", account))
+            }
+            None => Err(format!("Invalid password for {}.", name)),
+        }
+    }
+
+    async fn nickserv_drop(&mut self, password: &str) -> Answer {
+        let account = self.logged_in()?;
+        if self.check_password(&account, password).await.is_none() {
+            return Err(format!("Invalid password for {}.", account));
+        }
+        // Channels can't be left without a founder.
+        let state = self.server.state();
+        let channels = state.registered_channels();
+        let founded = channels.founded_by(&account);
+        if !founded.is_empty() {
+            return Err(format!(
+                "{} still founds {}, drop those with ChanServ first.",
+                account,
+                founded.join(", ")
+            ));
+        }
+        self.server
+            .accounts()
+            .drop_account(&account)
+            .await
+            .map_err(Self::account_failure)?;
+        // Whoever registers the name next doesn't inherit its status.
+        if let Err(e) = channels.remove_access(&account).await {
+            error!("Failed to take {} off access lists: {}", account, e);
+        }
+        self.log_out().await;
+        self.server.state().log_out_everywhere(&account).await;
+        Ok(format!("{} has been dropped.", account))
+    }
+
+    async fn nickserv_set_password(&mut self, password: &str) -> Answer {
+        let account = self.logged_in()?;
+        self.check_password_length(password)?;
+        self.server
+            .accounts()
+            .set_password(&account, password)
+            .await
+            .map_err(Self::account_failure)?;
+        Ok("Your password has been changed.".to_owned())
+    }
+
+    async fn nickserv_group(&mut self) -> Answer {
+        let account = self.logged_in()?;
+        self.server
+            .accounts()
+            .group(&account, &self.nick)
+            .await
+            .map_err(Self::account_failure)?;
+        self.check_nick();
+        Ok(format!("{} is now grouped to {}.", self.nick, account))
+    }
+
+    async fn nickserv_ungroup(&mut self, nick: &str) -> Answer {
+        let account = self.logged_in()?;
+        self.server
+            .accounts()
+            .ungroup(&account, nick)
+            .await
+            .map_err(Self::account_failure)?;
+        self.check_nick();
+        Ok(format!("{} is no longer grouped to {}.", nick, account))
+    }
+
+    /// GHOST disconnects whoever uses `nick`, REGAIN also takes it. Either works for the
+    /// owner of the nick, or with its password.
+    async fn nickserv_ghost(&mut self, nick: &str, password: Option<&str>, regain: bool) -> Answer {
+        let owner = self
+            .server
+            .accounts()
+            .owner(nick)
+            .ok_or_else(|| format!("{} is not registered.", nick))?;
+        let by_password = match password {
+            Some(password) => self.check_password(&owner, password).await,
+            None => None,
+        };
+        if self.account.as_ref() != Some(&owner) && by_password.is_none() {
+            return Err(format!("Access denied for {}.", nick));
+        }
+        let state = self.server.state();
+        let ghost = state.find_client(nick).await.filter(|uuid| *uuid != self.uuid);
+        if let Some(ghost) = ghost {
+            state.kill(&ghost, format!("GHOST command used by {}", self.nick)).await;
+        } else if !regain {
+            return Err(format!("{} is not online.", nick));
+        }
+        if !regain {
+            return Ok(format!("{} has been ghosted.", nick));
+        }
+        if let (None, Some(account)) = (&self.account, by_password) {
+            self.log_in(account).await;
+        }
+        self.change_nick(nick.to_owned())
+            .await
+            .map_err(|_| format!("{} is still in use.", nick))?;
+        Ok(format!("You have regained {}.", nick))
+    }
+}
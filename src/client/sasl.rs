@@ -0,0 +1,127 @@
+use crate::client::Client;
+use crate::proto::command::Command;
+use crate::proto::reply::Reply;
+use crate::server::caps;
+use crate::server::sasl::{self, Mechanism, SaslContext, Step};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// AUTHENTICATE payloads are split into chunks of this many bytes, a shorter chunk or `+`
+/// ends the message.
+const CHUNK_LEN: usize = 400;
+/// Longest encoded response a client may send in chunks.
+const MAX_RESPONSE: usize = 8192;
+
+/// An exchange started with AUTHENTICATE and not finished yet.
+#[derive(Debug)]
+pub struct SaslSession {
+    mechanism: Box<dyn Mechanism>,
+    /// Chunks of the response received so far.
+    buffer: String,
+}
+
+impl Client {
+    pub async fn handle_authenticate_message(&mut self, data: String) -> Result<(), Reply> {
+        if !self.caps.has(caps::SASL) {
+            return Err(Reply::ErrSaslFail);
+        }
+        if data == "*" {
+            self.sasl = None;
+            return Err(Reply::ErrSaslAborted);
+        }
+        let session = match self.sasl.as_mut() {
+            Some(session) => session,
+            None => return self.start_sasl(&data),
+        };
+        if data.len() > CHUNK_LEN {
+            self.sasl = None;
+            return Err(Reply::ErrSaslTooLong);
+        }
+        if data != "+" {
+            session.buffer.push_str(&data);
+        }
+        if session.buffer.len() > MAX_RESPONSE {
+            self.sasl = None;
+            return Err(Reply::ErrSaslTooLong);
+        }
+        if data.len() == CHUNK_LEN {
+            return Ok(());
+        }
+        let mut session = self.sasl.take().unwrap();
+        let response = match STANDARD.decode(&session.buffer) {
+            Ok(response) => response,
+            Err(_) => return Err(Reply::ErrSaslFail),
+        };
+        session.buffer.clear();
+        let certfp = self.tls.as_ref().and_then(|tls| tls.certfp.clone());
+        let server = self.server.clone();
+        // PLAIN derives a key from the password, which takes a while.
+        let step = tokio::task::spawn_blocking(move || {
+            let context = SaslContext {
+                accounts: server.accounts(),
+                certfp: certfp.as_ref(),
+            };
+            let step = session.mechanism.step(&context, &response);
+            (session, step)
+        });
+        let (session, step) = match step.await {
+            Ok(stepped) => stepped,
+            Err(_) => return Err(Reply::ErrSaslFail),
+        };
+        match step {
+            Step::Challenge(challenge) => {
+                self.send_sasl(&challenge);
+                self.sasl = Some(session);
+                Ok(())
+            }
+            Step::Success(account) => {
+                self.log_in(account).await;
+                let _ = self.send(Reply::SaslSuccess);
+                Ok(())
+            }
+            Step::Failure => {
+                self.login_failed();
+                Err(Reply::ErrSaslFail)
+            }
+        }
+    }
+
+    fn start_sasl(&mut self, name: &str) -> Result<(), Reply> {
+        if self.account.is_some() {
+            return Err(Reply::ErrSaslAlready);
+        }
+        let mechanism = match sasl::start(name) {
+            Some(mechanism) => mechanism,
+            None => {
+                let _ = self.send(Reply::SaslMechs(sasl::mechanism_names()));
+                return Err(Reply::ErrSaslFail);
+            }
+        };
+        self.sasl = Some(SaslSession {
+            mechanism,
+            buffer: String::new(),
+        });
+        self.send_sasl(&[]);
+        Ok(())
+    }
+
+    /// Sends a challenge base64 encoded in as many chunks as it takes.
+    fn send_sasl(&self, challenge: &[u8]) {
+        let encoded = STANDARD.encode(challenge);
+        let mut rest = encoded.as_str();
+        while rest.len() >= CHUNK_LEN {
+            let (chunk, tail) = rest.split_at(CHUNK_LEN);
+            let _ = self.send(Command::Authenticate(chunk));
+            rest = tail;
+        }
+        let last = if rest.is_empty() { "+" } else { rest };
+        let _ = self.send(Command::Authenticate(last));
+    }
+
+    /// Ends an unfinished exchange, for when the client moves on without it.
+    pub(super) fn abort_sasl(&mut self) {
+        if self.sasl.take().is_some() {
+            let _ = self.send(Reply::ErrSaslAborted);
+        }
+    }
+}
@@ -4,23 +4,549 @@ use figment::{
     providers::{Serialized, Yaml},
     Figment,
 };
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ListenConfig {
-    pub address: SocketAddr,
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// Listen on a Unix domain socket at this path instead of `address`. Connections through
+    /// it are trusted as local, TLS and WebSocket settings don't apply.
+    pub path: Option<String>,
+    /// Permissions of the socket file at `path`.
+    #[serde(default = "def_path_mode")]
+    pub path_mode: u32,
+    /// Hostname given to clients connecting through `path`.
+    #[serde(default = "def_local_hostname")]
+    pub local_hostname: String,
     #[serde(default = "def_false")]
     pub tls: bool,
     pub tls_cert: Option<String>,
     pub tls_key: Option<String>,
+    #[serde(default = "def_class")]
+    pub class: String,
+    /// Seconds a connection has to complete its TLS handshake.
+    #[serde(default = "def_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Connections that may be in their PROXY, TLS or WebSocket handshake at once. Further
+    /// connections wait to be accepted until one finishes.
+    #[serde(default = "def_max_handshakes")]
+    pub max_handshakes: usize,
+    /// TLS library used for this listener, rustls is preferred when it is compiled in.
+    /// Only rustls asks clients for a certificate, so CertFP needs the rustls backend.
+    pub tls_backend: Option<TlsBackend>,
+    /// Protocols offered through ALPN, only supported by the rustls backend.
+    #[serde(default)]
+    pub tls_alpn: Vec<String>,
+    #[serde(default = "def_tls_min_version")]
+    pub tls_min_version: TlsVersion,
+    /// Speak the IRCv3 WebSocket transport instead of raw IRC.
+    #[serde(default = "def_false")]
+    pub websocket: bool,
+    /// Origins browsers may open a WebSocket from, any origin is accepted when empty.
+    #[serde(default)]
+    pub websocket_origins: Vec<String>,
+    /// Expect a PROXY protocol v1 or v2 header on every connection, ahead of TLS.
+    #[serde(default = "def_false")]
+    pub proxy_protocol: bool,
+    /// Proxies allowed to tell us the client's real address, through X-Forwarded-For or
+    /// the PROXY protocol. Only these may connect when `proxy_protocol` is set.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsBackend {
+    NativeTls,
+    Rustls,
+}
+
+impl Default for TlsBackend {
+    fn default() -> Self {
+        if cfg!(feature = "rustls") {
+            TlsBackend::Rustls
+        } else {
+            TlsBackend::NativeTls
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl ListenConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    /// Whether clients connect through native-tls here, directly or with STARTTLS.
+    pub fn uses_native_tls(&self) -> bool {
+        let starttls = !self.websocket && (self.tls_cert.is_some() || self.tls_key.is_some());
+        self.path.is_none()
+            && (self.tls || starttls)
+            && self.tls_backend.unwrap_or_default() == TlsBackend::NativeTls
+    }
+}
+
+/// Settings shared by every connection accepted through listeners of the same class.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClassConfig {
+    /// Seconds of inbound silence before the server sends a PING.
+    #[serde(default = "def_ping_interval")]
+    pub ping_interval: u64,
+    /// Seconds a client has to answer a PING before it is disconnected.
+    #[serde(default = "def_ping_timeout")]
+    pub ping_timeout: u64,
+    /// Send a random PING cookie on connect that must be echoed before registration completes.
+    #[serde(default = "def_false")]
+    pub ping_cookie: bool,
+    /// Seconds an unregistered client has to echo the PING cookie.
+    #[serde(default = "def_ping_cookie_timeout")]
+    pub ping_cookie_timeout: u64,
+    /// Bytes that may be queued for a client before it is disconnected.
+    #[serde(default = "def_sendq")]
+    pub sendq: usize,
+    /// Bytes queued before operators are warned about a slow client.
+    #[serde(default = "def_sendq_soft")]
+    pub sendq_soft: usize,
+    /// Commands a client may send back to back before fakelag starts delaying them.
+    #[serde(default = "def_flood_burst")]
+    pub flood_burst: u32,
+    /// Commands a client may be behind before it is disconnected for flooding.
+    #[serde(default = "def_flood_limit")]
+    pub flood_limit: u32,
+    /// Milliseconds of fakelag added per unit of command cost.
+    #[serde(default = "def_flood_interval")]
+    pub flood_interval: u64,
+    /// Cost of each command by name, commands not listed cost 1.
+    #[serde(default)]
+    pub flood_costs: HashMap<String, u32>,
+    /// Skip flood control entirely, for trusted bots and services.
+    #[serde(default = "def_false")]
+    pub flood_exempt: bool,
+    /// Maximum number of clients connected through this class.
+    pub max_clients: Option<usize>,
+    /// Concurrent connections allowed from a single address, unlimited if unset.
+    pub max_per_ip: Option<usize>,
+    /// Concurrent connections allowed from a single network, see `cidr_v4` and `cidr_v6`.
+    /// Unlimited if unset.
+    pub max_per_cidr: Option<usize>,
+    #[serde(default = "def_cidr_v4")]
+    pub cidr_v4: u8,
+    #[serde(default = "def_cidr_v6")]
+    pub cidr_v6: u8,
+    /// Connections an address may make within `throttle_period` seconds, unlimited if
+    /// unset.
+    pub throttle_count: Option<usize>,
+    #[serde(default = "def_throttle_period")]
+    pub throttle_period: u64,
+    #[serde(skip)]
+    pub name: String,
+}
+
+impl ClassConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout)
+    }
+
+    pub fn ping_cookie_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_cookie_timeout)
+    }
+
+    pub fn flood_interval(&self) -> Duration {
+        Duration::from_millis(self.flood_interval)
+    }
+
+    pub fn throttle_period(&self) -> Duration {
+        Duration::from_secs(self.throttle_period)
+    }
+
+    pub fn flood_cost(&self, command: &str) -> u32 {
+        self.flood_costs.get(command).copied().unwrap_or(1)
+    }
+}
+
+impl Default for ClassConfig {
+    fn default() -> Self {
+        ClassConfig {
+            ping_interval: def_ping_interval(),
+            ping_timeout: def_ping_timeout(),
+            ping_cookie: false,
+            ping_cookie_timeout: def_ping_cookie_timeout(),
+            sendq: def_sendq(),
+            sendq_soft: def_sendq_soft(),
+            flood_burst: def_flood_burst(),
+            flood_limit: def_flood_limit(),
+            flood_interval: def_flood_interval(),
+            flood_costs: HashMap::new(),
+            flood_exempt: false,
+            max_clients: None,
+            max_per_ip: None,
+            max_per_cidr: None,
+            cidr_v4: def_cidr_v4(),
+            cidr_v6: def_cidr_v6(),
+            throttle_count: None,
+            throttle_period: def_throttle_period(),
+            name: def_class(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OperConfig {
+    pub password: String,
+    /// SHA-256 or SHA-512 fingerprint the operator's client certificate must match.
+    pub certfp: Option<String>,
+    #[serde(default)]
+    pub privileges: Vec<OperPrivilege>,
+}
+
+/// An account users can log in to with SASL.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccountConfig {
+    /// Password for SASL PLAIN and SCRAM-SHA-256, only salted SCRAM credentials derived
+    /// from it are kept.
+    pub password: Option<String>,
+    /// SHA-256 or SHA-512 fingerprints of client certificates that log in with SASL
+    /// EXTERNAL.
+    #[serde(default)]
+    pub certfp: Vec<String>,
+}
+
+/// Accounts users register themselves, with REGISTER or by messaging NickServ.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RegistrationConfig {
+    /// Whether new accounts may be registered, existing ones work either way.
+    #[serde(default = "def_true")]
+    pub enabled: bool,
+    /// Seconds a user has to identify for a registered nick before it is changed.
+    #[serde(default = "def_nick_grace_period")]
+    pub grace_period: u64,
+    /// Shortest password accepted for a new account.
+    #[serde(default = "def_min_password")]
+    pub min_password: usize,
+}
+
+impl RegistrationConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        RegistrationConfig {
+            enabled: def_true(),
+            grace_period: def_nick_grace_period(),
+            min_password: def_min_password(),
+        }
+    }
+}
+
+/// Where registered accounts and channels are kept across restarts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoreConfig {
+    /// Directory holding a snapshot and the log of changes made since.
+    pub path: String,
+    /// Changes logged before they are folded into a new snapshot.
+    #[serde(default = "def_snapshot_every")]
+    pub snapshot_every: usize,
+}
+
+/// What an operator may do beyond the basics every operator gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperPrivilege {
+    /// See the real host of cloaked users in WHOIS.
+    SeeHidden,
+    /// Give themselves and others a vhost with SETHOST and CHGHOST.
+    SetHost,
+}
+
+/// A host shown for every client whose real `nick!user@host` matches one of the masks.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VhostConfig {
+    pub host: String,
+    pub masks: Vec<String>,
+}
+
+/// Hides client hosts behind keyed hashes while they have user mode +x.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CloakConfig {
+    /// Secret keys the cloaks are derived from, cloaking is off without any. Changing them
+    /// changes every cloak.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Put in front of cloaked hostnames.
+    #[serde(default = "def_cloak_prefix")]
+    pub prefix: String,
+    /// Whether clients get +x when they connect.
+    #[serde(default = "def_true")]
+    pub default: bool,
+}
+
+impl Default for CloakConfig {
+    fn default() -> Self {
+        CloakConfig {
+            keys: Vec::new(),
+            prefix: def_cloak_prefix(),
+            default: def_true(),
+        }
+    }
+}
+
+/// Hostname lookups for connecting clients.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsConfig {
+    /// Seconds the reverse and forward lookups get together before the client is given
+    /// its IP address instead.
+    #[serde(default = "def_dns_timeout")]
+    pub timeout: u64,
+    /// Longest a confirmed hostname is cached for in seconds, shorter record TTLs win.
+    #[serde(default = "def_dns_cache_ttl")]
+    pub cache_ttl: u64,
+    /// Nameservers to query instead of the system's.
+    #[serde(default)]
+    pub nameservers: Vec<SocketAddr>,
+}
+
+impl DnsConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl)
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            timeout: def_dns_timeout(),
+            cache_ttl: def_dns_cache_ttl(),
+            nameservers: Vec::new(),
+        }
+    }
+}
+
+/// DNS blocklists checked for every connecting client.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsblConfig {
+    #[serde(default)]
+    pub zones: HashMap<String, DnsblZone>,
+    /// Networks that are never checked.
+    #[serde(default)]
+    pub exempt: Vec<IpNet>,
+    /// Seconds the result for an address is reused for.
+    #[serde(default = "def_dnsbl_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+impl DnsblConfig {
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl)
+    }
+}
+
+impl Default for DnsblConfig {
+    fn default() -> Self {
+        DnsblConfig {
+            zones: HashMap::new(),
+            exempt: Vec::new(),
+            cache_ttl: def_dnsbl_cache_ttl(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsblZone {
+    /// The zone queried, like `dnsbl.example.org`.
+    pub zone: String,
+    /// What to do for each reply address, replies not listed are ignored.
+    pub replies: HashMap<Ipv4Addr, DnsblAction>,
+    /// Told to the client and operators, `{ip}`, `{zone}` and `{reply}` are filled in.
+    #[serde(default = "def_dnsbl_reason")]
+    pub reason: String,
+}
+
+/// Ordered from mildest to harshest, the harshest action of all hits is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsblAction {
+    /// Let the client in but show the reason to operators in WHOIS.
+    Mark,
+    /// Only let the client in once it has authenticated with SASL.
+    RequireSasl,
+    Reject,
+}
+
+/// RFC 1413 lookups for connecting clients, run alongside the hostname lookup.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdentConfig {
+    #[serde(default = "def_true")]
+    pub enabled: bool,
+    /// Port of the ident server on the client's host.
+    #[serde(default = "def_ident_port")]
+    pub port: u16,
+    /// Seconds to wait for the ident server before giving up.
+    #[serde(default = "def_ident_timeout")]
+    pub timeout: u64,
+}
+
+impl IdentConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for IdentConfig {
+    fn default() -> Self {
+        IdentConfig {
+            enabled: true,
+            port: def_ident_port(),
+            timeout: def_ident_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebIrcConfig {
+    pub password: String,
+    /// Addresses the gateway connects from.
+    pub hosts: Vec<IpNet>,
 }
 
 fn def_false() -> bool {
     false
 }
 
+fn def_true() -> bool {
+    true
+}
+
+fn def_class() -> String {
+    "default".to_string()
+}
+
+fn def_handshake_timeout() -> u64 {
+    10
+}
+
+fn def_max_handshakes() -> usize {
+    64
+}
+
+fn def_dns_timeout() -> u64 {
+    5
+}
+
+fn def_dns_cache_ttl() -> u64 {
+    3600
+}
+
+fn def_cloak_prefix() -> String {
+    "pawpaw".to_string()
+}
+
+fn def_dnsbl_cache_ttl() -> u64 {
+    600
+}
+
+fn def_dnsbl_reason() -> String {
+    "Your address {ip} is listed in {zone}".to_string()
+}
+
+fn def_ident_port() -> u16 {
+    113
+}
+
+fn def_ident_timeout() -> u64 {
+    3
+}
+
+fn def_nick_grace_period() -> u64 {
+    60
+}
+
+fn def_min_password() -> usize {
+    8
+}
+
+fn def_snapshot_every() -> usize {
+    1000
+}
+
+fn def_path_mode() -> u32 {
+    0o660
+}
+
+fn def_local_hostname() -> String {
+    "localhost".to_string()
+}
+
+fn def_tls_min_version() -> TlsVersion {
+    TlsVersion::Tls12
+}
+
+fn def_ping_interval() -> u64 {
+    120
+}
+
+fn def_ping_timeout() -> u64 {
+    30
+}
+
+fn def_ping_cookie_timeout() -> u64 {
+    15
+}
+
+fn def_sendq() -> usize {
+    1024 * 1024
+}
+
+fn def_sendq_soft() -> usize {
+    768 * 1024
+}
+
+fn def_flood_burst() -> u32 {
+    10
+}
+
+fn def_flood_limit() -> u32 {
+    30
+}
+
+fn def_flood_interval() -> u64 {
+    1000
+}
+
+fn def_cidr_v4() -> u8 {
+    24
+}
+
+fn def_cidr_v6() -> u8 {
+    64
+}
+
+fn def_throttle_period() -> u64 {
+    60
+}
+
 #[derive(Parser, Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     #[clap(short, long, value_parser, default_value = "config.yml")]
@@ -33,6 +559,49 @@ pub struct Config {
     pub motd: String,
     #[clap(skip)]
     pub listeners: HashMap<String, ListenConfig>,
+    #[clap(skip)]
+    #[serde(default)]
+    pub classes: HashMap<String, ClassConfig>,
+    #[clap(skip)]
+    #[serde(default)]
+    pub opers: HashMap<String, OperConfig>,
+    #[clap(skip)]
+    #[serde(default)]
+    pub accounts: HashMap<String, AccountConfig>,
+    #[clap(skip)]
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+    #[clap(skip)]
+    #[serde(default)]
+    pub store: Option<StoreConfig>,
+    #[clap(skip)]
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[clap(skip)]
+    #[serde(default)]
+    pub ident: IdentConfig,
+    #[clap(skip)]
+    #[serde(default)]
+    pub dnsbl: DnsblConfig,
+    #[clap(skip)]
+    #[serde(default)]
+    pub cloak: CloakConfig,
+    #[clap(skip)]
+    #[serde(default)]
+    pub vhosts: Vec<VhostConfig>,
+    /// Gateways allowed to pass on their users' real address with WEBIRC.
+    #[clap(skip)]
+    #[serde(default)]
+    pub webirc: HashMap<String, WebIrcConfig>,
+    /// Networks that bypass per-address connection limits and throttling.
+    #[clap(skip)]
+    #[serde(default)]
+    pub throttle_exempt: Vec<IpNet>,
+    /// Seconds between checks of the TLS certificate and key files, listeners are reloaded
+    /// when a file changes. Unset disables the watcher, SIGHUP and REHASH still work.
+    #[clap(skip)]
+    #[serde(default)]
+    pub tls_watch: Option<u64>,
 }
 
 pub fn load_config() -> Config {
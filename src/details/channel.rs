@@ -1,7 +1,7 @@
 use std::fmt;
 use std::fmt::Formatter;
 use std::sync::Arc;
-use crate::details::modes::ChannelMode;
+use crate::details::modes::{matches_mask, ChannelMode};
 use uuid::Uuid;
 use crate::proto;
 use crate::proto::{Prefix, Reply};
@@ -35,6 +35,38 @@ impl ChannelUser {
             chat_allowed: false,
         }
     }
+
+    /// A member who is given status on joining, as registered channels do.
+    pub fn with_status(uuid: Uuid, nick: String, is_oper: bool, chat_allowed: bool) -> Self {
+        ChannelUser {
+            uuid,
+            nick,
+            is_oper,
+            chat_allowed,
+        }
+    }
+}
+
+impl ChannelUser {
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.is_oper
+    }
+
+    /// The channel modes that give this member its status.
+    pub fn status_modes(&self) -> String {
+        let mut modes = String::new();
+        if self.is_oper {
+            modes.push('o');
+        }
+        if self.chat_allowed {
+            modes.push('v');
+        }
+        modes
+    }
 }
 
 impl fmt::Display for ChannelUser {
@@ -66,14 +98,90 @@ impl Channel {
         }
     }
 
+    /// A channel nobody is in yet, as a registered channel was left.
+    pub fn restore(
+        name: String,
+        topic: Option<String>,
+        flags: &str,
+        bans: &[String],
+        exceptions: &[String],
+    ) -> Self {
+        let mut mode = ChannelMode::default();
+        mode.set_flags(flags);
+        for ban in bans {
+            mode.add_ban(ban.clone());
+        }
+        for exception in exceptions {
+            mode.add_exception(exception.clone());
+        }
+        Self {
+            name,
+            clients: Vec::new(),
+            mode,
+            topic,
+        }
+    }
+
     pub fn add_client(&mut self, uuid: Uuid, nick: String) {
         self.clients.push(ChannelUser::new(uuid, nick));
     }
 
+    pub fn add_member(&mut self, member: ChannelUser) {
+        self.clients.push(member);
+    }
+
+    /// Shows the member `uuid` under its new `nick`.
+    pub fn rename_client(&mut self, uuid: &Uuid, nick: &str) {
+        for client in self.clients.iter_mut().filter(|client| client.uuid == *uuid) {
+            client.nick = nick.to_owned();
+        }
+    }
+
     pub fn get_clients(&self) -> &Vec<ChannelUser> {
         &self.clients
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_member(&self, uuid: &Uuid) -> bool {
+        self.clients.iter().any(|client| client.uuid == *uuid)
+    }
+
+    pub fn member(&self, uuid: &Uuid) -> Option<&ChannelUser> {
+        self.clients.iter().find(|client| client.uuid == *uuid)
+    }
+
+    pub fn is_operator(&self, uuid: &Uuid) -> bool {
+        self.clients.iter().any(|client| client.uuid == *uuid && client.is_oper)
+    }
+
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    pub fn set_topic(&mut self, topic: Option<String>) {
+        self.topic = topic;
+    }
+
+    pub fn mode(&self) -> &ChannelMode {
+        &self.mode
+    }
+
+    pub fn mode_mut(&mut self) -> &mut ChannelMode {
+        &mut self.mode
+    }
+
+    /// Whether any ban matches any of the client's `masks` and no exception does.
+    pub fn is_banned(&self, masks: &[String]) -> bool {
+        let matches = |list: &[String]| {
+            list.iter()
+                .any(|set| masks.iter().any(|mask| matches_mask(set, mask)))
+        };
+        matches(self.mode.bans()) && !matches(self.mode.exceptions())
+    }
+
     pub fn reply_topic(&self) -> Reply {
         if let Some(topic) = &self.topic {
             Reply::Topic(self.name.clone(), topic.clone())
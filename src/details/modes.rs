@@ -1,3 +1,6 @@
+/// Channel modes that are simply on or off, which operators may change.
+pub const FLAG_MODES: &str = "inpst";
+
 #[derive(Debug)]
 pub struct ChannelMode {
     private: bool,
@@ -6,8 +9,9 @@ pub struct ChannelMode {
     topic_oper_only: bool,
     no_outside_messages: bool,
     limit: i32,
-    //TODO: Replace this with some kind of ban mask type.
     ban_mask: Vec<String>,
+    /// Masks the bans don't apply to.
+    exceptions: Vec<String>,
     key: Option<String>,
 }
 
@@ -29,7 +33,151 @@ impl ChannelMode {
             no_outside_messages: true,
             limit: 0,
             ban_mask: Vec::new(),
+            exceptions: Vec::new(),
             key: None,
         }
     }
 }
+
+impl ChannelMode {
+    /// The set flags as shown in RPL_CHANNELMODEIS, without the key.
+    pub fn flags(&self) -> String {
+        let mut flags = "+".to_string();
+        for (set, flag) in [
+            (self.invite_only, 'i'),
+            (self.no_outside_messages, 'n'),
+            (self.private, 'p'),
+            (self.secret, 's'),
+            (self.topic_oper_only, 't'),
+            (self.key.is_some(), 'k'),
+            (self.limit > 0, 'l'),
+        ] {
+            if set {
+                flags.push(flag);
+            }
+        }
+        flags
+    }
+
+    /// Sets or unsets one of `FLAG_MODES`, returning whether it changed.
+    pub fn set_flag(&mut self, flag: char, set: bool) -> bool {
+        let current = match flag {
+            'i' => &mut self.invite_only,
+            'n' => &mut self.no_outside_messages,
+            'p' => &mut self.private,
+            's' => &mut self.secret,
+            't' => &mut self.topic_oper_only,
+            _ => return false,
+        };
+        let changed = *current != set;
+        *current = set;
+        changed
+    }
+
+    /// Sets exactly the `FLAG_MODES` in `flags`, as given by `flags()`.
+    pub fn set_flags(&mut self, flags: &str) {
+        for flag in FLAG_MODES.chars() {
+            self.set_flag(flag, flags.contains(flag));
+        }
+    }
+
+    pub fn topic_oper_only(&self) -> bool {
+        self.topic_oper_only
+    }
+
+    pub fn bans(&self) -> &[String] {
+        &self.ban_mask
+    }
+
+    /// Adds a ban unless an equal mask is already set.
+    pub fn add_ban(&mut self, mask: String) -> bool {
+        add_mask(&mut self.ban_mask, mask)
+    }
+
+    pub fn remove_ban(&mut self, mask: &str) -> bool {
+        remove_mask(&mut self.ban_mask, mask)
+    }
+
+    pub fn exceptions(&self) -> &[String] {
+        &self.exceptions
+    }
+
+    /// Adds a ban exception unless an equal mask is already set.
+    pub fn add_exception(&mut self, mask: String) -> bool {
+        add_mask(&mut self.exceptions, mask)
+    }
+
+    pub fn remove_exception(&mut self, mask: &str) -> bool {
+        remove_mask(&mut self.exceptions, mask)
+    }
+}
+
+fn add_mask(list: &mut Vec<String>, mask: String) -> bool {
+    if list.iter().any(|set| set.eq_ignore_ascii_case(&mask)) {
+        return false;
+    }
+    list.push(mask);
+    true
+}
+
+fn remove_mask(list: &mut Vec<String>, mask: &str) -> bool {
+    let len = list.len();
+    list.retain(|set| !set.eq_ignore_ascii_case(mask));
+    list.len() != len
+}
+
+/// Matches `nick!user@host` against a mask where `*` stands for any run of characters and
+/// `?` for exactly one, ignoring ASCII case.
+pub fn matches_mask(mask: &str, target: &str) -> bool {
+    let mask = mask.as_bytes();
+    let target = target.as_bytes();
+    let (mut m, mut t) = (0, 0);
+    // Where the last `*` was and how much of the target it had taken when we backtrack.
+    let mut star = None;
+    while t < target.len() {
+        if m < mask.len() && (mask[m] == b'?' || mask[m].eq_ignore_ascii_case(&target[t])) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == b'*' {
+            star = Some((m, t));
+            m += 1;
+        } else if let Some((star_m, star_t)) = star {
+            m = star_m + 1;
+            t = star_t + 1;
+            star = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn mask_matching() {
+        assert!(matches_mask("*!*@*.example.com", "nick!user@host.Example.com"));
+        assert!(matches_mask("nick!*@*", "NICK!user@host"));
+        assert!(matches_mask("n?ck!*", "nick!user@host"));
+        assert!(matches_mask("*", ""));
+        assert!(!matches_mask("*!*@*.example.com", "nick!user@example.com"));
+        assert!(!matches_mask("nick!user@host", "nick!user@host2"));
+        assert!(matches_mask("*a*b*c", "xaxxbxxc"));
+        assert!(!matches_mask("*a*b*c", "xaxxcxxb"));
+    }
+
+    #[test]
+    pub fn flags() {
+        let mut mode = ChannelMode::default();
+        assert_eq!(mode.flags(), "+nt");
+        assert!(mode.set_flag('s', true));
+        assert!(!mode.set_flag('s', true));
+        assert!(mode.set_flag('n', false));
+        assert!(!mode.set_flag('b', true));
+        assert_eq!(mode.flags(), "+st");
+        mode.set_flags("+in");
+        assert_eq!(mode.flags(), "+in");
+    }
+}
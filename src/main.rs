@@ -1,11 +1,14 @@
 extern crate core;
 
 use crate::config::load_config;
+use crate::server::dns::ip_hostname;
 use crate::server::Server;
 use env_logger::Env;
-use log::error;
+use log::{debug, error, info};
 use std::sync::Arc;
 use tokio::runtime::Builder;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 mod client;
 mod config;
@@ -14,29 +17,18 @@ mod proto;
 mod server;
 
 macro_rules! return_err {
-    ($expression:expr) => {
+    ($client:expr, $expression:expr) => {
         match $expression {
             Ok(v) => v,
             Err(e) => {
                 error!("client err: {}", e);
+                $client.quit(&e.to_string()).await;
                 return;
             }
         }
     };
 }
 
-macro_rules! break_err {
-    ($expression:expr) => {
-        match $expression {
-            Ok(v) => v,
-            Err(e) => {
-                error!("client err: {}", e);
-                break;
-            }
-        }
-    };
-}
-
 fn main() {
     if cfg!(debug_assertions) {
         env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
@@ -51,25 +43,97 @@ fn main() {
             .expect("Failed to construct server");
         server.server_loop().await;
         let server = Arc::new(server);
+        #[cfg(unix)]
+        {
+            let server = server.clone();
+            let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    info!("Received SIGHUP, rehashing");
+                    server.rehash().await;
+                }
+            });
+        }
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        server.spawn_tls_watcher();
         loop {
             let mut client = server.accept().await.expect("Failed to accept client");
             let server = server.clone();
             tokio::spawn(async move {
-                return_err!(client.send_notice("*** Attempting lookup of your hostname..."));
-                return_err!(client.poll_nowait().await);
-                let hostname = match server.resolver().reverse_lookup(client.address()).await {
-                    Ok(val) => val
-                        .iter()
-                        .nth(0)
-                        .expect("Failed to get hostname from hostname??")
-                        .to_string(),
-                    Err(e) => client.address().to_string(),
-                };
-                client.set_hostname(hostname.clone());
-                return_err!(client.send_notice(format!("*** Found hostname using {}.", hostname)));
-                return_err!(client.poll_nowait().await);
+                // Local connections already have their hostname from the listener.
+                if client.hostname.is_empty() {
+                    return_err!(client, client.send_notice("*** Looking up your hostname..."));
+                    let ident = client.ident_query().filter(|_| server.ident().enabled());
+                    if ident.is_some() {
+                        return_err!(client, client.send_notice("*** Checking Ident"));
+                    }
+                    return_err!(client, client.poll_nowait().await);
+                    let ip = client.address();
+                    // WEBIRC in the first read has already named the client and checked the
+                    // address it passed on.
+                    let webirc = !client.hostname.is_empty();
+                    let (lookup, ident, dnsbl) = tokio::join!(
+                        async {
+                            if webirc {
+                                None
+                            } else {
+                                Some(server.dns().lookup(ip).await)
+                            }
+                        },
+                        async {
+                            match ident {
+                                Some((remote, local)) => Some(server.ident().lookup(remote, local).await),
+                                None => None,
+                            }
+                        },
+                        async {
+                            if webirc {
+                                Ok(())
+                            } else {
+                                client.check_dnsbl().await
+                            }
+                        }
+                    );
+                    if let Err(e) = dnsbl {
+                        client.quit(&e.to_string()).await;
+                        return;
+                    }
+                    if let Some(lookup) = lookup {
+                        let notice = match lookup {
+                            Ok(hostname) => {
+                                client.set_hostname(hostname.name);
+                                if hostname.cached {
+                                    "*** Found your hostname (cached)".to_owned()
+                                } else {
+                                    "*** Found your hostname".to_owned()
+                                }
+                            }
+                            Err(e) => {
+                                client.set_hostname(ip_hostname(ip));
+                                format!("*** {}", e)
+                            }
+                        };
+                        return_err!(client, client.send_notice(notice));
+                    }
+                    // WEBIRC also makes the gateway's ident reply meaningless.
+                    match ident.filter(|_| client.ident_query().is_some()) {
+                        Some(Ok(user)) => {
+                            client.set_ident(user);
+                            return_err!(client, client.send_notice("*** Got Ident response"));
+                        }
+                        Some(Err(e)) => {
+                            debug!("ident: {}", e);
+                            return_err!(client, client.send_notice("*** No Ident response"));
+                        }
+                        None => {}
+                    }
+                    return_err!(client, client.poll_nowait().await);
+                }
                 loop {
-                    break_err!(client.poll().await);
+                    if let Err(e) = client.poll().await {
+                        client.quit(&e.to_string()).await;
+                        break;
+                    }
                 }
             });
         }
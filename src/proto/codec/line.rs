@@ -21,6 +21,11 @@ impl LineCodec {
             .ok_or_else(|| ProtocolError::UnsupportedEncoding(label.to_string()))
     }
 
+    /// Forgets any partially scanned line, for when the buffer it pointed into is gone.
+    pub fn reset(&mut self) {
+        self.next_index = 0;
+    }
+
     pub fn name(&self) -> &str {
         self.encoding.name()
     }
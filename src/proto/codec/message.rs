@@ -4,10 +4,23 @@ use crate::proto::message::Message;
 use bytes::BytesMut;
 use tokio_util::codec::{Decoder, Encoder};
 
-#[cfg(ircv3)]
-pub const MESSAGE_LINE_LENGTH: usize = 4096 + 512;
-#[cfg(not(ircv3))]
+/// Longest message without its tags, including the CRLF.
 pub const MESSAGE_LINE_LENGTH: usize = 512;
+/// Longest tag section, including the leading `@` and the space after it.
+pub const TAG_LENGTH: usize = 8191;
+
+/// Checks a line, without its CRLF, against the separate budgets for tags and the rest of
+/// the message.
+fn within_budget(line: &str) -> bool {
+    let (tags, body) = match line.strip_prefix('@') {
+        Some(rest) => match rest.split_once(' ') {
+            Some((tags, body)) => (tags.len() + 2, body),
+            None => (line.len(), ""),
+        },
+        None => (0, line),
+    };
+    tags <= TAG_LENGTH && body.len() + 2 <= MESSAGE_LINE_LENGTH
+}
 
 #[derive(Debug)]
 pub struct MessageCodec {
@@ -16,9 +29,13 @@ pub struct MessageCodec {
 
 impl MessageCodec {
     pub fn new(label: &str) -> Result<MessageCodec, ProtocolError> {
-        let inner = LineCodec::new(label, MESSAGE_LINE_LENGTH)?;
+        let inner = LineCodec::new(label, TAG_LENGTH + MESSAGE_LINE_LENGTH)?;
         Ok(MessageCodec { inner })
     }
+
+    pub fn reset(&mut self) {
+        self.inner.reset();
+    }
 }
 
 ///
@@ -33,6 +50,7 @@ impl Decoder for MessageCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match self.inner.decode(src)? {
+            Some(val) if !within_budget(&val) => Ok(Some(Err(ProtocolError::InputTooLong))),
             Some(val) => {
                 let v = val.parse::<Message>();
                 Ok(Some(v))
@@ -46,6 +64,49 @@ impl Encoder<Message> for MessageCodec {
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let item = item.to_string();
+        if !within_budget(&item) {
+            return Err(ProtocolError::MaxLineLengthExceeded);
+        }
         self.inner.encode(item, dst)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    fn decode(line: &str) -> Result<Message, ProtocolError> {
+        let mut codec = MessageCodec::new("utf-8").unwrap();
+        let mut src = BytesMut::new();
+        src.put_slice(line.as_bytes());
+        src.put_slice(b"\r\n");
+        codec.decode(&mut src).unwrap().unwrap()
+    }
+
+    #[test]
+    pub fn tags_have_their_own_budget() {
+        let body = format!("NOTICE * :{}", "a".repeat(MESSAGE_LINE_LENGTH - 12));
+        let tags = format!("@+long={}", "b".repeat(TAG_LENGTH - 8));
+        assert!(decode(&format!("{} {}", tags, body)).is_ok());
+        assert!(matches!(
+            decode(&format!("{}b {}", tags, body)),
+            Err(ProtocolError::InputTooLong)
+        ));
+        assert!(matches!(
+            decode(&format!("{} {}a", tags, body)),
+            Err(ProtocolError::InputTooLong)
+        ));
+    }
+
+    #[test]
+    pub fn encoding_keeps_tags() {
+        let mut codec = MessageCodec::new("utf-8").unwrap();
+        let mut message: Message = crate::proto::Command::Notice("*", "hi").into();
+        message.tags.insert("+a", "b c");
+        let mut dst = BytesMut::new();
+        codec.encode(message.clone(), &mut dst).unwrap();
+        assert_eq!(&dst[..], b"@+a=b\\sc NOTICE * hi\r\n");
+        assert_eq!(decode("@+a=b\\sc NOTICE * hi").unwrap(), message);
+    }
+}
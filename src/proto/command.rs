@@ -16,6 +16,39 @@ pub enum Command {
     PONG(String, Option<String>),
     /* Channels */
     JOIN(Vec<String>, Option<Vec<String>>),
+    /* Target, mode string and its arguments */
+    MODE(String, Vec<String>),
+    /* Channel, new topic */
+    TOPIC(String, Option<String>),
+
+    /* Connection */
+    QUIT(Option<String>),
+    ERROR(String),
+    STARTTLS,
+    /* Password, gateway, hostname, ip, options */
+    WEBIRC(String, String, String, String, Option<String>),
+    /* Base64 encoded chunk, or the mechanism to start with */
+    AUTHENTICATE(String),
+    /* Target, subcommand, more lines follow, parameter */
+    CAP(Option<String>, String, bool, Option<String>),
+    /* Account, email and password from clients, SUCCESS, account and message from the server */
+    REGISTER(String, String, String),
+    /* Account, code */
+    VERIFY(String, String),
+    /* Command, code, context, description */
+    FAIL(String, String, Vec<String>, String),
+
+    /* Operators */
+    OPER(String, String),
+    /* Host */
+    SETHOST(String),
+    /* Nick and host from clients, user and host from the server */
+    CHGHOST(String, String),
+    STATS(Option<String>, Option<String>),
+    REHASH,
+
+    /* User queries */
+    WHOIS(Option<String>, String),
 
     RAW(String),
 }
@@ -57,6 +90,97 @@ impl Command {
         )
     }
 
+    pub fn Mode<S: Into<String>>(target: S, params: Vec<S>) -> Command {
+        Command::MODE(
+            target.into(),
+            params.into_iter().map(|s| s.into()).collect(),
+        )
+    }
+
+    pub fn Topic<S: Into<String>>(channel: S, topic: Option<S>) -> Command {
+        Command::TOPIC(channel.into(), topic.map(|s| s.into()))
+    }
+
+    pub fn Quit<S: Into<String>>(reason: Option<S>) -> Command {
+        Command::QUIT(reason.map(|s| s.into()))
+    }
+    pub fn Error<S: Into<String>>(message: S) -> Command {
+        Command::ERROR(message.into())
+    }
+    pub fn StartTls() -> Command {
+        Command::STARTTLS
+    }
+    pub fn WebIrc<S: Into<String>>(
+        password: S,
+        gateway: S,
+        hostname: S,
+        ip: S,
+        options: Option<S>,
+    ) -> Command {
+        Command::WEBIRC(
+            password.into(),
+            gateway.into(),
+            hostname.into(),
+            ip.into(),
+            options.map(|s| s.into()),
+        )
+    }
+    pub fn Cap<S: Into<String>>(target: Option<S>, subcommand: S, param: Option<S>) -> Command {
+        Command::CAP(
+            target.map(|s| s.into()),
+            subcommand.into(),
+            false,
+            param.map(|s| s.into()),
+        )
+    }
+    pub fn Authenticate<S: Into<String>>(data: S) -> Command {
+        Command::AUTHENTICATE(data.into())
+    }
+    pub fn Register<S: Into<String>>(account: S, email: S, password: S) -> Command {
+        Command::REGISTER(account.into(), email.into(), password.into())
+    }
+    pub fn Verify<S: Into<String>>(account: S, code: S) -> Command {
+        Command::VERIFY(account.into(), code.into())
+    }
+    /// A standard reply telling the client `command` failed.
+    pub fn Fail<S: Into<String>>(command: S, code: S, context: Vec<S>, description: S) -> Command {
+        Command::FAIL(
+            command.into(),
+            code.into(),
+            context.into_iter().map(|s| s.into()).collect(),
+            description.into(),
+        )
+    }
+    /// A line of a listing that continues on the next one.
+    pub fn CapContinued<S: Into<String>>(target: S, subcommand: S, param: S) -> Command {
+        Command::CAP(
+            Some(target.into()),
+            subcommand.into(),
+            true,
+            Some(param.into()),
+        )
+    }
+
+    pub fn Oper<S: Into<String>>(name: S, password: S) -> Command {
+        Command::OPER(name.into(), password.into())
+    }
+    pub fn SetHost<S: Into<String>>(host: S) -> Command {
+        Command::SETHOST(host.into())
+    }
+    pub fn ChgHost<S: Into<String>>(target: S, host: S) -> Command {
+        Command::CHGHOST(target.into(), host.into())
+    }
+    pub fn Stats<S: Into<String>>(query: Option<S>, target: Option<S>) -> Command {
+        Command::STATS(query.map(|s| s.into()), target.map(|s| s.into()))
+    }
+    pub fn Rehash() -> Command {
+        Command::REHASH
+    }
+
+    pub fn Whois<S: Into<String>>(target: Option<S>, nick: S) -> Command {
+        Command::WHOIS(target.map(|s| s.into()), nick.into())
+    }
+
     pub fn Raw<S: Into<String>>(raw: S) -> Command {
         Command::RAW(raw.into())
     }
@@ -73,6 +197,23 @@ impl Command {
             Command::PING(_, _) => "PING".to_string(),
             Command::PONG(_, _) => "PONG".to_string(),
             Command::JOIN(_, _) => "JOIN".to_string(),
+            Command::MODE(_, _) => "MODE".to_string(),
+            Command::TOPIC(_, _) => "TOPIC".to_string(),
+            Command::QUIT(_) => "QUIT".to_string(),
+            Command::ERROR(_) => "ERROR".to_string(),
+            Command::STARTTLS => "STARTTLS".to_string(),
+            Command::WEBIRC(_, _, _, _, _) => "WEBIRC".to_string(),
+            Command::AUTHENTICATE(_) => "AUTHENTICATE".to_string(),
+            Command::CAP(..) => "CAP".to_string(),
+            Command::REGISTER(..) => "REGISTER".to_string(),
+            Command::VERIFY(..) => "VERIFY".to_string(),
+            Command::FAIL(..) => "FAIL".to_string(),
+            Command::OPER(_, _) => "OPER".to_string(),
+            Command::SETHOST(_) => "SETHOST".to_string(),
+            Command::CHGHOST(_, _) => "CHGHOST".to_string(),
+            Command::STATS(_, _) => "STATS".to_string(),
+            Command::REHASH => "REHASH".to_string(),
+            Command::WHOIS(_, _) => "WHOIS".to_string(),
             Command::RAW(_) => "RAW".to_string(),
         }
     }
@@ -171,6 +312,83 @@ impl Command {
                 }
                 _ => Err(ProtocolError::ParseError),
             },
+            "MODE" => match args.split_first() {
+                Some((target, params)) => Ok(Command::Mode(*target, params.to_vec())),
+                None => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "TOPIC" => match args.len() {
+                1 => Ok(Command::Topic(args[0], None)),
+                2 => Ok(Command::Topic(args[0], Some(args[1]))),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "QUIT" => match args.len() {
+                0 => Ok(Command::Quit::<String>(None)),
+                1 => Ok(Command::Quit(Some(args[0]))),
+                _ => Err(ProtocolError::ParseError),
+            },
+            "ERROR" => match args.len() {
+                1 => Ok(Command::Error(args[0])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "STARTTLS" => Ok(Command::StartTls()),
+            "WEBIRC" => match args.len() {
+                4 => Ok(Command::WebIrc(args[0], args[1], args[2], args[3], None)),
+                5 => Ok(Command::WebIrc(
+                    args[0],
+                    args[1],
+                    args[2],
+                    args[3],
+                    Some(args[4]),
+                )),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "AUTHENTICATE" => match args.len() {
+                1 => Ok(Command::Authenticate(args[0])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "CAP" => match args.len() {
+                1 => Ok(Command::Cap(None, args[0], None)),
+                2 => Ok(Command::Cap(None, args[0], Some(args[1]))),
+                3 => Ok(Command::Cap(Some(args[0]), args[1], Some(args[2]))),
+                4 if args[2] == "*" => Ok(Command::CapContinued(args[0], args[1], args[3])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "REGISTER" => match args.len() {
+                3 => Ok(Command::Register(args[0], args[1], args[2])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "VERIFY" => match args.len() {
+                2 => Ok(Command::Verify(args[0], args[1])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "FAIL" => match args.len() {
+                0..=2 => Err(ProtocolError::NotEnoughArguments(command)),
+                n => Ok(Command::Fail(args[0], args[1], args[2..n - 1].to_vec(), args[n - 1])),
+            },
+            "OPER" => match args.len() {
+                2 => Ok(Command::Oper(args[0], args[1])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "SETHOST" => match args.len() {
+                1 => Ok(Command::SetHost(args[0])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "CHGHOST" => match args.len() {
+                2 => Ok(Command::ChgHost(args[0], args[1])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
+            "STATS" => match args.len() {
+                0 => Ok(Command::Stats::<String>(None, None)),
+                1 => Ok(Command::Stats(Some(args[0]), None)),
+                2 => Ok(Command::Stats(Some(args[0]), Some(args[1]))),
+                _ => Err(ProtocolError::ParseError),
+            },
+            "REHASH" => Ok(Command::Rehash()),
+            "WHOIS" => match args.len() {
+                1 => Ok(Command::Whois(None, args[0])),
+                2 => Ok(Command::Whois(Some(args[0]), args[1])),
+                _ => Err(ProtocolError::NotEnoughArguments(command)),
+            },
             _ => Err(ProtocolError::UnknownCommand(command)),
         }
     }
@@ -217,6 +435,54 @@ impl<'a> From<&'a Command> for String {
                 stringify("JOIN", &[chans.join(",").as_str(), keys.join(",").as_str()])
             }
             Command::JOIN(ref chans, None) => stringify("JOIN", &[chans.join(",").as_str()]),
+            Command::MODE(ref target, ref params) => {
+                let mut args = vec![target.as_str()];
+                args.extend(params.iter().map(String::as_str));
+                stringify("MODE", &args)
+            }
+            Command::TOPIC(ref channel, Some(ref topic)) => stringify("TOPIC", &[channel, topic]),
+            Command::TOPIC(ref channel, None) => stringify("TOPIC", &[channel]),
+            Command::QUIT(Some(ref reason)) => stringify("QUIT", &[reason]),
+            Command::QUIT(None) => stringify("QUIT", &[]),
+            Command::ERROR(ref message) => stringify("ERROR", &[message]),
+            Command::STARTTLS => stringify("STARTTLS", &[]),
+            Command::WEBIRC(ref password, ref gateway, ref host, ref ip, Some(ref options)) => {
+                stringify("WEBIRC", &[password, gateway, host, ip, options])
+            }
+            Command::WEBIRC(ref password, ref gateway, ref host, ref ip, None) => {
+                stringify("WEBIRC", &[password, gateway, host, ip])
+            }
+            Command::AUTHENTICATE(ref data) => stringify("AUTHENTICATE", &[data]),
+            Command::CAP(Some(ref target), ref sub, true, Some(ref param)) => {
+                stringify("CAP", &[target, sub, "*", param])
+            }
+            Command::CAP(Some(ref target), ref sub, _, Some(ref param)) => {
+                stringify("CAP", &[target, sub, param])
+            }
+            Command::CAP(Some(ref target), ref sub, _, None) => stringify("CAP", &[target, sub]),
+            Command::CAP(None, ref sub, _, Some(ref param)) => stringify("CAP", &[sub, param]),
+            Command::CAP(None, ref sub, _, None) => stringify("CAP", &[sub]),
+            Command::REGISTER(ref account, ref email, ref password) => {
+                stringify("REGISTER", &[account, email, password])
+            }
+            Command::VERIFY(ref account, ref code) => stringify("VERIFY", &[account, code]),
+            Command::FAIL(ref command, ref code, ref context, ref description) => {
+                let mut args = vec![command.as_str(), code.as_str()];
+                args.extend(context.iter().map(String::as_str));
+                args.push(description);
+                stringify("FAIL", &args)
+            }
+            Command::OPER(ref name, ref password) => stringify("OPER", &[name, password]),
+            Command::SETHOST(ref host) => stringify("SETHOST", &[host]),
+            Command::CHGHOST(ref target, ref host) => stringify("CHGHOST", &[target, host]),
+            Command::STATS(Some(ref query), Some(ref target)) => {
+                stringify("STATS", &[query, target])
+            }
+            Command::STATS(Some(ref query), None) => stringify("STATS", &[query]),
+            Command::STATS(None, _) => stringify("STATS", &[]),
+            Command::REHASH => stringify("REHASH", &[]),
+            Command::WHOIS(Some(ref target), ref nick) => stringify("WHOIS", &[target, nick]),
+            Command::WHOIS(None, ref nick) => stringify("WHOIS", &[nick]),
             Command::RAW(ref raw) => stringify(raw, &[]),
         }
     }
@@ -256,4 +522,60 @@ mod test {
         let cmd = Command::new("NOTICE", src);
         assert_eq!("NOTICE * Hello", cmd.unwrap().to_string());
     }
+
+    #[test]
+    pub fn cap_reply_keeps_empty_list() {
+        let cmd = Command::Cap(Some("*"), "LS", Some(""));
+        assert_eq!("CAP * LS :", cmd.to_string());
+    }
+
+    #[test]
+    pub fn cap_listing_continues() {
+        let cmd = Command::CapContinued("*", "LS", "a b");
+        assert_eq!("CAP * LS * :a b", cmd.to_string());
+        let parsed = Command::new("CAP", vec!["*", "LS", "*", "a b"]).unwrap();
+        assert_eq!(cmd, parsed);
+    }
+
+    #[test]
+    pub fn standard_replies() {
+        let cmd = Command::Fail("REGISTER", "ACCOUNT_EXISTS", vec!["alice"], "Account already exists");
+        assert_eq!("FAIL REGISTER ACCOUNT_EXISTS alice :Account already exists", cmd.to_string());
+        let parsed = Command::new("FAIL", vec!["REGISTER", "ACCOUNT_EXISTS", "alice", "Account already exists"]);
+        assert_eq!(cmd, parsed.unwrap());
+        let cmd = Command::Register("SUCCESS", "alice", "Account created");
+        assert_eq!("REGISTER SUCCESS alice :Account created", cmd.to_string());
+    }
+
+    #[test]
+    pub fn quit_without_reason() {
+        let cmd = Command::new("QUIT", vec![]);
+        assert_eq!(Command::QUIT(None), cmd.unwrap());
+    }
+
+    #[test]
+    pub fn mode_keeps_its_arguments() {
+        let cmd = Command::new("MODE", vec!["#chan", "+b", "*!*@*.example"]);
+        assert_eq!(Command::Mode("#chan", vec!["+b", "*!*@*.example"]), cmd.unwrap());
+        assert_eq!("MODE nick +x", Command::Mode("nick", vec!["+x"]).to_string());
+    }
+
+    #[test]
+    pub fn empty_topic_is_kept() {
+        let cmd = Command::new("TOPIC", vec!["#chan", ""]);
+        assert_eq!(Command::Topic("#chan", Some("")), cmd.unwrap());
+        assert_eq!("TOPIC #chan :", Command::Topic("#chan", Some("")).to_string());
+        assert_eq!("TOPIC #chan", Command::Topic("#chan", None).to_string());
+    }
+
+    #[test]
+    pub fn webirc_options_are_optional() {
+        let cmd = Command::new("WEBIRC", vec!["pw", "gw", "host.example", "192.0.2.1"]);
+        assert_eq!(
+            Command::WebIrc("pw", "gw", "host.example", "192.0.2.1", None),
+            cmd.unwrap()
+        );
+        let cmd = Command::WebIrc("pw", "gw", "host.example", "192.0.2.1", Some("secure"));
+        assert_eq!("WEBIRC pw gw host.example 192.0.2.1 secure", cmd.to_string());
+    }
 }
@@ -17,6 +17,8 @@ pub enum ProtocolError {
     UnsupportedEncoding(String),
     #[error("Maximum line length exceeded")]
     MaxLineLengthExceeded,
+    #[error("Input line too long")]
+    InputTooLong,
     /* MessageParsing */
     #[error("empty message")]
     EmptyMessage,
@@ -35,4 +37,8 @@ pub enum ProtocolError {
     PingTimeout,
     #[error("send error")]
     SendError,
+    #[error("Max SendQ exceeded")]
+    SendQExceeded,
+    #[error("Excess Flood")]
+    ExcessFlood,
 }
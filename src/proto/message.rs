@@ -8,6 +8,7 @@ use super::command::Command;
 use super::error::ProtocolError;
 use super::prefix::Prefix;
 use super::reply::Reply;
+use super::tags::Tags;
 
 #[non_exhaustive]
 #[derive(Clone, PartialEq, Debug)]
@@ -34,6 +35,7 @@ impl fmt::Display for MessageContents {
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Message {
+    pub tags: Tags,
     pub prefix: Option<Prefix>,
     pub contents: MessageContents,
 }
@@ -45,6 +47,7 @@ impl Message {
         args: Vec<&str>,
     ) -> Result<Message, ProtocolError> {
         Ok(Message {
+            tags: Tags::new(),
             prefix: prefix.map(|p| p.into()),
             contents: MessageContents::Command(Command::new(command, args)?),
         })
@@ -62,6 +65,7 @@ impl Message {
 impl From<Command> for Message {
     fn from(value: Command) -> Self {
         Message {
+            tags: Tags::new(),
             prefix: None,
             contents: MessageContents::Command(value),
         }
@@ -71,6 +75,7 @@ impl From<Command> for Message {
 impl From<Reply> for Message {
     fn from(value: Reply) -> Self {
         Message {
+            tags: Tags::new(),
             prefix: None,
             contents: MessageContents::Reply(value),
         }
@@ -80,6 +85,11 @@ impl From<Reply> for Message {
 impl<'a> From<&'a Message> for String {
     fn from(value: &'a Message) -> Self {
         let mut buf = String::new();
+        if !value.tags.is_empty() {
+            buf.push('@');
+            buf.push_str(&value.tags.to_string());
+            buf.push(' ');
+        }
         if let Some(prefix) = &value.prefix {
             buf.push_str(&prefix.to_string());
             buf.push(' ');
@@ -115,6 +125,14 @@ impl FromStr for Message {
         }
 
         let mut state = s;
+        let tags = match state.strip_prefix('@') {
+            Some(rest) => {
+                let (section, rest) = rest.split_once(' ').ok_or(ProtocolError::InvalidCommand)?;
+                state = rest.trim_start_matches(' ');
+                Tags::parse(section)
+            }
+            None => Tags::new(),
+        };
         let prefix = if state.starts_with(':') {
             let prefix = state.find(' ').map(|i| &state[1..i]);
             state = state.find(' ').map_or("", |i| &state[i + 1..]);
@@ -152,7 +170,9 @@ impl FromStr for Message {
             args.push(suffix);
         }
 
-        Message::new(prefix, command, args)
+        let mut message = Message::new(prefix, command, args)?;
+        message.tags = tags;
+        Ok(message)
     }
 }
 
@@ -164,6 +184,7 @@ mod tests {
     pub fn parse_message() {
         let str = ":localhost NOTICE * :*** Hello World.";
         let message = Message {
+            tags: Tags::new(),
             prefix: Some(Prefix::ServerOrNick("localhost".to_string())),
             contents: MessageContents::Command(Command::NOTICE(
                 "*".to_string(),
@@ -172,4 +193,66 @@ mod tests {
         };
         assert_eq!(str.parse::<Message>().unwrap(), message);
     }
+
+    #[test]
+    pub fn parse_tagged_message() {
+        let str = "@id=1;+draft/reply=a\\sb :nick!user@host NOTICE #chan :hi there";
+        let message = str.parse::<Message>().unwrap();
+        assert_eq!(message.tags.get("+draft/reply"), Some("a b"));
+        assert_eq!(message.prefix, Some(Prefix::from("nick!user@host")));
+        assert_eq!(message.to_string(), str);
+        assert!("@id=1".parse::<Message>().is_err());
+    }
+
+    #[test]
+    pub fn tags_round_trip() {
+        let mut message: Message = Command::Notice("*", "Hello").into();
+        message.tags.insert("+example.com/x", "semi;colon space\\slash\r\n");
+        message.tags.insert("flag", "");
+        message.tags.insert("time", "2023-01-01T00:00:00.000Z");
+        let parsed = message.to_string().parse::<Message>().unwrap();
+        assert_eq!(parsed, message);
+    }
+
+    /// Tiny xorshift generator so the fuzz test needs no extra dependency and failures
+    /// reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<'a>(&mut self, from: &'a [&'a str]) -> &'a str {
+            from[self.next() as usize % from.len()]
+        }
+    }
+
+    #[test]
+    pub fn fuzz_tagged_lines() {
+        let pieces = [
+            "@", ";", "=", " ", ":", "\\", "\\s", "+", "/", "a", "key", "val", "é", "\r",
+            "NOTICE", "PRIVMSG", "*", "#c",
+        ];
+        let values = ["", "a", ";", " ", "\\", "\r\n", "=", "x y;z\\", "ü"];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            // Whatever comes in must not bring the parser down.
+            let line: String = (0..rng.next() % 16).map(|_| rng.pick(&pieces)).collect();
+            let _ = line.parse::<Message>();
+
+            // Whatever valid tags go out must come back unchanged.
+            let mut message: Message = Command::Notice("*", "fuzz").into();
+            for i in 0..rng.next() % 5 {
+                let prefix = if rng.next() & 1 == 0 { "+" } else { "" };
+                let value: String = (0..rng.next() % 4).map(|_| rng.pick(&values)).collect();
+                message.tags.insert(format!("{}k{}", prefix, i), value);
+            }
+            let line = message.to_string();
+            assert_eq!(line.parse::<Message>().unwrap(), message, "{}", line);
+        }
+    }
 }
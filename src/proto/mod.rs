@@ -4,6 +4,7 @@ pub mod error;
 pub mod message;
 pub mod prefix;
 pub mod reply;
+pub mod tags;
 
 pub use codec::{line::LineCodec, message::MessageCodec};
 pub use command::Command;
@@ -7,38 +7,131 @@ use crate::details::channel::ChannelUser;
 #[repr(u32)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Reply {
+    StatsLinkInfo(String, usize, usize) = 211,
+    EndOfStats(String) = 219,
+    UModeIs(String) = 221,
+
+    WhoisCertFp(String, String) = 276,
+    WhoisUser(String, String, String, String) = 311,
+    WhoisServer(String, String) = 312,
+    WhoisOperator(String) = 313,
+    EndOfWhois(String) = 318,
+    WhoisSpecial(String, String) = 320,
+    WhoisAccount(String, String) = 330,
+
+    ChannelModeIs(String, String) = 324,
     NoTopic(String) = 331,
     Topic(String, String) = 332,
+    ExceptList(String, String) = 348,
+    EndOfExceptList(String) = 349,
     NamReply(String, Vec<ChannelUser>) = 353,
     EndOfNames(String) = 366,
+    BanList(String, String) = 367,
+    EndOfBanList(String) = 368,
 
     MotdStart(String) = 375,
     Motd(String) = 372,
     MotdEnd = 376,
+    WhoisHost(String, String, String) = 378,
+
+    YoureOper = 381,
+    Rehashing(String) = 382,
+    HostHidden(String) = 396,
+
+    StartTls = 670,
+    WhoisSecure(String) = 671,
+
+    LoggedIn(String, String) = 900,
+    LoggedOut(String) = 901,
+    SaslSuccess = 903,
+    ErrSaslFail = 904,
+    ErrSaslTooLong = 905,
+    ErrSaslAborted = 906,
+    ErrSaslAlready = 907,
+    SaslMechs(String) = 908,
 
     ErrGeneric(String, Option<Vec<String>>, String) = 400,
+    ErrNoSuchNick(String) = 401,
+    ErrNoSuchChannel(String) = 403,
+    ErrInvalidCapCmd(String) = 410,
+    ErrInputTooLong = 417,
     ErrNoSuchCommand(String) = 421,
     ErrNoNicknameGiven = 431,
     ErrErroneousNickname(String) = 432,
     ErrNicknameInUse(String) = 433,
     ErrNickCollision(String) = 436,
+    ErrNotOnChannel(String) = 442,
     ErrNotRegistered = 451,
     ErrNeedMoreParams(String) = 461,
     ErrAlreadyRegistered = 462,
+    ErrPasswdMismatch = 464,
+    ErrUnknownMode(char) = 472,
+    ErrBannedFromChan(String) = 474,
+    ErrNoPrivileges = 481,
+    ErrChanOPrivsNeeded(String) = 482,
+    ErrNoOperHost = 491,
+    ErrUModeUnknownFlag = 501,
+    ErrUsersDontMatch = 502,
+    ErrStartTls = 691,
+    ErrMlockRestricted(String, char, String) = 742,
 }
 
 impl<'a> From<&'a Reply> for String {
     fn from(value: &'a Reply) -> Self {
         match value {
+            Reply::StatsLinkInfo(link, sendq, max) => format!("211 {} {} {}", link, sendq, max),
+            Reply::EndOfStats(query) => format!("219 {} :End of /STATS report", query),
+            Reply::UModeIs(modes) => format!("221 {}", modes),
+            Reply::WhoisCertFp(nick, fingerprint) => format!(
+                "276 {} :has client certificate fingerprint {}",
+                nick, fingerprint
+            ),
+            Reply::WhoisUser(nick, user, host, real) => {
+                format!("311 {} {} {} * :{}", nick, user, host, real)
+            }
+            Reply::WhoisServer(nick, server) => format!("312 {} {} :{}", nick, server, server),
+            Reply::WhoisOperator(nick) => format!("313 {} :is an IRC operator", nick),
+            Reply::EndOfWhois(nick) => format!("318 {} :End of /WHOIS list", nick),
+            Reply::WhoisSpecial(nick, text) => format!("320 {} :{}", nick, text),
+            Reply::WhoisAccount(nick, account) => {
+                format!("330 {} {} :is logged in as", nick, account)
+            }
+            Reply::ChannelModeIs(channel, modes) => format!("324 {} {}", channel, modes),
             Reply::NoTopic(channel) => format!("331 {} :No topic is set", channel),
             Reply::Topic(channel, message) => format!("332 {} :{}", channel, message),
+            Reply::ExceptList(channel, mask) => format!("348 {} {}", channel, mask),
+            Reply::EndOfExceptList(channel) => {
+                format!("349 {} :End of channel exception list", channel)
+            }
             Reply::NamReply(channel, nicks) => {
                 format!("353 {} :{}", channel, nicks.iter().format(" "))
             }
             Reply::EndOfNames(channel) => format!("366 {} :End of /NAMES list", channel),
+            Reply::BanList(channel, mask) => format!("367 {} {}", channel, mask),
+            Reply::EndOfBanList(channel) => {
+                format!("368 {} :End of channel ban list", channel)
+            }
             Reply::MotdStart(server) => format!("375 :- {} Message of the day - ", server),
             Reply::Motd(line) => format!("372 :- {}", line),
             Reply::MotdEnd => "376 :End of /MOTD command".to_string(),
+            Reply::WhoisHost(nick, host, ip) => {
+                format!("378 {} :is connecting from *@{} {}", nick, host, ip)
+            }
+            Reply::YoureOper => "381 :You are now an IRC operator".to_string(),
+            Reply::Rehashing(config) => format!("382 {} :Rehashing", config),
+            Reply::HostHidden(host) => format!("396 {} :is now your displayed host", host),
+            Reply::StartTls => "670 :STARTTLS successful, proceed with TLS handshake".to_string(),
+            Reply::WhoisSecure(nick) => format!("671 {} :is using a secure connection", nick),
+            Reply::LoggedIn(mask, account) => {
+                format!("900 {} {} :You are now logged in as {}", mask, account, account)
+            }
+            Reply::LoggedOut(mask) => format!("901 {} :You are now logged out", mask),
+            Reply::SaslSuccess => "903 :SASL authentication successful".to_string(),
+            Reply::ErrSaslFail => "904 :SASL authentication failed".to_string(),
+            Reply::ErrSaslTooLong => "905 :SASL message too long".to_string(),
+            Reply::ErrSaslAborted => "906 :SASL authentication aborted".to_string(),
+            Reply::ErrSaslAlready => "907 :You have already authenticated using SASL".to_string(),
+            Reply::SaslMechs(mechs) => format!("908 {} :are available SASL mechanisms", mechs),
 
             Reply::ErrGeneric(cmd, subs, message) => format!(
                 "400 {} {} :{}",
@@ -46,14 +139,40 @@ impl<'a> From<&'a Reply> for String {
                 subs.as_ref().unwrap_or(&vec!("".to_owned())).join(" "),
                 message
             ),
+            Reply::ErrNoSuchNick(nick) => format!("401 {} :No such nick/channel", nick),
+            Reply::ErrNoSuchChannel(channel) => format!("403 {} :No such channel", channel),
+            Reply::ErrInvalidCapCmd(cmd) => format!("410 {} :Invalid CAP command", cmd),
+            Reply::ErrInputTooLong => "417 :Input line was too long".to_string(),
             Reply::ErrNoSuchCommand(cmd) => format!("421 {} :Unknown command", cmd),
             Reply::ErrNoNicknameGiven => "431 :No nickname given".to_string(),
             Reply::ErrErroneousNickname(nick) => format!("432 {} :Erroneous nickname", nick),
             Reply::ErrNicknameInUse(nick) => format!("433 {} :Nickname is already in use", nick),
             Reply::ErrNickCollision(nick) => format!("436 {} :Nickname collision KILL", nick),
+            Reply::ErrNotOnChannel(channel) => {
+                format!("442 {} :You're not on that channel", channel)
+            }
             Reply::ErrNotRegistered => "451 :You have not registered".to_string(),
             Reply::ErrNeedMoreParams(cmd) => format!("462 {} :Not enough parameters", cmd),
             Reply::ErrAlreadyRegistered => "462 :You may not reregister".to_string(),
+            Reply::ErrPasswdMismatch => "464 :Password incorrect".to_string(),
+            Reply::ErrUnknownMode(mode) => format!("472 {} :is unknown mode char to me", mode),
+            Reply::ErrBannedFromChan(channel) => {
+                format!("474 {} :Cannot join channel (+b)", channel)
+            }
+            Reply::ErrNoPrivileges => {
+                "481 :Permission Denied- You're not an IRC operator".to_string()
+            }
+            Reply::ErrChanOPrivsNeeded(channel) => {
+                format!("482 {} :You're not channel operator", channel)
+            }
+            Reply::ErrNoOperHost => "491 :No O-lines for your host".to_string(),
+            Reply::ErrUModeUnknownFlag => "501 :Unknown MODE flag".to_string(),
+            Reply::ErrUsersDontMatch => "502 :Cant change mode for other users".to_string(),
+            Reply::ErrStartTls => "691 :STARTTLS failed (Wrong moment)".to_string(),
+            Reply::ErrMlockRestricted(channel, mode, mlock) => format!(
+                "742 {} {} {} :MODE cannot be set due to channel having an active MLOCK restriction policy",
+                channel, mode, mlock
+            ),
         }
     }
 }
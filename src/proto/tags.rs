@@ -0,0 +1,173 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Tags sent by clients that the server passes along without acting on them start with
+/// this.
+pub const CLIENT_ONLY_PREFIX: char = '+';
+
+/// Whether `key` names a client-only tag.
+pub fn is_client_only(key: &str) -> bool {
+    key.starts_with(CLIENT_ONLY_PREFIX)
+}
+
+/// Keys are an optional `+`, an optional vendor hostname followed by `/`, and a name of
+/// letters, digits and hyphens.
+fn valid_key(key: &str) -> bool {
+    let key = key.strip_prefix(CLIENT_ONLY_PREFIX).unwrap_or(key);
+    let (vendor, name) = match key.rsplit_once('/') {
+        Some((vendor, name)) => (Some(vendor), name),
+        None => (None, key),
+    };
+    let vendor_ok = match vendor {
+        Some(vendor) => {
+            !vendor.is_empty()
+                && vendor
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'))
+        }
+        None => true,
+    };
+    vendor_ok && !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Escapes a value so it can't end the tag or the tag section.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape`]. Unknown escapes stand for the escaped character itself and a
+/// trailing backslash is dropped.
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// The tags of a message in the order they were added. A tag without a value and one
+/// with an empty value are the same thing, both are kept with an empty value.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Tags {
+    tags: Vec<(String, String)>,
+}
+
+impl Tags {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Sets `key` to `value`, keeping its place if it was already set.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        match self.tags.iter_mut().find(|(known, _)| *known == key) {
+            Some((_, known)) => *known = value,
+            None => self.tags.push((key, value)),
+        }
+    }
+
+    /// Drops every tag a client had no business sending, which is all of them but the
+    /// client-only ones.
+    pub fn retain_client_only(&mut self) {
+        self.tags.retain(|(key, _)| is_client_only(key));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Parses the tag section of a line, without its leading `@`. Tags with invalid keys
+    /// are skipped and a repeated key takes the last value given.
+    pub fn parse(section: &str) -> Self {
+        let mut tags = Tags::new();
+        for tag in section.split(';') {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            if valid_key(key) {
+                tags.insert(key, unescape(value));
+            }
+        }
+        tags
+    }
+}
+
+/// Formats the tag section without its leading `@`.
+impl fmt::Display for Tags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            f.write_str(key)?;
+            if !value.is_empty() {
+                write!(f, "={}", escape(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn escaping_round_trips() {
+        let value = "a;b c\\d\r\ne";
+        assert_eq!(escape(value), "a\\:b\\sc\\\\d\\r\\ne");
+        assert_eq!(unescape(&escape(value)), value);
+        assert_eq!(unescape("\\b\\"), "b");
+    }
+
+    #[test]
+    pub fn parse_tags() {
+        let tags = Tags::parse("+example.com/foo=a\\sb;id=1;flag;id=2;bad key=x;=y");
+        let parsed: Vec<_> = tags.iter().collect();
+        assert_eq!(parsed, vec![("+example.com/foo", "a b"), ("id", "2"), ("flag", "")]);
+        assert_eq!(tags.get("flag"), Some(""));
+        assert_eq!(tags.to_string(), "+example.com/foo=a\\sb;id=2;flag");
+    }
+
+    #[test]
+    pub fn client_only_tags() {
+        let mut tags = Tags::parse("+typing=active;time=2023-01-01T00:00:00Z");
+        assert!(is_client_only("+typing"));
+        assert!(!is_client_only("time"));
+        tags.retain_client_only();
+        assert_eq!(tags.to_string(), "+typing=active");
+    }
+}
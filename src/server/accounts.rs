@@ -0,0 +1,297 @@
+use crate::config::AccountConfig;
+use crate::server::scram::ScramCredentials;
+use crate::server::socket::CertFp;
+use crate::server::store::{Change, Store, StoreError, StoredMap};
+use dashmap::DashMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum AccountError {
+    #[error("{0} is already registered")]
+    Exists(String),
+    #[error("{0} is not a valid account name")]
+    InvalidName(String),
+    #[error("{0} is not registered")]
+    NoSuchAccount(String),
+    #[error("{0} belongs to another account")]
+    NickTaken(String),
+    #[error("{0} is not grouped to your account")]
+    NotGrouped(String),
+    #[error("{0} is set up by the server configuration and can't be changed")]
+    Configured(String),
+    #[error("account {source}")]
+    Storage {
+        #[from]
+        source: StoreError,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Account {
+    /// The name as it was registered, lookups ignore case.
+    name: String,
+    password: Option<ScramCredentials>,
+    #[serde(default)]
+    certfps: Vec<String>,
+    /// Nicks grouped to the account besides its name, which it owns as well.
+    #[serde(default)]
+    nicks: Vec<String>,
+    email: Option<String>,
+    /// Set up by the config file, which is where it has to be changed.
+    #[serde(skip)]
+    configured: bool,
+}
+
+impl Account {
+    fn owns(&self, nick: &str) -> bool {
+        self.name.eq_ignore_ascii_case(nick) || self.nicks.iter().any(|n| n.eq_ignore_ascii_case(nick))
+    }
+}
+
+/// Whether `name` may be used as an account name, which is also a nick.
+pub fn valid_name(name: &str) -> bool {
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || special(c) => {}
+        _ => return false,
+    }
+    name.len() <= 30 && chars.all(|c| c.is_ascii_alphanumeric() || special(c) || c == '-')
+}
+
+/// Accounts users can log in to. Every method returns the account's name as it was
+/// registered, which is what clients are shown.
+#[derive(Debug)]
+pub struct Accounts {
+    accounts: StoredMap<Account>,
+    /// Keys the decoy SCRAM credentials of accounts that don't exist.
+    secret: Vec<u8>,
+}
+
+impl Accounts {
+    /// The accounts in `config` along with those registered so far in `store`. The config
+    /// wins when both have an account.
+    pub fn new(config: &HashMap<String, AccountConfig>, store: Arc<dyn Store>) -> Self {
+        let accounts: DashMap<String, Account> = config
+            .iter()
+            .map(|(name, account)| {
+                let account = Account {
+                    name: name.clone(),
+                    password: account.password.as_deref().map(ScramCredentials::new),
+                    certfps: account.certfp.clone(),
+                    nicks: Vec::new(),
+                    email: None,
+                    configured: true,
+                };
+                (name.to_lowercase(), account)
+            })
+            .collect();
+        let snapshot = store.load();
+        let mut secret = snapshot.secret;
+        if secret.is_empty() {
+            secret = Uuid::new_v4().as_bytes().to_vec();
+            let change = Change::Secret {
+                secret: secret.clone(),
+            };
+            if let Err(e) = store.record(change) {
+                warn!("Failed to store the account secret: {}", e);
+            }
+        }
+        for (key, account) in snapshot.accounts {
+            if accounts.contains_key(&key) {
+                warn!("Ignoring stored account {}, the config defines it too", account.name);
+                continue;
+            }
+            accounts.insert(key, account);
+        }
+        Self {
+            accounts: StoredMap::new(accounts, store, |key, account| Change::Account { key, account }),
+            secret,
+        }
+    }
+
+    /// A copy of the account `name` that may be changed.
+    fn editable(&self, name: &str) -> Result<Account, AccountError> {
+        let account = self
+            .accounts
+            .get(&name.to_lowercase())
+            .ok_or_else(|| AccountError::NoSuchAccount(name.to_owned()))?;
+        if account.configured {
+            return Err(AccountError::Configured(account.name.clone()));
+        }
+        Ok(account.clone())
+    }
+
+    pub async fn register(
+        &self,
+        name: &str,
+        password: &str,
+        email: Option<String>,
+    ) -> Result<String, AccountError> {
+        if !valid_name(name) {
+            return Err(AccountError::InvalidName(name.to_owned()));
+        }
+        let _changing = self.accounts.lock().await;
+        if self.owner(name).is_some() {
+            return Err(AccountError::Exists(name.to_owned()));
+        }
+        let account = Account {
+            name: name.to_owned(),
+            password: Some(ScramCredentials::new(password)),
+            certfps: Vec::new(),
+            nicks: Vec::new(),
+            email,
+            configured: false,
+        };
+        self.accounts.commit(name.to_lowercase(), Some(account)).await?;
+        Ok(name.to_owned())
+    }
+
+    /// Deletes the account `name` along with its grouped nicks.
+    pub async fn drop_account(&self, name: &str) -> Result<(), AccountError> {
+        let _changing = self.accounts.lock().await;
+        self.editable(name)?;
+        self.accounts.commit(name.to_lowercase(), None).await?;
+        Ok(())
+    }
+
+    pub async fn set_password(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        let _changing = self.accounts.lock().await;
+        let mut account = self.editable(name)?;
+        account.password = Some(ScramCredentials::new(password));
+        self.accounts.commit(name.to_lowercase(), Some(account)).await?;
+        Ok(())
+    }
+
+    /// Lets the account `name` own `nick` as well.
+    pub async fn group(&self, name: &str, nick: &str) -> Result<(), AccountError> {
+        if !valid_name(nick) {
+            return Err(AccountError::InvalidName(nick.to_owned()));
+        }
+        let _changing = self.accounts.lock().await;
+        let mut account = self.editable(name)?;
+        match self.owner(nick) {
+            Some(owner) if owner == account.name => return Ok(()),
+            Some(_) => return Err(AccountError::NickTaken(nick.to_owned())),
+            None => {}
+        }
+        account.nicks.push(nick.to_owned());
+        self.accounts.commit(name.to_lowercase(), Some(account)).await?;
+        Ok(())
+    }
+
+    pub async fn ungroup(&self, name: &str, nick: &str) -> Result<(), AccountError> {
+        let _changing = self.accounts.lock().await;
+        let mut account = self.editable(name)?;
+        let before = account.nicks.len();
+        account.nicks.retain(|n| !n.eq_ignore_ascii_case(nick));
+        if account.nicks.len() == before {
+            return Err(AccountError::NotGrouped(nick.to_owned()));
+        }
+        self.accounts.commit(name.to_lowercase(), Some(account)).await?;
+        Ok(())
+    }
+
+    /// The account that owns `nick`, either as its name or as a grouped nick.
+    pub fn owner(&self, nick: &str) -> Option<String> {
+        if let Some(account) = self.accounts.get(&nick.to_lowercase()) {
+            return Some(account.name.clone());
+        }
+        self.accounts
+            .iter()
+            .find(|account| account.owns(nick))
+            .map(|account| account.name.clone())
+    }
+
+    /// The account `name` if `password` is its password.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<String> {
+        let account = self.accounts.get(&name.to_lowercase())?;
+        let credentials = account.password.as_ref()?;
+        credentials.verify(password).then(|| account.name.clone())
+    }
+
+    /// The account `name` and what SCRAM needs to check the client knows its password.
+    /// Without such an account there are only decoy credentials that no password matches.
+    pub fn scram_credentials(&self, name: &str) -> (Option<String>, ScramCredentials) {
+        let account = self.accounts.get(&name.to_lowercase());
+        match account.as_ref().and_then(|account| account.password.clone()) {
+            Some(credentials) => (account.map(|account| account.name.clone()), credentials),
+            None => (None, ScramCredentials::decoy(&self.secret, name)),
+        }
+    }
+
+    /// The account a client certificate with `certfp` belongs to.
+    pub fn find_by_certfp(&self, certfp: &CertFp) -> Option<String> {
+        self.accounts
+            .iter()
+            .find(|account| account.certfps.iter().any(|fp| certfp.matches(fp)))
+            .map(|account| account.name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::store::MemoryStore;
+
+    #[tokio::test]
+    pub async fn passwords_and_certificates() {
+        let fp = CertFp::new(b"certificate");
+        let config = HashMap::from([
+            (
+                "Alice".to_string(),
+                AccountConfig {
+                    password: Some("secret".to_string()),
+                    certfp: vec![fp.sha256.clone()],
+                },
+            ),
+            (
+                "bob".to_string(),
+                AccountConfig {
+                    password: None,
+                    certfp: vec![],
+                },
+            ),
+        ]);
+        let accounts = Accounts::new(&config, Arc::new(MemoryStore::default()));
+        assert_eq!(accounts.authenticate("alice", "secret").as_deref(), Some("Alice"));
+        assert_eq!(accounts.authenticate("alice", "wrong"), None);
+        assert_eq!(accounts.authenticate("bob", ""), None);
+        assert_eq!(accounts.authenticate("carol", "secret"), None);
+        assert_eq!(accounts.find_by_certfp(&fp).as_deref(), Some("Alice"));
+        assert_eq!(accounts.find_by_certfp(&CertFp::new(b"other")), None);
+        assert!(matches!(accounts.drop_account("alice").await, Err(AccountError::Configured(_))));
+    }
+
+    #[tokio::test]
+    pub async fn registered_accounts_are_stored() {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let accounts = Accounts::new(&HashMap::new(), store.clone());
+        assert_eq!(accounts.register("Carol", "password", None).await.unwrap(), "Carol");
+        assert!(matches!(accounts.register("carol", "other", None).await, Err(AccountError::Exists(_))));
+        assert!(matches!(accounts.register("#carol", "other", None).await, Err(AccountError::InvalidName(_))));
+        accounts.group("carol", "carol_away").await.unwrap();
+        assert!(matches!(accounts.register("Carol_Away", "x", None).await, Err(AccountError::Exists(_))));
+        accounts.register("dave", "password", None).await.unwrap();
+        assert!(matches!(accounts.group("dave", "carol_away").await, Err(AccountError::NickTaken(_))));
+        accounts.set_password("carol", "changed").await.unwrap();
+        accounts.drop_account("dave").await.unwrap();
+
+        let restored = Accounts::new(&HashMap::new(), store);
+        // Unknown accounts look the same to SCRAM after a restart, like real ones do.
+        let (none, decoy) = restored.scram_credentials("nobody");
+        assert_eq!(none, None);
+        assert_eq!(decoy, accounts.scram_credentials("Nobody").1);
+        assert_eq!(restored.authenticate("carol", "changed").as_deref(), Some("Carol"));
+        assert_eq!(restored.authenticate("carol", "password"), None);
+        assert_eq!(restored.owner("CAROL_AWAY").as_deref(), Some("Carol"));
+        assert_eq!(restored.owner("dave"), None);
+        restored.ungroup("carol", "carol_away").await.unwrap();
+        assert!(matches!(restored.ungroup("carol", "carol_away").await, Err(AccountError::NotGrouped(_))));
+    }
+}
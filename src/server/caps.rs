@@ -0,0 +1,133 @@
+use std::sync::RwLock;
+
+pub const ACCOUNT_REGISTRATION: &str = "draft/account-registration";
+pub const CAP_NOTIFY: &str = "cap-notify";
+pub const CHGHOST: &str = "chghost";
+pub const SASL: &str = "sasl";
+pub const TLS: &str = "tls";
+
+/// The CAP version that introduced capability values, multi-line listings and implicit
+/// cap-notify.
+pub const CAP_302: u32 = 302;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capability {
+    pub name: &'static str,
+    /// Listed after a `=` to clients that negotiate version 302 or later.
+    pub value: Option<String>,
+}
+
+impl Capability {
+    pub fn new(name: &'static str) -> Self {
+        Self { name, value: None }
+    }
+
+    pub fn with_value<S: Into<String>>(name: &'static str, value: S) -> Self {
+        Self {
+            name,
+            value: Some(value.into()),
+        }
+    }
+
+    /// How the capability is listed to a client negotiating `version`.
+    pub fn format(&self, version: u32) -> String {
+        match &self.value {
+            Some(value) if version >= CAP_302 => format!("{}={}", self.name, value),
+            _ => self.name.to_owned(),
+        }
+    }
+}
+
+/// Every capability the server offers. Subsystems declare theirs here, and check whether
+/// a client enabled one before formatting output that depends on it.
+#[derive(Debug)]
+pub struct CapRegistry {
+    caps: RwLock<Vec<Capability>>,
+}
+
+impl CapRegistry {
+    pub fn new() -> Self {
+        Self {
+            caps: RwLock::new(vec![Capability::new(CAP_NOTIFY)]),
+        }
+    }
+
+    /// Adds `cap` or replaces its value. Returns whether anything changed.
+    pub fn declare(&self, cap: Capability) -> bool {
+        let mut caps = self.caps.write().unwrap();
+        match caps.iter_mut().find(|known| known.name == cap.name) {
+            Some(known) if *known == cap => false,
+            Some(known) => {
+                *known = cap;
+                true
+            }
+            None => {
+                caps.push(cap);
+                true
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<Capability> {
+        self.caps.read().unwrap().clone()
+    }
+}
+
+/// Joins `caps` into space separated lists of at most `budget` bytes each. There is
+/// always at least one list, if only an empty one.
+pub fn split_list(caps: &[String], budget: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for cap in caps {
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() && line.len() + 1 + cap.len() > budget {
+            lines.push(cap.clone());
+        } else {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(cap);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn values_need_302() {
+        let cap = Capability {
+            name: "sasl",
+            value: Some("PLAIN,EXTERNAL".to_string()),
+        };
+        assert_eq!(cap.format(0), "sasl");
+        assert_eq!(cap.format(301), "sasl");
+        assert_eq!(cap.format(302), "sasl=PLAIN,EXTERNAL");
+        assert_eq!(Capability::new("chghost").format(302), "chghost");
+    }
+
+    #[test]
+    pub fn declaring_reports_changes() {
+        let registry = CapRegistry::new();
+        assert!(registry.declare(Capability::new(CHGHOST)));
+        assert!(!registry.declare(Capability::new(CHGHOST)));
+        let valued = Capability {
+            name: CHGHOST,
+            value: Some("x".to_string()),
+        };
+        assert!(registry.declare(valued.clone()));
+        assert!(registry.list().contains(&valued));
+        assert_eq!(registry.list().len(), 2);
+    }
+
+    #[test]
+    pub fn long_lists_are_split() {
+        let caps: Vec<String> = (0..10).map(|i| format!("cap-{}", i)).collect();
+        let lines = split_list(&caps, 20);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= 20));
+        assert_eq!(lines.join(" "), caps.join(" "));
+        assert_eq!(split_list(&[], 20), vec![String::new()]);
+    }
+}
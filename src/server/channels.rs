@@ -0,0 +1,298 @@
+use crate::server::store::{Change, Store, StoreError, StoredMap};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RegistrationError {
+    #[error("{0} is already registered")]
+    Exists(String),
+    #[error("{0} is not registered")]
+    NotRegistered(String),
+    #[error("channel {source}")]
+    Storage {
+        #[from]
+        source: StoreError,
+    },
+}
+
+/// The status an account gets when it joins a registered channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLevel {
+    Voice,
+    Op,
+    /// Owns the channel, which takes it to change the access list.
+    Founder,
+}
+
+impl AccessLevel {
+    pub fn parse(level: &str) -> Option<Self> {
+        match level.to_lowercase().as_str() {
+            "voice" => Some(AccessLevel::Voice),
+            "op" => Some(AccessLevel::Op),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccessLevel::Voice => "voice",
+            AccessLevel::Op => "op",
+            AccessLevel::Founder => "founder",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Access {
+    pub account: String,
+    pub level: AccessLevel,
+}
+
+/// What is kept of a registered channel while nobody is in it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RegisteredChannel {
+    /// The name as it was registered, lookups ignore case.
+    pub name: String,
+    pub founder: String,
+    #[serde(default)]
+    pub access: Vec<Access>,
+    pub topic: Option<String>,
+    /// Flags as shown in RPL_CHANNELMODEIS, like `+nt`.
+    #[serde(default)]
+    pub modes: String,
+    #[serde(default)]
+    pub bans: Vec<String>,
+    /// Masks exempt from the bans.
+    #[serde(default)]
+    pub exceptions: Vec<String>,
+    /// Flags after a `+` are kept set and those after a `-` kept unset, like `+nt-s`.
+    #[serde(default)]
+    pub mlock: String,
+}
+
+impl RegisteredChannel {
+    pub fn new(name: &str, founder: &str) -> Self {
+        RegisteredChannel {
+            name: name.to_owned(),
+            founder: founder.to_owned(),
+            access: Vec::new(),
+            topic: None,
+            modes: String::new(),
+            bans: Vec::new(),
+            exceptions: Vec::new(),
+            mlock: String::new(),
+        }
+    }
+
+    /// The status `account` gets in the channel, if any.
+    pub fn access(&self, account: Option<&str>) -> Option<AccessLevel> {
+        let account = account?;
+        if self.founder.eq_ignore_ascii_case(account) {
+            return Some(AccessLevel::Founder);
+        }
+        self.access
+            .iter()
+            .find(|access| access.account.eq_ignore_ascii_case(account))
+            .map(|access| access.level)
+    }
+
+    /// Gives `account` the status `level`, or takes it away given `None`. Returns whether
+    /// anything changed.
+    pub fn set_access(&mut self, account: &str, level: Option<AccessLevel>) -> bool {
+        let current = self
+            .access
+            .iter()
+            .find(|access| access.account.eq_ignore_ascii_case(account))
+            .map(|access| access.level);
+        if current == level {
+            return false;
+        }
+        self.access.retain(|access| !access.account.eq_ignore_ascii_case(account));
+        if let Some(level) = level {
+            self.access.push(Access {
+                account: account.to_owned(),
+                level,
+            });
+        }
+        true
+    }
+
+    /// Every flag the mode lock mentions, with whether it is kept set.
+    pub fn locked_flags(&self) -> Vec<(char, bool)> {
+        let mut set = true;
+        let mut flags = Vec::new();
+        for c in self.mlock.chars() {
+            match c {
+                '+' => set = true,
+                '-' => set = false,
+                c => flags.push((c, set)),
+            }
+        }
+        flags
+    }
+
+    /// Whether the mode lock forbids setting `flag` to `set`.
+    pub fn is_locked(&self, flag: char, set: bool) -> bool {
+        self.locked_flags().contains(&(flag, !set))
+    }
+}
+
+/// Channels registered with ChanServ.
+#[derive(Debug)]
+pub struct RegisteredChannels {
+    channels: StoredMap<RegisteredChannel>,
+}
+
+impl RegisteredChannels {
+    /// The channels registered so far in `store`.
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        let channels = store.load().channels.into_iter().collect();
+        RegisteredChannels {
+            channels: StoredMap::new(channels, store, |key, channel| Change::Channel { key, channel }),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<RegisteredChannel> {
+        self.channels.get(&name.to_lowercase()).map(|channel| channel.clone())
+    }
+
+    pub async fn register(&self, channel: RegisteredChannel) -> Result<(), RegistrationError> {
+        let _changing = self.channels.lock().await;
+        let key = channel.name.to_lowercase();
+        if self.channels.contains_key(&key) {
+            return Err(RegistrationError::Exists(channel.name));
+        }
+        self.channels.commit(key, Some(channel)).await?;
+        Ok(())
+    }
+
+    pub async fn drop_channel(&self, name: &str) -> Result<(), RegistrationError> {
+        let _changing = self.channels.lock().await;
+        let key = name.to_lowercase();
+        if !self.channels.contains_key(&key) {
+            return Err(RegistrationError::NotRegistered(name.to_owned()));
+        }
+        self.channels.commit(key, None).await?;
+        Ok(())
+    }
+
+    /// The channels `account` founded.
+    pub fn founded_by(&self, account: &str) -> Vec<String> {
+        self.channels
+            .iter()
+            .filter(|channel| channel.founder.eq_ignore_ascii_case(account))
+            .map(|channel| channel.name.clone())
+            .collect()
+    }
+
+    /// Takes `account` off every access list, for when it is dropped.
+    pub async fn remove_access(&self, account: &str) -> Result<(), RegistrationError> {
+        let listed: Vec<String> = self
+            .channels
+            .iter()
+            .filter(|channel| channel.access.iter().any(|access| access.account.eq_ignore_ascii_case(account)))
+            .map(|channel| channel.name.clone())
+            .collect();
+        for name in listed {
+            self.update(&name, |channel| {
+                channel.set_access(account, None);
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Changes the registration of `name` with `change` and saves it if anything changed.
+    pub async fn update<F>(&self, name: &str, change: F) -> Result<RegisteredChannel, RegistrationError>
+    where
+        F: FnOnce(&mut RegisteredChannel),
+    {
+        let _changing = self.channels.lock().await;
+        let mut channel = self
+            .get(name)
+            .ok_or_else(|| RegistrationError::NotRegistered(name.to_owned()))?;
+        let before = channel.clone();
+        change(&mut channel);
+        if channel != before {
+            self.channels.commit(name.to_lowercase(), Some(channel.clone())).await?;
+        }
+        Ok(channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::store::MemoryStore;
+
+    #[test]
+    pub fn access_and_mode_lock() {
+        let mut channel = RegisteredChannel::new("#Pawpaw", "Alice");
+        channel.mlock = "+nt-s".to_owned();
+        assert!(channel.set_access("bob", Some(AccessLevel::Op)));
+        assert!(!channel.set_access("BOB", Some(AccessLevel::Op)));
+        assert!(channel.set_access("carol", Some(AccessLevel::Voice)));
+        assert_eq!(channel.access(Some("alice")), Some(AccessLevel::Founder));
+        assert_eq!(channel.access(Some("Bob")), Some(AccessLevel::Op));
+        assert_eq!(channel.access(Some("carol")), Some(AccessLevel::Voice));
+        assert_eq!(channel.access(Some("dave")), None);
+        assert_eq!(channel.access(None), None);
+        assert!(channel.set_access("carol", None));
+        assert_eq!(channel.access(Some("carol")), None);
+        assert!(channel.is_locked('n', false));
+        assert!(!channel.is_locked('n', true));
+        assert!(channel.is_locked('s', true));
+        assert!(!channel.is_locked('i', true));
+    }
+
+    #[tokio::test]
+    pub async fn registrations_are_stored() {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let channels = RegisteredChannels::new(store.clone());
+        channels.register(RegisteredChannel::new("#Pawpaw", "Alice")).await.unwrap();
+        channels.register(RegisteredChannel::new("#other", "Alice")).await.unwrap();
+        assert!(matches!(
+            channels.register(RegisteredChannel::new("#pawpaw", "Bob")).await,
+            Err(RegistrationError::Exists(_))
+        ));
+        channels
+            .update("#PAWPAW", |channel| {
+                channel.topic = Some("Fruit".to_owned());
+                channel.bans.push("*!*@spam".to_owned());
+            })
+            .await
+            .unwrap();
+        channels.drop_channel("#other").await.unwrap();
+        assert!(matches!(
+            channels.update("#other", |_| {}).await,
+            Err(RegistrationError::NotRegistered(_))
+        ));
+
+        let restored = RegisteredChannels::new(store);
+        let channel = restored.get("#pawpaw").unwrap();
+        assert_eq!(channel.name, "#Pawpaw");
+        assert_eq!(channel.topic.as_deref(), Some("Fruit"));
+        assert_eq!(channel.bans, vec!["*!*@spam".to_owned()]);
+        assert!(restored.get("#other").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn dropped_accounts_lose_access() {
+        let channels = RegisteredChannels::new(Arc::new(MemoryStore::default()));
+        let mut pawpaw = RegisteredChannel::new("#Pawpaw", "Alice");
+        pawpaw.set_access("bob", Some(AccessLevel::Op));
+        channels.register(pawpaw).await.unwrap();
+        let mut other = RegisteredChannel::new("#other", "Bob");
+        other.set_access("carol", Some(AccessLevel::Voice));
+        channels.register(other).await.unwrap();
+        assert_eq!(channels.founded_by("alice"), vec!["#Pawpaw".to_owned()]);
+        assert!(channels.founded_by("carol").is_empty());
+
+        channels.remove_access("Bob").await.unwrap();
+        assert_eq!(channels.get("#pawpaw").unwrap().access(Some("bob")), None);
+        assert_eq!(channels.get("#other").unwrap().access(Some("bob")), Some(AccessLevel::Founder));
+        assert_eq!(channels.get("#other").unwrap().access(Some("carol")), Some(AccessLevel::Voice));
+    }
+}
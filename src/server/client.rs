@@ -1,43 +1,219 @@
+use crate::config::OperPrivilege;
+use crate::proto::Prefix;
+use crate::server::cloak::Host;
+use crate::server::socket::{CertFp, TlsInfo};
 use crate::server::transport::Sender;
 use dashmap::DashSet;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
 
 #[derive(Debug)]
 pub struct ServerClient {
-    nickname: RwLock<String>,
+    /// Behind a std lock so the nick can be read while holding a guard on the client map.
+    nickname: Mutex<String>,
     user_name: String,
-    server_name: String,
-    hostname: String,
+    host: Host,
+    /// User mode +x, showing the cloak instead of the real host.
+    cloaked: AtomicBool,
+    /// Shown instead of both the cloak and the real host.
+    vhost: Mutex<Option<String>>,
+    realname: String,
     sender: Sender,
+    tls: Option<TlsInfo>,
     connected_channels: DashSet<String>,
+    /// Reasons operators see in WHOIS, like a DNSBL listing.
+    marks: DashSet<String>,
+    oper: AtomicBool,
+    privileges: DashSet<OperPrivilege>,
+    caps: DashSet<&'static str>,
+    /// The account the client logged in to.
+    account: Mutex<Option<String>>,
+    /// The CAP version the client negotiated, which decides how capabilities are listed.
+    cap_version: AtomicU32,
+    /// Ends the client's connection with the reason sent through it.
+    kill: Mutex<Option<oneshot::Sender<String>>>,
 }
 
 impl ServerClient {
     pub fn new(
         nick: String,
         user_name: String,
-        server_name: String,
-        hostname: String,
+        host: Host,
+        realname: String,
         sender: Sender,
+        tls: Option<TlsInfo>,
+        kill: oneshot::Sender<String>,
     ) -> Self {
         Self {
-            nickname: RwLock::new(nick),
+            nickname: Mutex::new(nick),
             user_name,
-            server_name,
-            hostname,
+            host,
+            cloaked: AtomicBool::new(false),
+            vhost: Mutex::new(None),
+            realname,
             sender,
+            tls,
             connected_channels: DashSet::new(),
+            marks: DashSet::new(),
+            oper: AtomicBool::new(false),
+            privileges: DashSet::new(),
+            caps: DashSet::new(),
+            account: Mutex::new(None),
+            cap_version: AtomicU32::new(0),
+            kill: Mutex::new(Some(kill)),
         }
     }
 
-    pub async fn get_nickname(&self) -> String {
-        self.nickname.read().await.clone()
+    pub fn get_nickname(&self) -> String {
+        self.nickname.lock().unwrap().clone()
     }
 
-    pub async fn set_nickname(&self, nick: String) {
-        let mut n = self.nickname.write().await;
-        *n = nick;
+    pub fn set_nickname(&self, nick: String) {
+        *self.nickname.lock().unwrap() = nick;
     }
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    pub fn user_name(&self) -> &str {
+        &self.user_name
+    }
+
+    /// The real hostname, see `displayed_host` for what others get to see.
+    pub fn hostname(&self) -> &str {
+        &self.host.hostname
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    /// The vhost if there is one, otherwise the cloak under +x, otherwise the real host.
+    pub fn displayed_host(&self) -> String {
+        if let Some(vhost) = self.vhost.lock().unwrap().as_ref() {
+            return vhost.clone();
+        }
+        match &self.host.cloak {
+            Some(cloak) if self.is_cloaked() => cloak.clone(),
+            _ => self.host.hostname.clone(),
+        }
+    }
+
+    /// Sets or removes the vhost, returning whether the displayed host changed.
+    pub fn set_vhost(&self, vhost: Option<String>) -> bool {
+        let before = self.displayed_host();
+        *self.vhost.lock().unwrap() = vhost;
+        self.displayed_host() != before
+    }
+
+    pub fn is_cloaked(&self) -> bool {
+        self.cloaked.load(Ordering::Acquire)
+    }
+
+    /// Sets or unsets +x, which only works with a cloak. Returns whether the mode changed.
+    pub fn set_cloaked(&self, cloaked: bool) -> bool {
+        if self.host.cloak.is_none() {
+            return false;
+        }
+        self.cloaked.swap(cloaked, Ordering::AcqRel) != cloaked
+    }
+
+    pub fn prefix(&self) -> Prefix {
+        Prefix::Usermask(
+            self.get_nickname(),
+            self.user_name.clone(),
+            self.displayed_host(),
+        )
+    }
+
+    /// Every mask bans are matched against: a `nick!user@host` for the real host, the
+    /// address, the vhost and the cloak whether or not it is shown, and `$a:account` once
+    /// logged in.
+    pub fn masks(&self) -> Vec<String> {
+        let nick = self.get_nickname();
+        let mut hosts = vec![self.host.hostname.clone(), self.host.ip.to_string()];
+        hosts.extend(self.host.cloak.clone());
+        hosts.extend(self.vhost.lock().unwrap().clone());
+        hosts.dedup();
+        let mut masks: Vec<String> = hosts
+            .into_iter()
+            .map(|host| format!("{}!{}@{}", nick, self.user_name, host))
+            .collect();
+        masks.extend(self.account().map(|account| format!("$a:{}", account)));
+        masks
+    }
+
+    pub fn account(&self) -> Option<String> {
+        self.account.lock().unwrap().clone()
+    }
+
+    pub fn set_account(&self, account: Option<String>) {
+        *self.account.lock().unwrap() = account;
+    }
+
+    /// Disconnects the client, returning false if that already happened.
+    pub fn kill(&self, reason: String) -> bool {
+        match self.kill.lock().unwrap().take() {
+            Some(kill) => kill.send(reason).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn realname(&self) -> &str {
+        &self.realname
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn certfp(&self) -> Option<&CertFp> {
+        self.tls.as_ref().and_then(|tls| tls.certfp.as_ref())
+    }
+
+    pub fn marks(&self) -> Vec<String> {
+        self.marks.iter().map(|mark| mark.clone()).collect()
+    }
+
+    pub fn mark(&self, reason: String) {
+        self.marks.insert(reason);
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.contains(cap)
+    }
+
+    pub fn cap_version(&self) -> u32 {
+        self.cap_version.load(Ordering::Acquire)
+    }
+
+    pub fn set_caps(&self, caps: &[&'static str], version: u32) {
+        self.caps.clear();
+        for cap in caps {
+            self.caps.insert(cap);
+        }
+        self.cap_version.store(version, Ordering::Release);
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.oper.load(Ordering::Acquire)
+    }
+
+    pub fn set_oper(&self, oper: bool) {
+        self.oper.store(oper, Ordering::Release)
+    }
+
+    pub fn grant(&self, privileges: &[OperPrivilege]) {
+        for privilege in privileges {
+            self.privileges.insert(*privilege);
+        }
+    }
+
+    pub fn has_privilege(&self, privilege: OperPrivilege) -> bool {
+        self.is_oper() && self.privileges.contains(&privilege)
+    }
+
     pub fn join_channel(&self, channel: &String) -> bool {
         if self.connected_channels.contains(channel) {
             return false;
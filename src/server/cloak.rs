@@ -0,0 +1,161 @@
+use crate::config::CloakConfig;
+use crate::server::dns::ip_hostname;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+/// Hex digits of the HMAC kept for every part of a cloak.
+const HASH_LEN: usize = 8;
+
+/// A client's real hostname and address, and the cloak shown in their place while it has
+/// user mode +x.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    pub hostname: String,
+    pub ip: IpAddr,
+    pub cloak: Option<String>,
+}
+
+/// Derives stable cloaks from the configured keys. Cloaks keep the structure of what they
+/// hide, the domain of a hostname stays readable and addresses in the same network share
+/// the trailing parts of their cloak, so bans on a cloak can still cover a whole range.
+#[derive(Debug)]
+pub struct Cloak {
+    keys: Vec<String>,
+    prefix: String,
+    default: bool,
+}
+
+impl Cloak {
+    pub fn new(config: &CloakConfig) -> Self {
+        Self {
+            keys: config.keys.clone(),
+            prefix: config.prefix.clone(),
+            default: config.default,
+        }
+    }
+
+    /// Whether clients get +x when they connect.
+    pub fn by_default(&self) -> bool {
+        self.default && !self.keys.is_empty()
+    }
+
+    /// The host of a client with `hostname` connecting from `ip`. Clients without a
+    /// hostname of their own have their address as hostname, which is cloaked as such.
+    pub fn host(&self, hostname: &str, ip: IpAddr) -> Host {
+        let cloak = if self.keys.is_empty() {
+            None
+        } else if hostname == ip_hostname(ip) {
+            Some(self.cloak_ip(ip))
+        } else {
+            Some(self.cloak_hostname(hostname))
+        };
+        Host {
+            hostname: hostname.to_owned(),
+            ip,
+            cloak,
+        }
+    }
+
+    /// `host.example.com` becomes `prefix-HASH.example.com`, only the first label is
+    /// hidden unless that would leave nothing but the top level domain.
+    fn cloak_hostname(&self, hostname: &str) -> String {
+        let hash = self.hash(hostname.to_ascii_lowercase().as_bytes());
+        let domain = match hostname.split_once('.') {
+            Some((_, domain)) if domain.contains('.') => Some(domain),
+            _ => hostname.rsplit_once('.').map(|(_, tld)| tld),
+        };
+        match domain {
+            Some(domain) => format!("{}-{}.{}", self.prefix, hash, domain),
+            None => format!("{}-{}", self.prefix, hash),
+        }
+    }
+
+    /// `a.b.c.d` becomes `HASH(a.b.c.d).HASH(a.b.c).HASH(a.b).IP`, and IPv6 addresses
+    /// likewise hash the full address, its /64 and its /48.
+    fn cloak_ip(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                format!(
+                    "{}.{}.{}.IP",
+                    self.hash(&octets),
+                    self.hash(&octets[..3]),
+                    self.hash(&octets[..2])
+                )
+            }
+            IpAddr::V6(ip) => {
+                let octets = ip.octets();
+                format!(
+                    "{}:{}:{}:IP",
+                    self.hash(&octets),
+                    self.hash(&octets[..8]),
+                    self.hash(&octets[..6])
+                )
+            }
+        }
+    }
+
+    /// HMAC-SHA256 of `data` under every key in turn.
+    fn hash(&self, data: &[u8]) -> String {
+        let mut digest = data.to_vec();
+        for key in &self.keys {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                .expect("HMAC takes keys of any length");
+            mac.update(&digest);
+            digest = mac.finalize().into_bytes().to_vec();
+        }
+        let mut hash = hex::encode_upper(digest);
+        hash.truncate(HASH_LEN);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cloak(keys: &[&str]) -> Cloak {
+        Cloak::new(&CloakConfig {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            prefix: "test".to_string(),
+            default: true,
+        })
+    }
+
+    #[test]
+    pub fn hostnames_keep_their_domain() {
+        let cloak = cloak(&["key one", "key two"]);
+        let host = cloak.host("dsl-1.isp.example", "192.0.2.1".parse().unwrap());
+        let cloaked = host.cloak.unwrap();
+        assert!(cloaked.starts_with("test-"));
+        assert!(cloaked.ends_with(".isp.example"));
+        assert!(!cloaked.contains("dsl-1"));
+        assert_eq!(cloak.cloak_hostname("DSL-1.isp.example"), cloaked);
+        assert!(cloak.cloak_hostname("isp.example").ends_with(".example"));
+        assert!(!cloak.cloak_hostname("localhost").contains("localhost"));
+    }
+
+    #[test]
+    pub fn addresses_share_their_network_parts() {
+        let cloak = cloak(&["key one"]);
+        let a = cloak.host("192.0.2.1", "192.0.2.1".parse().unwrap()).cloak.unwrap();
+        let b = cloak.host("192.0.2.2", "192.0.2.2".parse().unwrap()).cloak.unwrap();
+        let (a_host, a_net) = a.split_once('.').unwrap();
+        let (b_host, b_net) = b.split_once('.').unwrap();
+        assert_ne!(a_host, b_host);
+        assert_eq!(a_net, b_net);
+        assert!(a_net.ends_with(".IP"));
+        let v6 = cloak.cloak_ip("2001:db8::1".parse().unwrap());
+        assert!(v6.ends_with(":IP"));
+        assert!(!v6.contains("2001"));
+    }
+
+    #[test]
+    pub fn keys_change_every_cloak() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_ne!(cloak(&["one"]).cloak_ip(ip), cloak(&["two"]).cloak_ip(ip));
+        assert_eq!(cloak(&["one"]).cloak_ip(ip), cloak(&["one"]).cloak_ip(ip));
+        assert_eq!(cloak(&[]).host("192.0.2.1", ip).cloak, None);
+    }
+}
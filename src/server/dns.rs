@@ -0,0 +1,179 @@
+use crate::config::DnsConfig;
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::time;
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Longest hostname we give a client, as in other ircds.
+const HOSTLEN: usize = 63;
+/// PTR records tried before giving up on forward confirmation.
+const MAX_PTR: usize = 4;
+/// Above this many cached addresses expired entries are swept on every insert.
+const CACHE_SWEEP_THRESHOLD: usize = 1024;
+
+/// Why a client didn't get a hostname. The messages are the notices sent to the client.
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum HostnameError {
+    #[error("Couldn't look up your hostname")]
+    NotFound,
+    #[error("Your forward and reverse DNS do not match, ignoring hostname")]
+    Mismatch,
+    #[error("Your hostname is too long, ignoring hostname")]
+    TooLong,
+    #[error("Your hostname is invalid, ignoring hostname")]
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hostname {
+    pub name: String,
+    pub cached: bool,
+}
+
+/// Forward-confirmed reverse DNS: a PTR name is only used when it resolves back to the
+/// address it was looked up for. Confirmed names are cached until their records expire,
+/// but never longer than the configured TTL.
+#[derive(Debug)]
+pub struct HostResolver {
+    resolver: TokioAsyncResolver,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: DashMap<IpAddr, (String, Instant)>,
+}
+
+impl HostResolver {
+    pub fn new(resolver: TokioAsyncResolver, config: &DnsConfig) -> Self {
+        Self {
+            resolver,
+            timeout: config.timeout(),
+            cache_ttl: config.cache_ttl(),
+            cache: DashMap::new(),
+        }
+    }
+
+    pub fn resolver(&self) -> &TokioAsyncResolver {
+        &self.resolver
+    }
+
+    pub async fn lookup(&self, ip: IpAddr) -> Result<Hostname, HostnameError> {
+        let now = Instant::now();
+        if let Some(entry) = self.cache.get(&ip) {
+            if entry.1 > now {
+                return Ok(Hostname {
+                    name: entry.0.clone(),
+                    cached: true,
+                });
+            }
+        }
+        let (name, valid_until) = time::timeout(self.timeout, self.confirm(ip))
+            .await
+            .unwrap_or(Err(HostnameError::NotFound))?;
+        if self.cache.len() > CACHE_SWEEP_THRESHOLD {
+            self.cache.retain(|_, (_, expires)| *expires > now);
+        }
+        let expires = valid_until.min(now + self.cache_ttl);
+        self.cache.insert(ip, (name.clone(), expires));
+        Ok(Hostname {
+            name,
+            cached: false,
+        })
+    }
+
+    /// Returns the first PTR name that resolves back to `ip`, and when its records expire.
+    async fn confirm(&self, ip: IpAddr) -> Result<(String, Instant), HostnameError> {
+        let ptr = self
+            .resolver
+            .reverse_lookup(ip)
+            .await
+            .map_err(|_| HostnameError::NotFound)?;
+        let mut error = HostnameError::NotFound;
+        for name in ptr.iter().take(MAX_PTR) {
+            let name = name.to_ascii();
+            let name = name.trim_end_matches('.');
+            if let Err(e) = validate(name) {
+                error = e;
+                continue;
+            }
+            match self.resolver.lookup_ip(name).await {
+                Ok(forward) if forward.iter().any(|addr| addr == ip) => {
+                    let valid_until = ptr.valid_until().min(forward.valid_until());
+                    return Ok((name.to_ascii_lowercase(), valid_until));
+                }
+                _ => error = HostnameError::Mismatch,
+            }
+        }
+        Err(error)
+    }
+}
+
+/// A resolver for the configured nameservers, or the system's when there are none.
+pub fn resolver(config: &DnsConfig) -> TokioAsyncResolver {
+    if config.nameservers.is_empty() {
+        return TokioAsyncResolver::tokio_from_system_conf().expect("Failed to create DNS resolver.");
+    }
+    let nameservers: Vec<_> = config
+        .nameservers
+        .iter()
+        .map(|addr| NameServerConfig::new(*addr, Protocol::Udp))
+        .collect();
+    let config = ResolverConfig::from_parts(None, Vec::new(), nameservers);
+    TokioAsyncResolver::tokio(config, ResolverOpts::default()).expect("Failed to create DNS resolver.")
+}
+
+/// Hostnames are limited to letters, digits and hyphens in dot separated labels, so they
+/// can't smuggle anything into a user's mask.
+pub fn validate(name: &str) -> Result<(), HostnameError> {
+    if name.len() > HOSTLEN {
+        return Err(HostnameError::TooLong);
+    }
+    let valid = !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(HostnameError::Invalid)
+    }
+}
+
+/// The hostname of a client without one. IPv6 addresses can start with a colon, which
+/// would end up looking like a trailing parameter, so those get a leading zero.
+pub fn ip_hostname(ip: IpAddr) -> String {
+    let host = ip.to_string();
+    if host.starts_with(':') {
+        format!("0{}", host)
+    } else {
+        host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn hostname_syntax() {
+        assert_eq!(validate("host-1.example.com"), Ok(()));
+        assert_eq!(validate("localhost"), Ok(()));
+        assert_eq!(validate("bad_host.example"), Err(HostnameError::Invalid));
+        assert_eq!(validate("-dash.example"), Err(HostnameError::Invalid));
+        assert_eq!(validate("double..dot"), Err(HostnameError::Invalid));
+        assert_eq!(validate("evil@host"), Err(HostnameError::Invalid));
+        let long = format!("{}.example", "a".repeat(60));
+        assert_eq!(validate(&long), Err(HostnameError::TooLong));
+    }
+
+    #[test]
+    pub fn ipv6_hostname_gets_leading_zero() {
+        assert_eq!(ip_hostname("::1".parse().unwrap()), "0::1");
+        assert_eq!(ip_hostname("2001:db8::1".parse().unwrap()), "2001:db8::1");
+        assert_eq!(ip_hostname("192.0.2.1".parse().unwrap()), "192.0.2.1");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::config::{DnsblAction, DnsblConfig, DnsblZone};
+use dashmap::DashMap;
+use futures::future::join_all;
+use ipnet::IpNet;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use tokio::time;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Above this many cached addresses expired entries are swept on every insert.
+const CACHE_SWEEP_THRESHOLD: usize = 1024;
+
+/// A blocklist that has the client's address listed with a reply we act on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsblHit {
+    /// Name of the zone's block in the config.
+    pub name: String,
+    pub reply: Ipv4Addr,
+    pub action: DnsblAction,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub struct Dnsbl {
+    zones: Vec<(String, DnsblZone)>,
+    exempt: Vec<IpNet>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: DashMap<IpAddr, (Vec<DnsblHit>, Instant)>,
+}
+
+impl Dnsbl {
+    pub fn new(config: &DnsblConfig, timeout: Duration) -> Self {
+        Self {
+            zones: config.zones.clone().into_iter().collect(),
+            exempt: config.exempt.clone(),
+            timeout,
+            cache_ttl: config.cache_ttl(),
+            cache: DashMap::new(),
+        }
+    }
+
+    /// Queries every zone for `ip` at once. Zones that time out or fail are treated as not
+    /// listing the address.
+    pub async fn check(&self, resolver: &TokioAsyncResolver, ip: IpAddr) -> Vec<DnsblHit> {
+        if self.exempt.iter().any(|net| net.contains(&ip)) {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        if let Some(entry) = self.cache.get(&ip) {
+            if entry.1 > now {
+                return entry.0.clone();
+            }
+        }
+        let lookups = self
+            .zones
+            .iter()
+            .map(|(name, zone)| self.check_zone(resolver, name, zone, ip));
+        let hits: Vec<DnsblHit> = join_all(lookups).await.into_iter().flatten().collect();
+        if self.cache.len() > CACHE_SWEEP_THRESHOLD {
+            self.cache.retain(|_, (_, expires)| *expires > now);
+        }
+        self.cache.insert(ip, (hits.clone(), now + self.cache_ttl));
+        hits
+    }
+
+    async fn check_zone(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+        zone: &DnsblZone,
+        ip: IpAddr,
+    ) -> Option<DnsblHit> {
+        let query = format!("{}.{}.", reverse_name(ip), zone.zone.trim_end_matches('.'));
+        let replies = time::timeout(self.timeout, resolver.ipv4_lookup(query))
+            .await
+            .ok()?
+            .ok()?;
+        let (reply, action) = replies
+            .iter()
+            .filter_map(|reply| zone.replies.get(reply).map(|action| (*reply, *action)))
+            .max_by_key(|(_, action)| *action)?;
+        let reason = zone
+            .reason
+            .replace("{ip}", &ip.to_string())
+            .replace("{zone}", &zone.zone)
+            .replace("{reply}", &reply.to_string());
+        Some(DnsblHit {
+            name: name.to_owned(),
+            reply,
+            action,
+            reason,
+        })
+    }
+}
+
+/// The address in the form blocklists expect in front of their zone, octets or nibbles in
+/// reverse order.
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(63);
+            for byte in ip.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", byte & 0x0f, byte >> 4);
+            }
+            name.pop();
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn reverse_names() {
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+}
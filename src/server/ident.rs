@@ -0,0 +1,160 @@
+use crate::config::IdentConfig;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpSocket;
+use tokio::time;
+
+/// Longest username a client can end up with, the ident reply is cut down to this.
+pub const USERLEN: usize = 10;
+/// RFC 1413 caps replies at 1000 characters.
+const MAX_REPLY: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum IdentError {
+    #[error("ident connection error")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("ident lookup timed out")]
+    Timeout,
+    #[error("ident server replied with error {0}")]
+    Error(String),
+    #[error("malformed ident reply")]
+    Malformed,
+}
+
+/// Asks the ident server on a client's host which user owns its connection (RFC 1413).
+#[derive(Debug)]
+pub struct Ident {
+    enabled: bool,
+    port: u16,
+    timeout: Duration,
+}
+
+impl Ident {
+    pub fn new(config: &IdentConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            port: config.port,
+            timeout: config.timeout(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Looks up the user behind the connection from `client` to our `server` address.
+    pub async fn lookup(&self, client: SocketAddr, server: SocketAddr) -> Result<String, IdentError> {
+        time::timeout(self.timeout, self.query(client, server))
+            .await
+            .unwrap_or(Err(IdentError::Timeout))
+    }
+
+    async fn query(&self, client: SocketAddr, server: SocketAddr) -> Result<String, IdentError> {
+        let socket = match client {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // Connect from the address the client reached us on so multihomed hosts match up.
+        socket.bind(SocketAddr::new(server.ip(), 0))?;
+        let mut stream = socket
+            .connect(SocketAddr::new(client.ip(), self.port))
+            .await?;
+        let query = format!("{}, {}\r\n", client.port(), server.port());
+        stream.write_all(query.as_bytes()).await?;
+        let mut reply = String::new();
+        BufReader::new(stream.take(MAX_REPLY))
+            .read_line(&mut reply)
+            .await?;
+        parse_reply(&reply, client.port(), server.port())
+    }
+}
+
+/// Parses `<client port> , <server port> : USERID : <os> : <user id>`.
+fn parse_reply(reply: &str, client_port: u16, server_port: u16) -> Result<String, IdentError> {
+    let mut fields = reply.trim_end_matches(['\r', '\n']).splitn(4, ':');
+    let ports = fields.next().ok_or(IdentError::Malformed)?;
+    let (client, server) = ports.split_once(',').ok_or(IdentError::Malformed)?;
+    if client.trim().parse() != Ok(client_port) || server.trim().parse() != Ok(server_port) {
+        return Err(IdentError::Malformed);
+    }
+    match fields.next().map(str::trim) {
+        Some("USERID") => {}
+        Some("ERROR") => {
+            let error = fields.next().unwrap_or_default().trim();
+            return Err(IdentError::Error(error.to_owned()));
+        }
+        _ => return Err(IdentError::Malformed),
+    }
+    let _os = fields.next().ok_or(IdentError::Malformed)?;
+    let user = fields.next().ok_or(IdentError::Malformed)?.trim();
+    let valid = !user.is_empty()
+        && !user.starts_with('~')
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(IdentError::Malformed);
+    }
+    Ok(user.chars().take(USERLEN).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    pub fn reply_parsing() {
+        assert_eq!(
+            parse_reply("6193, 23 : USERID : UNIX : stjohns\r\n", 6193, 23).unwrap(),
+            "stjohns"
+        );
+        assert_eq!(
+            parse_reply("6193,23:USERID:UNIX,UTF-8:averyveryverylongname\r\n", 6193, 23).unwrap(),
+            "averyveryv"
+        );
+        assert!(matches!(
+            parse_reply("6195, 23 : ERROR : NO-USER\r\n", 6195, 23),
+            Err(IdentError::Error(e)) if e == "NO-USER"
+        ));
+        assert!(matches!(
+            parse_reply("6193, 24 : USERID : UNIX : stjohns\r\n", 6193, 23),
+            Err(IdentError::Malformed)
+        ));
+        assert!(matches!(
+            parse_reply("6193, 23 : USERID : UNIX : bad user\r\n", 6193, 23),
+            Err(IdentError::Malformed)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn queries_stand_in_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut query = String::new();
+            stream.read_line(&mut query).await.unwrap();
+            let (client, server) = query.trim().split_once(',').unwrap();
+            let reply = format!("{}, {} : USERID : UNIX : alice\r\n", client, server.trim());
+            stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        });
+        let ident = Ident::new(&IdentConfig {
+            enabled: true,
+            port,
+            timeout: 2,
+        });
+        let user = ident
+            .lookup("127.0.0.1:40000".parse().unwrap(), "127.0.0.1:6667".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(user, "alice");
+    }
+}
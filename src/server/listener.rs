@@ -1,15 +1,34 @@
+use std::fmt;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use ipnet::IpNet;
 use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-#[cfg(feature = "native-tls")]
-use tokio_native_tls::TlsAcceptor;
-#[cfg(feature = "rustls")]
-use tokio_rustls::TlsAcceptor;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio::time::Instant;
 
-use log::debug;
+use log::{debug, error};
 
+use crate::config::ClassConfig;
+use crate::server::proxy::{self, ProxyError};
 use crate::server::socket::Socket;
+use crate::server::throttle::{ConnectionGuard, Throttle, ThrottleError};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::server::tls::AcceptorHandle;
+use crate::server::websocket::{self, WebSocketSettings};
+
+/// How long a rejected connection gets to receive its ERROR line.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long an accept loop waits after a failed accept, which is usually the process
+/// running out of file descriptors and would fail again straight away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Error)]
 pub enum ListenerError {
@@ -26,19 +45,118 @@ pub enum ListenerError {
     },
     #[cfg(feature = "rustls")]
     #[error("tls error")]
-    TlsError {
+    RusTlsError { source: io::Error },
+    #[error("rejected connection from {0}: {1}")]
+    Rejected(SocketAddr, ThrottleError),
+    #[error("handshake with {0} timed out")]
+    HandshakeTimeout(SocketAddr),
+    #[error("PROXY protocol error")]
+    Proxy {
         #[from]
-        source: tokio_rustls::rustls::Error,
+        source: ProxyError,
     },
+    #[error("{0} is not a trusted proxy")]
+    UntrustedProxy(SocketAddr),
+    #[error("websocket error")]
+    WebSocket {
+        source: Box<tokio_tungstenite::tungstenite::Error>,
+    },
+}
+
+/// What the accept loop needs to know about a listener besides its socket.
+#[derive(Debug)]
+pub struct ListenerSettings {
+    pub class: Arc<ClassConfig>,
+    /// Time allowed for the TLS and WebSocket handshakes together.
+    pub handshake_timeout: Duration,
+    /// Connections that may be in their handshake at once.
+    pub max_handshakes: usize,
+    pub websocket: Option<WebSocketSettings>,
+    /// Expect a PROXY protocol header ahead of everything else.
+    pub proxy_protocol: bool,
+    /// Proxies allowed to tell us the client's real address.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl ListenerSettings {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
 }
 
+/// A connection that passed the connection limits and finished its handshake.
 #[derive(Debug)]
+pub struct Accepted {
+    pub socket: Socket<TcpStream>,
+    /// The client's address, which is not the peer's when a trusted proxy forwarded it.
+    pub addr: SocketAddr,
+    /// Our end of the client's connection, unknown when it came through a proxy.
+    pub local: Option<SocketAddr>,
+    pub guard: ConnectionGuard,
+    pub class: Arc<ClassConfig>,
+    pub starttls: Option<StartTls>,
+    /// Set for local connections, which skip the hostname lookup.
+    pub hostname: Option<String>,
+}
+
+/// How to upgrade a plaintext connection if the client sends STARTTLS.
+#[derive(Clone)]
+pub struct StartTls {
+    pub handshake: Handshake,
+    pub timeout: Duration,
+}
+
+impl fmt::Debug for StartTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StartTls")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// Sends a rejected connection a short ERROR line and closes it off the accept loop.
+fn reject<S>(mut socket: S, addr: SocketAddr, e: ThrottleError) -> ListenerError
+where
+    S: AsyncWrite + Unpin + Send + 'static,
+{
+    let line = format!("ERROR :Closing Link: {} ({})\r\n", addr.ip(), e);
+    tokio::spawn(async move {
+        let _ = time::timeout(REJECT_TIMEOUT, async {
+            socket.write_all(line.as_bytes()).await?;
+            socket.flush().await
+        })
+        .await;
+    });
+    ListenerError::Rejected(addr, e)
+}
+
 pub enum Listener {
-    #[cfg(feature = "native-tls")]
-    Tls(String, TcpListener, TlsAcceptor),
-    #[cfg(feature = "rustls")]
-    Tls(TcpListener, TlsAcceptor),
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    Tls(String, TcpListener, AcceptorHandle),
     Plain(String, TcpListener),
+    /// A plaintext listener that lets clients upgrade with STARTTLS.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    StartTls(String, TcpListener, AcceptorHandle),
+}
+
+/// The part of a listener needed to finish a connection off the accept loop. A TLS
+/// handshake takes whatever acceptor is current once it runs, so the first connection
+/// after a reload already gets the new certificate.
+#[derive(Clone)]
+pub enum Handshake {
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    Tls(AcceptorHandle),
+    Plain,
+}
+
+impl Handshake {
+    pub async fn run(self, socket: TcpStream) -> Result<Socket<TcpStream>, ListenerError> {
+        match self {
+            Handshake::Plain => Ok(Socket::Plain(socket)),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            Handshake::Tls(accept) => accept.accept(socket).await,
+        }
+    }
 }
 
 impl Listener {
@@ -47,64 +165,306 @@ impl Listener {
         Ok(Listener::Plain(name, listener))
     }
 
-    #[cfg(feature = "native-tls")]
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
     pub async fn new_tls(
         name: String,
         addr: SocketAddr,
-        tls: TlsAcceptor,
+        tls: AcceptorHandle,
     ) -> Result<Listener, io::Error> {
         let listener = TcpListener::bind(addr).await?;
         Ok(Listener::Tls(name, listener, tls))
     }
 
-    #[cfg(rustls)]
-    pub async fn new_tls(addr: SocketAddr, tls: TlsAcceptor) -> Result<Listener, io::Error> {
-        unimplemented!();
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub async fn new_starttls(
+        name: String,
+        addr: SocketAddr,
+        tls: AcceptorHandle,
+    ) -> Result<Listener, io::Error> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(Listener::Tls(listener, tls))
+        Ok(Listener::StartTls(name, listener, tls))
     }
 
-    pub async fn accept(&self) -> Result<Socket<TcpStream>, ListenerError> {
+    /// The listener's name and socket, the handshake for new connections and the upgrade
+    /// offered through STARTTLS.
+    fn parts(&self) -> (&str, &TcpListener, Handshake, Option<Handshake>) {
         match self {
-            Listener::Plain(ref name, ref acceptor) => {
-                let (socket, addr) = acceptor
-                    .accept()
-                    .await
-                    .map_err(|source| ListenerError::ConnectionError { source })?;
-                debug!(
-                    "ACCEPTOR(plain): {} accepted a connection from: {}",
-                    name, addr
-                );
-                Ok(Socket::Plain(socket))
+            Listener::Plain(ref name, ref listen) => (name, listen, Handshake::Plain, None),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            Listener::StartTls(ref name, ref listen, ref accept) => {
+                (name, listen, Handshake::Plain, Some(Handshake::Tls(accept.clone())))
             }
-            #[cfg(feature = "native-tls")]
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
             Listener::Tls(ref name, ref listen, ref accept) => {
-                let (socket, addr) = listen
-                    .accept()
-                    .await
-                    .map_err(|source| ListenerError::ConnectionError { source })?;
-                let stream = accept
-                    .accept(socket)
-                    .await
-                    .map_err(|source| ListenerError::TlsError { source })?;
-                debug!(
-                    "ACCEPTOR(native-tls): {} accepted a connection from: {}",
-                    name, addr
-                );
-                Ok(Socket::Tls(stream))
+                (name, listen, Handshake::Tls(accept.clone()), None)
             }
-            #[cfg(feature = "rustls")]
-            Listener::Tls(ref name, ref listen, ref accept) => {
-                let (socket, addr) = listen
-                    .accept()
-                    .await
-                    .map_err(|source| ListenerError::TlsError { source })?;
-                debug!(
-                    "ACCEPTOR(rustls): {} accepted a connection from: {}",
-                    name, addr
-                );
-                unimplemented!();
+        }
+    }
+
+    /// Checks a connection against the connection limits.
+    fn admit<S>(
+        socket: S,
+        addr: SocketAddr,
+        throttle: &Arc<Throttle>,
+        class: &ClassConfig,
+    ) -> Result<(S, ConnectionGuard), ListenerError>
+    where
+        S: AsyncWrite + Unpin + Send + 'static,
+    {
+        match throttle.admit(addr.ip(), class, Instant::now()) {
+            Ok(guard) => Ok((socket, guard)),
+            Err(e) => Err(reject(socket, addr, e)),
+        }
+    }
+
+    /// Reads the PROXY header if the listener expects one, then runs the TLS handshake and,
+    /// on WebSocket listeners, the WebSocket handshake.
+    async fn handshake(
+        handshake: Handshake,
+        mut socket: TcpStream,
+        mut addr: SocketAddr,
+        settings: &ListenerSettings,
+    ) -> Result<(Socket<TcpStream>, SocketAddr), ListenerError> {
+        if settings.proxy_protocol {
+            if !settings.is_trusted(&addr.ip()) {
+                return Err(ListenerError::UntrustedProxy(addr));
             }
+            if let Some(source) = proxy::read_header(&mut socket).await? {
+                addr = source;
+            }
+        }
+        let socket = handshake.run(socket).await?;
+        match &settings.websocket {
+            Some(websocket) => {
+                let tls = socket.tls_info();
+                let (stream, addr) = websocket::accept(
+                    Box::new(socket),
+                    tls,
+                    addr,
+                    websocket,
+                    &settings.trusted_proxies,
+                )
+                .await?;
+                Ok((Socket::Ws(Box::new(stream)), addr))
+            }
+            None => Ok((socket, addr)),
         }
     }
+
+    /// Runs the accept loop for this listener. Every admitted connection gets its own task
+    /// for the handshake so a slow client can't hold up anyone else, completed connections
+    /// are handed to the server through `tx`. Once `max_handshakes` are underway nothing
+    /// more is accepted until one of them finishes or times out.
+    pub fn spawn(
+        self,
+        throttle: Arc<Throttle>,
+        settings: Arc<ListenerSettings>,
+        tx: mpsc::Sender<Accepted>,
+    ) -> JoinHandle<()> {
+        let handshakes = Arc::new(Semaphore::new(settings.max_handshakes));
+        tokio::spawn(async move {
+            loop {
+                let (name, listen, handshake, starttls) = self.parts();
+                let starttls = starttls.map(|handshake| StartTls {
+                    handshake,
+                    timeout: settings.handshake_timeout,
+                });
+                let permit = handshakes.clone().acquire_owned().await.unwrap();
+                let (socket, addr) = match listen.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("ACCEPTOR: {} failed to accept a connection: {}", name, e);
+                        time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let local = socket.local_addr().ok();
+                // Trusted proxies are admitted under the address they forward for once the
+                // handshake tells us what it is, so they don't use up the limits themselves.
+                let (socket, guard) = if settings.is_trusted(&addr.ip()) {
+                    (socket, None)
+                } else {
+                    match Self::admit(socket, addr, &throttle, &settings.class) {
+                        Ok((socket, guard)) => (socket, Some(guard)),
+                        Err(e) => {
+                            debug!("ACCEPTOR: {} {}", name, e);
+                            continue;
+                        }
+                    }
+                };
+                let name = name.to_owned();
+                let settings = settings.clone();
+                let throttle = throttle.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let connect = Self::handshake(handshake, socket, addr, &settings);
+                    let connected = time::timeout(settings.handshake_timeout, connect).await;
+                    drop(permit);
+                    let (socket, client) = match connected {
+                        Ok(Ok(connected)) => connected,
+                        Ok(Err(e)) => {
+                            debug!("ACCEPTOR: {} handshake with {} failed: {}", name, addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("ACCEPTOR: {} {}", name, ListenerError::HandshakeTimeout(addr));
+                            return;
+                        }
+                    };
+                    let (socket, guard) = match guard {
+                        Some(guard) => (socket, guard),
+                        None => match Self::admit(socket, client, &throttle, &settings.class) {
+                            Ok(admitted) => admitted,
+                            Err(e) => {
+                                debug!("ACCEPTOR: {} {}", name, e);
+                                return;
+                            }
+                        },
+                    };
+                    let local = if client == addr { local } else { None };
+                    let addr = client;
+                    debug!("ACCEPTOR: {} accepted a connection from: {}", name, addr);
+                    let _ = tx
+                        .send(Accepted {
+                            socket,
+                            addr,
+                            local,
+                            guard,
+                            class: settings.class.clone(),
+                            starttls,
+                            hostname: None,
+                        })
+                        .await;
+                });
+            }
+        })
+    }
+}
+
+/// A Unix domain socket for bridges and bouncers running on the same host.
+#[cfg(unix)]
+pub struct LocalListener {
+    name: String,
+    listener: UnixListener,
+    hostname: String,
+}
+
+#[cfg(unix)]
+impl LocalListener {
+    /// Binds `path` with the given permissions, replacing a socket left behind by an
+    /// earlier run. The socket is bound in a directory nobody else can enter and only moved
+    /// to `path` once it has its permissions, so it is never reachable with the umask's.
+    pub fn bind(
+        name: String,
+        path: &str,
+        mode: u32,
+        hostname: String,
+    ) -> Result<LocalListener, io::Error> {
+        use std::fs;
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+        use std::path::Path;
+
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            // Moving the socket into place would replace it.
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} exists and is not a socket", path),
+                ))
+            }
+            Err(_) => (),
+        }
+        let target = Path::new(path);
+        let file_name = target.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path))
+        })?;
+        let mut staging = file_name.to_owned();
+        staging.push(format!(".{}", std::process::id()));
+        let staging = target.with_file_name(staging);
+        fs::DirBuilder::new().mode(0o700).create(&staging)?;
+        let staged = staging.join("socket");
+        let bound = UnixListener::bind(&staged).and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+            fs::rename(&staged, target)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&staging);
+        Ok(LocalListener {
+            name,
+            listener: bound?,
+            hostname,
+        })
+    }
+
+    /// Runs the accept loop. Local connections have nothing to hand shake, they only count
+    /// towards the class limit and are sent to the server straight away.
+    pub fn spawn(
+        self,
+        throttle: Arc<Throttle>,
+        settings: Arc<ListenerSettings>,
+        tx: mpsc::Sender<Accepted>,
+    ) -> JoinHandle<()> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        tokio::spawn(async move {
+            loop {
+                let socket = match self.listener.accept().await {
+                    Ok((socket, _)) => socket,
+                    Err(e) => {
+                        error!("ACCEPTOR: {} failed to accept a connection: {}", self.name, e);
+                        time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let guard = match throttle.admit_local(&settings.class) {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        debug!("ACCEPTOR: {} {}", self.name, reject(socket, addr, e));
+                        continue;
+                    }
+                };
+                debug!("ACCEPTOR: {} accepted a local connection", self.name);
+                let accepted = Accepted {
+                    socket: Socket::Unix(socket),
+                    addr,
+                    local: None,
+                    guard,
+                    class: settings.class.clone(),
+                    starttls: None,
+                    hostname: Some(self.hostname.clone()),
+                };
+                if tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    pub async fn local_socket_is_bound_with_its_mode() {
+        let dir = std::env::temp_dir().join(format!("pawpaw-local-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("irc.sock");
+        let path = path.to_str().unwrap();
+        fs::write(path, "").unwrap();
+        assert!(LocalListener::bind("local".into(), path, 0o600, "localhost".into()).is_err());
+
+        fs::remove_file(path).unwrap();
+        let listener = LocalListener::bind("local".into(), path, 0o600, "localhost".into()).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        // Only the socket is left behind, which the next bind replaces.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        LocalListener::bind("local".into(), path, 0o660, "localhost".into()).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
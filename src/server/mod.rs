@@ -1,31 +1,67 @@
-use crate::config::{Config, ListenConfig};
+use crate::config::{
+    ClassConfig, Config, ListenConfig, OperConfig, RegistrationConfig, VhostConfig, WebIrcConfig,
+};
+use crate::details::modes::matches_mask;
 use crate::proto::Prefix;
 use crate::server::state::{ServerState, ServerStateCommand};
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
+use std::collections::HashMap;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
-use tokio::fs::read;
+use log::{error, info, warn};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
-use trust_dns_resolver::TokioAsyncResolver;
+use tokio::time;
 
 use crate::client::{Client, ClientError};
 use crate::proto::Message;
 use crate::proto::Reply;
-#[cfg(feature = "native-tls")]
-use tokio_native_tls::{native_tls::Identity, TlsAcceptor};
 use uuid::Uuid;
 
-use crate::server::listener::Listener;
+#[cfg(unix)]
+use crate::server::listener::LocalListener;
+use crate::server::accounts::Accounts;
+use crate::server::caps::{CapRegistry, Capability};
+use crate::server::channels::RegisteredChannels;
+use crate::server::cloak::Cloak;
+use crate::server::dnsbl::Dnsbl;
+use crate::server::dns::HostResolver;
+use crate::server::ident::Ident;
+use crate::server::listener::{Accepted, Listener, ListenerSettings};
+use crate::server::store::{FileStore, MemoryStore, Store, StoreError};
+use crate::server::throttle::Throttle;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::server::tls::{AcceptorHandle, TlsReloader};
+use crate::server::websocket::WebSocketSettings;
 
+pub mod accounts;
+pub mod caps;
+pub mod channels;
+pub mod cloak;
+pub mod dns;
+pub mod dnsbl;
+pub mod ident;
+pub mod listener;
+pub mod sasl;
+pub mod scram;
 pub mod socket;
+pub mod store;
+pub mod throttle;
 pub mod transport;
 
 mod client;
-mod listener;
+mod proxy;
 mod state;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+mod tls;
+mod websocket;
+
+/// Connections that finished their handshake but haven't been picked up by `accept` yet.
+const ACCEPT_QUEUE: usize = 64;
 
 #[derive(Error, Debug)]
 pub enum ServerError {
@@ -33,6 +69,14 @@ pub enum ServerError {
     TLSMissingCert(String),
     #[error("listener {0} is missing tls key")]
     TLSMissingKey(String),
+    #[cfg(feature = "rustls")]
+    #[error("listener {0} has no usable tls certificate")]
+    TLSInvalidCert(String),
+    #[cfg(feature = "rustls")]
+    #[error("listener {0} has no usable tls key")]
+    TLSInvalidKey(String),
+    #[error("listener {0} asked for a tls backend that isn't compiled in")]
+    TLSBackendUnavailable(String),
     #[error("server IO error")]
     Io {
         #[from]
@@ -59,6 +103,22 @@ pub enum ServerError {
     },
     #[error("the uuid doesn't have a client")]
     InvalidUUID,
+    #[error("listener {0} uses undefined class {1}")]
+    UnknownClass(String, String),
+    #[error("listener {0} needs either an address or a path")]
+    NoListenAddress(String),
+    #[error("listener {0} expects PROXY headers but has no trusted_proxies")]
+    NoTrustedProxies(String),
+    #[error("failed to open the store: {source}")]
+    Store {
+        #[from]
+        source: StoreError,
+    },
+    #[error("vhost {0} is not a valid hostname")]
+    InvalidVhost(String),
+    #[cfg(not(unix))]
+    #[error("listener {0} has a path but unix sockets aren't supported on this platform")]
+    UnixUnsupported(String),
 }
 
 impl ServerError {
@@ -90,29 +150,102 @@ pub struct Server {
     prefix: Prefix,
     hostname: String,
     motd: Vec<String>,
-    resolver: TokioAsyncResolver,
-    listeners: Vec<Listener>,
+    dns: HostResolver,
+    dnsbl: Dnsbl,
+    ident: Ident,
+    cloak: Cloak,
+    caps: CapRegistry,
+    accounts: Accounts,
+    registration: RegistrationConfig,
+    listeners: Vec<JoinHandle<()>>,
+    accept_tx: mpsc::Sender<Accepted>,
+    incoming: Mutex<mpsc::Receiver<Accepted>>,
+    classes: HashMap<String, Arc<ClassConfig>>,
+    opers: HashMap<String, OperConfig>,
+    webirc: HashMap<String, WebIrcConfig>,
+    vhosts: Vec<VhostConfig>,
+    throttle: Arc<Throttle>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    tls: Vec<TlsReloader>,
+    tls_watch: Option<Duration>,
+    config_path: String,
     tx: UnboundedSender<ServerStateCommand>,
     phase: ServerPhase,
 }
 
 impl Server {
     pub async fn new(config: Config) -> Result<Server, ServerError> {
-        let resolver =
-            TokioAsyncResolver::tokio_from_system_conf().expect("Failed to create DNS resolver.");
+        let resolver = dns::resolver(&config.dns);
         let (tx, rx) = unbounded_channel();
+        let (accept_tx, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        let store: Arc<dyn Store> = match &config.store {
+            Some(store) => Arc::new(FileStore::open(store)?),
+            None => Arc::new(MemoryStore::default()),
+        };
+        let accounts = Accounts::new(&config.accounts, store.clone());
+        let registered = RegisteredChannels::new(store);
         let mut server = Self {
-            resolver,
+            dns: HostResolver::new(resolver, &config.dns),
+            dnsbl: Dnsbl::new(&config.dnsbl, config.dns.timeout()),
+            ident: Ident::new(&config.ident),
+            cloak: Cloak::new(&config.cloak),
+            caps: CapRegistry::new(),
+            accounts,
+            registration: config.registration,
             listeners: Vec::new(),
+            accept_tx,
+            incoming: Mutex::new(incoming),
+            classes: config
+                .classes
+                .into_iter()
+                .map(|(name, class)| {
+                    let class = ClassConfig { name: name.clone(), ..class };
+                    (name, Arc::new(class))
+                })
+                .collect(),
+            opers: config.opers,
+            webirc: config.webirc,
+            vhosts: config.vhosts,
+            throttle: Arc::new(Throttle::new(config.throttle_exempt)),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            tls: Vec::new(),
+            tls_watch: config.tls_watch.map(Duration::from_secs),
+            config_path: config.config,
             motd: config.motd.split("\n").map(|x| x.to_string()).collect(),
             hostname: config.hostname.clone(),
             prefix: Prefix::ServerOrNick(config.hostname.clone()),
-            state: Arc::new(ServerState::new(Prefix::ServerOrNick(config.hostname))),
+            state: Arc::new(ServerState::new(Prefix::ServerOrNick(config.hostname), registered)),
             tx,
             phase: ServerPhase::Starting,
         };
+        server.declare_cap(Capability::new(caps::CHGHOST)).await;
+        server
+            .declare_cap(Capability::with_value(caps::SASL, sasl::mechanism_names()))
+            .await;
+        if server.registration.enabled {
+            // Accounts may be registered before connecting, under any valid name.
+            let value = "before-connect,custom-account-name";
+            let cap = Capability::with_value(caps::ACCOUNT_REGISTRATION, value);
+            server.declare_cap(cap).await;
+        }
+        for vhost in &server.vhosts {
+            if dns::validate(&vhost.host).is_err() {
+                return Err(ServerError::InvalidVhost(vhost.host.clone()));
+            }
+        }
+        let wants_certfp = server.opers.values().any(|oper| oper.certfp.is_some())
+            || config.accounts.values().any(|account| !account.certfp.is_empty());
         for (name, listener) in config.listeners {
-            if listener.tls {
+            if wants_certfp && listener.uses_native_tls() {
+                warn!(
+                    "listener {} uses native-tls, which doesn't ask clients for a certificate, \
+                     CertFP and SASL EXTERNAL need the rustls backend",
+                    name
+                );
+            }
+            if listener.path.is_some() {
+                server.add_local_listener(name, listener).await?;
+            } else if listener.tls {
                 #[cfg(any(feature = "native-tls", feature = "rustls"))]
                 server.add_tls_listener(name, listener).await?;
                 #[cfg(all(not(feature = "native-tls"), not(feature = "rustls")))]
@@ -127,8 +260,39 @@ impl Server {
     pub fn prefix(&self) -> Prefix {
         self.prefix.clone()
     }
-    pub fn resolver(&self) -> TokioAsyncResolver {
-        self.resolver.clone()
+    pub fn dns(&self) -> &HostResolver {
+        &self.dns
+    }
+
+    pub fn dnsbl(&self) -> &Dnsbl {
+        &self.dnsbl
+    }
+
+    pub fn ident(&self) -> &Ident {
+        &self.ident
+    }
+
+    pub fn cloak(&self) -> &Cloak {
+        &self.cloak
+    }
+
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    pub fn registration(&self) -> &RegistrationConfig {
+        &self.registration
+    }
+
+    pub fn caps(&self) -> &CapRegistry {
+        &self.caps
+    }
+
+    /// Offers `cap` to clients, or changes its value, and tells those with cap-notify.
+    pub async fn declare_cap(&self, cap: Capability) {
+        if self.caps.declare(cap.clone()) {
+            self.state.cap_new(&cap).await;
+        }
     }
 
     pub fn get_motd(&self) -> &Vec<String> {
@@ -143,38 +307,192 @@ impl Server {
         self.state.clone()
     }
 
+    pub fn oper(&self, name: &str) -> Option<&OperConfig> {
+        self.opers.get(name)
+    }
+
+    /// Finds the WEBIRC block for a gateway connecting from `ip` with `password`.
+    pub fn webirc_gateway(&self, ip: &IpAddr, password: &str) -> Option<&str> {
+        self.webirc
+            .iter()
+            .find(|(_, gateway)| {
+                let matches: bool = gateway.password.as_bytes().ct_eq(password.as_bytes()).into();
+                matches && gateway.hosts.iter().any(|net| net.contains(ip))
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The first configured vhost for a client with any of these `nick!user@host` masks.
+    pub fn configured_vhost(&self, masks: &[String]) -> Option<&str> {
+        self.vhosts
+            .iter()
+            .find(|vhost| {
+                vhost
+                    .masks
+                    .iter()
+                    .any(|pattern| masks.iter().any(|mask| matches_mask(pattern, mask)))
+            })
+            .map(|vhost| vhost.host.as_str())
+    }
+
+    pub fn config_path(&self) -> &str {
+        &self.config_path
+    }
+
+    /// Reloads the certificate of every TLS listener. A listener that fails to reload
+    /// keeps serving its current certificate, operators are told either way.
+    pub async fn rehash(&self) {
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        for reloader in &self.tls {
+            match reloader.reload().await {
+                Ok(()) => {
+                    info!("Reloaded TLS certificate for listener {}", reloader.name());
+                    self.state
+                        .notice_opers(&format!(
+                            "Reloaded TLS certificate for listener {}",
+                            reloader.name()
+                        ))
+                        .await;
+                }
+                Err(e) => {
+                    let reason = match std::error::Error::source(&e) {
+                        Some(source) => format!("{}: {}", e, source),
+                        None => e.to_string(),
+                    };
+                    error!(
+                        "Failed to reload TLS certificate for listener {}: {}",
+                        reloader.name(),
+                        reason
+                    );
+                    self.state
+                        .notice_opers(&format!(
+                            "Failed to reload TLS certificate for listener {}: {}",
+                            reloader.name(),
+                            reason
+                        ))
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Modification times of every certificate and key file, in listener order.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    async fn tls_modified(&self) -> Vec<Option<SystemTime>> {
+        let mut modified = Vec::new();
+        for file in self.tls.iter().flat_map(|reloader| reloader.files()) {
+            let time = tokio::fs::metadata(file)
+                .await
+                .and_then(|meta| meta.modified())
+                .ok();
+            modified.push(time);
+        }
+        modified
+    }
+
+    /// Polls the TLS certificate and key files if `tls_watch` is configured and rehashes
+    /// once any of them changes.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn spawn_tls_watcher(self: &Arc<Self>) {
+        let interval = match self.tls_watch {
+            Some(interval) if !self.tls.is_empty() => interval,
+            _ => return,
+        };
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut last = server.tls_modified().await;
+            let mut ticker = time::interval(interval);
+            ticker.reset();
+            loop {
+                ticker.tick().await;
+                let modified = server.tls_modified().await;
+                if modified != last {
+                    last = modified;
+                    server.rehash().await;
+                }
+            }
+        });
+    }
+
+    /// Looks up the class a listener hands its connections to. The `default` class
+    /// falls back to built-in settings when it is not defined in the config.
+    fn class(&self, listener: &str, name: &str) -> Result<Arc<ClassConfig>, ServerError> {
+        match self.classes.get(name) {
+            Some(class) => Ok(class.clone()),
+            None if name == "default" => Ok(Arc::new(ClassConfig::default())),
+            None => Err(ServerError::UnknownClass(
+                listener.to_owned(),
+                name.to_owned(),
+            )),
+        }
+    }
+
+    fn address(name: &str, listener: &ListenConfig) -> Result<SocketAddr, ServerError> {
+        listener
+            .address
+            .ok_or_else(|| ServerError::NoListenAddress(name.to_owned()))
+    }
+
+    #[cfg(unix)]
+    pub async fn add_local_listener(
+        &mut self,
+        name: String,
+        listener: ListenConfig,
+    ) -> Result<(), ServerError> {
+        let settings = self.listener_settings(&name, &listener)?;
+        let path = listener
+            .path
+            .as_deref()
+            .ok_or_else(|| ServerError::NoListenAddress(name.clone()))?;
+        let listen = LocalListener::bind(name, path, listener.path_mode, listener.local_hostname)?;
+        let handle = listen.spawn(self.throttle.clone(), settings, self.accept_tx.clone());
+        self.listeners.push(handle);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn add_local_listener(
+        &mut self,
+        name: String,
+        _listener: ListenConfig,
+    ) -> Result<(), ServerError> {
+        Err(ServerError::UnixUnsupported(name))
+    }
+
+    fn listener_settings(
+        &self,
+        name: &str,
+        listener: &ListenConfig,
+    ) -> Result<Arc<ListenerSettings>, ServerError> {
+        if listener.proxy_protocol && listener.trusted_proxies.is_empty() {
+            return Err(ServerError::NoTrustedProxies(name.to_owned()));
+        }
+        let websocket = listener.websocket.then(|| WebSocketSettings {
+            origins: listener.websocket_origins.clone(),
+        });
+        Ok(Arc::new(ListenerSettings {
+            class: self.class(name, &listener.class)?,
+            handshake_timeout: listener.handshake_timeout(),
+            max_handshakes: listener.max_handshakes.max(1),
+            websocket,
+            proxy_protocol: listener.proxy_protocol,
+            trusted_proxies: listener.trusted_proxies.clone(),
+        }))
+    }
+
     #[cfg(any(feature = "native-tls", feature = "rustls"))]
     pub async fn add_tls_listener(
         &mut self,
         name: String,
         listener: ListenConfig,
     ) -> Result<(), ServerError> {
-        let cert = if let Some(cert) = listener.tls_cert {
-            read(cert)
-                .await
-                .map_err(|source| ServerError::Io { source })?
-        } else {
-            return Err(ServerError::TLSMissingCert(name));
-        };
-        let key = if let Some(key) = listener.tls_key {
-            read(key)
-                .await
-                .map_err(|source| ServerError::Io { source })?
-        } else {
-            return Err(ServerError::TLSMissingKey(name));
-        };
-        #[cfg(feature = "native-tls")]
-        let ident = Identity::from_pkcs8(&cert, &key)
-            .map_err(|source| ServerError::NativeTLSError { source })?;
-        let acceptor =
-            TlsAcceptor::from(tokio_native_tls::native_tls::TlsAcceptor::builder(ident).build()?);
-        let listener = Listener::new_tls(name, listener.address, acceptor)
-            .await
-            .map_err(|source| ServerError::Io { source })?;
-        self.listeners.push(listener);
-        return Ok(());
-        #[cfg(feature = "rustls")]
-        todo!();
+        let settings = self.listener_settings(&name, &listener)?;
+        let address = Self::address(&name, &listener)?;
+        let acceptor = AcceptorHandle::build(&name, &listener).await?;
+        let listen = Listener::new_tls(name.clone(), address, acceptor.clone()).await?;
+        self.tls.push(TlsReloader::new(name, listener, acceptor));
+        self.spawn_listener(listen, settings);
+        Ok(())
     }
 
     pub async fn add_listener(
@@ -182,22 +500,38 @@ impl Server {
         name: String,
         listener: ListenConfig,
     ) -> Result<(), ServerError> {
-        let listener = Listener::new(name, listener.address).await?;
-        self.listeners.push(listener);
+        let settings = self.listener_settings(&name, &listener)?;
+        let address = Self::address(&name, &listener)?;
+        // A certificate on a plaintext listener makes it offer STARTTLS.
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        if !listener.websocket && (listener.tls_cert.is_some() || listener.tls_key.is_some()) {
+            let acceptor = AcceptorHandle::build(&name, &listener).await?;
+            let listen =
+                Listener::new_starttls(name.clone(), address, acceptor.clone()).await?;
+            self.tls.push(TlsReloader::new(name, listener, acceptor));
+            self.declare_cap(Capability::new(caps::TLS)).await;
+            self.spawn_listener(listen, settings);
+            return Ok(());
+        }
+        let listener = Listener::new(name, address).await?;
+        self.spawn_listener(listener, settings);
         Ok(())
     }
 
+    fn spawn_listener(&mut self, listener: Listener, settings: Arc<ListenerSettings>) {
+        let handle = listener.spawn(self.throttle.clone(), settings, self.accept_tx.clone());
+        self.listeners.push(handle);
+    }
+
     pub async fn accept(self: &Arc<Self>) -> Result<Client, ClientError> {
-        let mut futs: FuturesUnordered<_> = self.listeners.iter().map(|l| l.accept()).collect();
-        let conn = loop {
-            if let Some(c) = futs.next().await {
-                match c {
-                    Ok(val) => break val,
-                    Err(e) => eprintln!("{}", e),
-                }
-            }
-        };
-        Client::new(conn, self.clone())
+        let accepted = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(ClientError::StreamClosed)?;
+        Client::new(accepted, self.clone())
     }
 
     pub async fn server_loop(&mut self) -> JoinHandle<Result<(), ServerError>> {
@@ -0,0 +1,159 @@
+use crate::server::listener::ListenerError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest possible v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ProxyError {
+    #[error("connection didn't start with a PROXY header")]
+    Missing,
+    #[error("PROXY header is too long")]
+    TooLong,
+    #[error("malformed PROXY header")]
+    Malformed,
+    #[error("unsupported PROXY protocol version {0}")]
+    Version(u8),
+}
+
+/// Reads a v1 or v2 PROXY header off the start of `stream` and returns the source address
+/// it advertises, or `None` when the proxy didn't pass one on, like for its own health
+/// checks. Nothing past the header is consumed, so the TLS handshake can follow.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ListenerError> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(ProxyError::TooLong.into());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| ProxyError::Malformed)?;
+        Ok(parse_v1(line)?)
+    } else if start == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 16];
+        header[..5].copy_from_slice(&start);
+        stream.read_exact(&mut header[5..]).await?;
+        if &header[..12] != V2_SIGNATURE {
+            return Err(ProxyError::Malformed.into());
+        }
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        Ok(parse_v2(header[12], header[13], &payload)?)
+    } else {
+        Err(ProxyError::Missing.into())
+    }
+}
+
+/// Parses `PROXY TCP4 <src> <dst> <sport> <dport>\r\n` and its TCP6 and UNKNOWN forms.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProxyError> {
+    let line = line.strip_suffix("\r\n").ok_or(ProxyError::Malformed)?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(ProxyError::Malformed);
+    }
+    let v4 = match fields.next() {
+        Some("TCP4") => true,
+        Some("TCP6") => false,
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(ProxyError::Malformed),
+    };
+    let mut field = || fields.next().ok_or(ProxyError::Malformed);
+    let src: IpAddr = field()?.parse().map_err(|_| ProxyError::Malformed)?;
+    let dst: IpAddr = field()?.parse().map_err(|_| ProxyError::Malformed)?;
+    let sport: u16 = field()?.parse().map_err(|_| ProxyError::Malformed)?;
+    let _dport: u16 = field()?.parse().map_err(|_| ProxyError::Malformed)?;
+    if field().is_ok() || src.is_ipv4() != v4 || dst.is_ipv4() != v4 {
+        return Err(ProxyError::Malformed);
+    }
+    Ok(Some(SocketAddr::new(src, sport)))
+}
+
+/// Parses the binary header that follows the v2 signature.
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    if ver_cmd >> 4 != 2 {
+        return Err(ProxyError::Version(ver_cmd >> 4));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL, the proxy talking to us on its own behalf.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(ProxyError::Malformed),
+    }
+    match family >> 4 {
+        1 => {
+            let addr: [u8; 12] = payload
+                .get(..12)
+                .and_then(|addr| addr.try_into().ok())
+                .ok_or(ProxyError::Malformed)?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 => {
+            let addr: [u8; 36] = payload
+                .get(..36)
+                .and_then(|addr| addr.try_into().ok())
+                .ok_or(ProxyError::Malformed)?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry nothing we could use as a client address.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn v1_addresses() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 203.0.113.9 192.0.2.1 51234 6697\r\n"),
+            Ok(Some("203.0.113.9:51234".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::9 2001:db8::1 51234 6697\r\n"),
+            Ok(Some("[2001:db8::9]:51234".parse().unwrap()))
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n"), Ok(None));
+        assert_eq!(
+            parse_v1("PROXY TCP4 2001:db8::9 192.0.2.1 51234 6697\r\n"),
+            Err(ProxyError::Malformed)
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP4 203.0.113.9 192.0.2.1 51234\r\n"),
+            Err(ProxyError::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn v2_header_leaves_the_rest_unread() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        data.extend_from_slice(&[203, 0, 113, 9, 192, 0, 2, 1, 0xc8, 0x22, 0x1a, 0x29]);
+        data.extend_from_slice(b"NICK a\r\n");
+        let mut stream = &data[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(stream, b"NICK a\r\n");
+    }
+
+    #[test]
+    pub fn v2_local_and_unknown_versions() {
+        assert_eq!(parse_v2(0x20, 0x00, &[]), Ok(None));
+        assert_eq!(parse_v2(0x11, 0x11, &[0; 12]), Err(ProxyError::Version(1)));
+        assert_eq!(parse_v2(0x21, 0x11, &[0; 4]), Err(ProxyError::Malformed));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::server::accounts::Accounts;
+use crate::server::scram::ScramSha256;
+use crate::server::socket::CertFp;
+use std::fmt::Debug;
+
+/// What a mechanism needs to know about the server and the connection.
+pub struct SaslContext<'a> {
+    pub accounts: &'a Accounts,
+    pub certfp: Option<&'a CertFp>,
+}
+
+/// The outcome of a client's response.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// Send this challenge and wait for the next response.
+    Challenge(Vec<u8>),
+    /// The client logged in to this account.
+    Success(String),
+    Failure,
+}
+
+/// One exchange of a SASL mechanism. Every response from the client, already decoded, is
+/// passed to `step` until it returns something other than a challenge.
+pub trait Mechanism: Debug + Send + Sync {
+    fn step(&mut self, context: &SaslContext, response: &[u8]) -> Step;
+}
+
+type Start = fn() -> Box<dyn Mechanism>;
+
+/// The mechanisms on offer, in order of preference. Supporting another one takes an
+/// implementation of [`Mechanism`] and an entry here.
+const MECHANISMS: &[(&str, Start)] = &[
+    ("PLAIN", || Box::<Plain>::default()),
+    ("EXTERNAL", || Box::<External>::default()),
+    ("SCRAM-SHA-256", || Box::<ScramSha256>::default()),
+];
+
+/// Starts an exchange with the mechanism called `name`.
+pub fn start(name: &str) -> Option<Box<dyn Mechanism>> {
+    MECHANISMS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, start)| start())
+}
+
+/// The mechanisms as listed in the sasl capability and RPL_SASLMECHS.
+pub fn mechanism_names() -> String {
+    MECHANISMS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// RFC 4616, `authzid NUL authcid NUL password` in a single response.
+#[derive(Debug, Default)]
+struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, context: &SaslContext, response: &[u8]) -> Step {
+        let response = match std::str::from_utf8(response) {
+            Ok(response) => response,
+            Err(_) => return Step::Failure,
+        };
+        let mut parts = response.split('\0');
+        let (authzid, authcid, password) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(authzid), Some(authcid), Some(password)) if parts.next().is_none() => {
+                (authzid, authcid, password)
+            }
+            _ => return Step::Failure,
+        };
+        // Logging in to one account with another's credentials isn't supported.
+        if !authzid.is_empty() && !authzid.eq_ignore_ascii_case(authcid) {
+            return Step::Failure;
+        }
+        match context.accounts.authenticate(authcid, password) {
+            Some(account) => Step::Success(account),
+            None => Step::Failure,
+        }
+    }
+}
+
+/// RFC 4422 appendix A, the account is the one the TLS client certificate belongs to. The
+/// client may name it, which then has to match.
+#[derive(Debug, Default)]
+struct External;
+
+impl Mechanism for External {
+    fn step(&mut self, context: &SaslContext, response: &[u8]) -> Step {
+        let account = match context.certfp {
+            Some(certfp) => context.accounts.find_by_certfp(certfp),
+            None => None,
+        };
+        let authzid = match std::str::from_utf8(response) {
+            Ok(authzid) => authzid,
+            Err(_) => return Step::Failure,
+        };
+        match account {
+            Some(account) if authzid.is_empty() || authzid.eq_ignore_ascii_case(&account) => {
+                Step::Success(account)
+            }
+            _ => Step::Failure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AccountConfig;
+    use crate::server::store::MemoryStore;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn accounts(fp: &CertFp) -> Accounts {
+        let config = HashMap::from([(
+            "alice".to_string(),
+            AccountConfig {
+                password: Some("secret".to_string()),
+                certfp: vec![fp.sha512.clone()],
+            },
+        )]);
+        Accounts::new(&config, Arc::new(MemoryStore::default()))
+    }
+
+    #[test]
+    pub fn plain() {
+        let fp = CertFp::new(b"certificate");
+        let accounts = accounts(&fp);
+        let context = SaslContext {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let mut plain = start("plain").unwrap();
+        assert_eq!(plain.step(&context, b"\0alice\0secret"), Step::Success("alice".into()));
+        assert_eq!(plain.step(&context, b"alice\0alice\0secret"), Step::Success("alice".into()));
+        assert_eq!(plain.step(&context, b"bob\0alice\0secret"), Step::Failure);
+        assert_eq!(plain.step(&context, b"\0alice\0wrong"), Step::Failure);
+        assert_eq!(plain.step(&context, b"alice\0secret"), Step::Failure);
+    }
+
+    #[test]
+    pub fn external() {
+        let fp = CertFp::new(b"certificate");
+        let accounts = accounts(&fp);
+        let mut context = SaslContext {
+            accounts: &accounts,
+            certfp: Some(&fp),
+        };
+        let mut external = start("EXTERNAL").unwrap();
+        assert_eq!(external.step(&context, b""), Step::Success("alice".into()));
+        assert_eq!(external.step(&context, b"ALICE"), Step::Success("alice".into()));
+        assert_eq!(external.step(&context, b"bob"), Step::Failure);
+        context.certfp = None;
+        assert_eq!(external.step(&context, b""), Step::Failure);
+        assert!(start("UNKNOWN").is_none());
+        assert_eq!(mechanism_names(), "PLAIN,EXTERNAL,SCRAM-SHA-256");
+    }
+}
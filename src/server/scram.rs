@@ -0,0 +1,320 @@
+use crate::server::sasl::{Mechanism, SaslContext, Step};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Iterations for newly derived credentials, the minimum RFC 7677 recommends.
+pub const ITERATIONS: u32 = 4096;
+/// Bytes of salt in newly derived credentials.
+const SALT_LEN: usize = 16;
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `Hi()` from RFC 5802, which is PBKDF2 with HMAC-SHA-256 and a single block.
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac(password, &block);
+    let mut result = u.clone();
+    for _ in 1..iterations {
+        u = hmac(password, &u);
+        result.iter_mut().zip(&u).for_each(|(r, u)| *r ^= u);
+    }
+    result
+}
+
+/// What the server keeps of a password. It is enough to check a SCRAM proof or a plain
+/// password, but neither the password nor anything a client could log in with directly
+/// can be recovered from it. Passwords are used as given, without SASLprep.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ScramCredentials {
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    #[serde(with = "hex")]
+    pub stored_key: Vec<u8>,
+    #[serde(with = "hex")]
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Derives credentials for `password` with a fresh random salt.
+    pub fn new(password: &str) -> Self {
+        Self::derive(password, Uuid::new_v4().as_bytes(), ITERATIONS)
+    }
+
+    /// Stands in for an account `name` that doesn't exist or has no password. The salt is
+    /// keyed by `secret` so it is the same on every attempt, as a real account's would be,
+    /// and no password matches.
+    pub fn decoy(secret: &[u8], name: &str) -> Self {
+        let key = hmac(secret, name.to_lowercase().as_bytes());
+        Self {
+            salt: key[..SALT_LEN].to_vec(),
+            iterations: ITERATIONS,
+            stored_key: hmac(&key, b"Client Key"),
+            server_key: hmac(&key, b"Server Key"),
+        }
+    }
+
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let salted = hi(password.as_bytes(), salt, iterations);
+        let client_key = hmac(&salted, b"Client Key");
+        Self {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(client_key).to_vec(),
+            server_key: hmac(&salted, b"Server Key"),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let derived = Self::derive(password, &self.salt, self.iterations);
+        derived.stored_key.ct_eq(&self.stored_key).into()
+    }
+}
+
+/// Undoes the `=2C` and `=3D` escapes of a SCRAM username.
+fn unescape_name(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(i) = rest.find('=') {
+        unescaped.push_str(&rest[..i]);
+        match rest.get(i..i + 3) {
+            Some("=2C") => unescaped.push(','),
+            Some("=3D") => unescaped.push('='),
+            _ => return None,
+        }
+        rest = &rest[i + 3..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    ClientFirst,
+    ClientFinal {
+        /// None when the client named an unknown account, the exchange then goes on with
+        /// decoy credentials and only fails at the end.
+        account: Option<String>,
+        credentials: ScramCredentials,
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+    },
+    /// The server signature went out, the client acknowledges it with an empty response.
+    Acknowledge(String),
+}
+
+/// RFC 7677 SCRAM-SHA-256 without channel binding.
+#[derive(Debug, Default)]
+pub struct ScramSha256 {
+    state: State,
+}
+
+impl Mechanism for ScramSha256 {
+    fn step(&mut self, context: &SaslContext, response: &[u8]) -> Step {
+        let response = match std::str::from_utf8(response) {
+            Ok(response) => response,
+            Err(_) => return Step::Failure,
+        };
+        match std::mem::take(&mut self.state) {
+            State::ClientFirst => {
+                let nonce = Uuid::new_v4().simple().to_string();
+                let accounts = context.accounts;
+                self.client_first(response, &nonce, |name| accounts.scram_credentials(name))
+            }
+            State::ClientFinal {
+                account,
+                credentials,
+                gs2_header,
+                client_first_bare,
+                server_first,
+                nonce,
+            } => {
+                let (without_proof, proof) = match response.rsplit_once(",p=") {
+                    Some(parts) => parts,
+                    None => return Step::Failure,
+                };
+                let mut attributes = without_proof.split(',');
+                let binding = attributes.next().and_then(|c| c.strip_prefix("c="));
+                let binding = binding.and_then(|c| STANDARD.decode(c).ok());
+                let echoed = attributes.next().and_then(|r| r.strip_prefix("r="));
+                if binding.as_deref() != Some(gs2_header.as_bytes()) || echoed != Some(&nonce) {
+                    return Step::Failure;
+                }
+                let proof = match STANDARD.decode(proof) {
+                    Ok(proof) if proof.len() == credentials.stored_key.len() => proof,
+                    _ => return Step::Failure,
+                };
+                let auth_message =
+                    format!("{},{},{}", client_first_bare, server_first, without_proof);
+                let signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+                let client_key: Vec<u8> =
+                    proof.iter().zip(&signature).map(|(p, s)| p ^ s).collect();
+                let proven: bool =
+                    Sha256::digest(client_key).as_slice().ct_eq(&credentials.stored_key).into();
+                match account {
+                    Some(account) if proven => {
+                        let verifier = hmac(&credentials.server_key, auth_message.as_bytes());
+                        self.state = State::Acknowledge(account);
+                        Step::Challenge(format!("v={}", STANDARD.encode(verifier)).into_bytes())
+                    }
+                    _ => Step::Failure,
+                }
+            }
+            State::Acknowledge(account) if response.is_empty() => Step::Success(account),
+            State::Acknowledge(_) => Step::Failure,
+        }
+    }
+}
+
+impl ScramSha256 {
+    /// Answers the client-first message with the salt, iteration count and a nonce that
+    /// starts with the client's and ends with `server_nonce`.
+    fn client_first<F>(&mut self, message: &str, server_nonce: &str, lookup: F) -> Step
+    where
+        F: FnOnce(&str) -> (Option<String>, ScramCredentials),
+    {
+        // gs2-header is the channel binding flag and an optional authzid.
+        let mut parts = message.splitn(3, ',');
+        let (flag, raw_authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(flag), Some(authzid), Some(bare)) => (flag, authzid, bare),
+            _ => return Step::Failure,
+        };
+        // Without -PLUS variants on offer, a client that requires channel binding is
+        // talking to the wrong server.
+        if flag != "n" && flag != "y" {
+            return Step::Failure;
+        }
+        let mut attributes = bare.split(',');
+        let name = attributes.next().and_then(|n| n.strip_prefix("n=")).and_then(unescape_name);
+        let client_nonce = attributes.next().and_then(|r| r.strip_prefix("r="));
+        let (name, client_nonce) = match (name, client_nonce) {
+            (Some(name), Some(nonce)) if !name.is_empty() && !nonce.is_empty() => (name, nonce),
+            _ => return Step::Failure,
+        };
+        let authzid = match raw_authzid.strip_prefix("a=").map(unescape_name) {
+            Some(Some(authzid)) => Some(authzid),
+            Some(None) => return Step::Failure,
+            None if raw_authzid.is_empty() => None,
+            None => return Step::Failure,
+        };
+        if authzid.is_some_and(|authzid| !authzid.eq_ignore_ascii_case(&name)) {
+            return Step::Failure;
+        }
+        let (account, credentials) = lookup(&name);
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&credentials.salt),
+            credentials.iterations
+        );
+        self.state = State::ClientFinal {
+            account,
+            credentials,
+            gs2_header: format!("{},{},", flag, raw_authzid),
+            client_first_bare: bare.to_owned(),
+            server_first: server_first.clone(),
+            nonce,
+        };
+        Step::Challenge(server_first.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The exchange from RFC 7677 section 3.
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    fn credentials() -> (Option<String>, ScramCredentials) {
+        let salt = STANDARD.decode(SALT).unwrap();
+        (Some("user".to_string()), ScramCredentials::derive("pencil", &salt, 4096))
+    }
+
+    fn accounts() -> crate::server::accounts::Accounts {
+        let store = std::sync::Arc::new(crate::server::store::MemoryStore::default());
+        crate::server::accounts::Accounts::new(&Default::default(), store)
+    }
+
+    #[test]
+    pub fn rfc7677_exchange() {
+        let accounts = accounts();
+        let context = SaslContext {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let mut scram = ScramSha256::default();
+        let first = scram.client_first(CLIENT_FIRST, SERVER_NONCE, |_| credentials());
+        assert_eq!(first, Step::Challenge(SERVER_FIRST.as_bytes().to_vec()));
+        let last = scram.step(&context, CLIENT_FINAL.as_bytes());
+        assert_eq!(last, Step::Challenge(SERVER_FINAL.as_bytes().to_vec()));
+        assert_eq!(scram.step(&context, b""), Step::Success("user".to_string()));
+        assert_eq!(scram.step(&context, b""), Step::Failure);
+    }
+
+    #[test]
+    pub fn wrong_proofs_fail() {
+        let accounts = accounts();
+        let context = SaslContext {
+            accounts: &accounts,
+            certfp: None,
+        };
+        let bad_proof = CLIENT_FINAL.replace("p=dHzb", "p=dHzc");
+        let bad_nonce = CLIENT_FINAL.replace("k0,", "k1,");
+        for client_final in [bad_proof, bad_nonce] {
+            let mut scram = ScramSha256::default();
+            scram.client_first(CLIENT_FIRST, SERVER_NONCE, |_| credentials());
+            assert_eq!(scram.step(&context, client_final.as_bytes()), Step::Failure);
+        }
+        // Unknown accounts get a challenge like any other, but can't succeed.
+        let decoy = |name: &str| (None, ScramCredentials::decoy(b"secret", name));
+        let mut scram = ScramSha256::default();
+        let first = scram.client_first(CLIENT_FIRST, SERVER_NONCE, decoy);
+        assert!(matches!(first, Step::Challenge(_)));
+        assert_eq!(scram.step(&context, CLIENT_FINAL.as_bytes()), Step::Failure);
+        let mut again = ScramSha256::default();
+        assert_eq!(again.client_first(CLIENT_FIRST, SERVER_NONCE, decoy), first);
+    }
+
+    #[test]
+    pub fn channel_binding_and_authzid() {
+        let mut scram = ScramSha256::default();
+        let required = "p=tls-unique,,n=user,r=abc";
+        assert_eq!(scram.client_first(required, "x", |_| credentials()), Step::Failure);
+        let other = "n,a=admin,n=user,r=abc";
+        assert_eq!(scram.client_first(other, "x", |_| credentials()), Step::Failure);
+        let same = "y,a=user,n=user,r=abc";
+        assert!(matches!(scram.client_first(same, "x", |_| credentials()), Step::Challenge(_)));
+        assert_eq!(unescape_name("a=2Cb=3Dc").as_deref(), Some("a,b=c"));
+        assert_eq!(unescape_name("a=b"), None);
+    }
+
+    #[test]
+    pub fn credentials_check_passwords() {
+        let credentials = ScramCredentials::new("pencil");
+        assert!(credentials.verify("pencil"));
+        assert!(!credentials.verify("pen"));
+        assert_ne!(credentials, ScramCredentials::new("pencil"));
+    }
+}
@@ -1,11 +1,15 @@
+use crate::server::websocket::WsStream;
 use pin_project::pin_project;
+use sha2::{Digest, Sha256, Sha512};
 use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 
 #[cfg(feature = "native-tls")]
 use tokio_native_tls::TlsStream;
 
 #[cfg(feature = "rustls")]
-use tokio_rustls::TlsStream as RustlsStream;
+use tokio_rustls::server::TlsStream as RustlsStream;
 
 #[derive(Debug)]
 #[pin_project(project = SocketProj)]
@@ -15,6 +19,68 @@ pub enum Socket<S> {
     Tls(#[pin] TlsStream<S>),
     #[cfg(feature = "rustls")]
     RusTls(#[pin] RustlsStream<S>),
+    Ws(Box<WsStream>),
+    #[cfg(unix)]
+    Unix(#[pin] UnixStream),
+}
+
+/// Hex encoded fingerprints of the certificate a client presented during the TLS handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertFp {
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl CertFp {
+    pub fn new(der: &[u8]) -> Self {
+        Self {
+            sha256: hex::encode(Sha256::digest(der)),
+            sha512: hex::encode(Sha512::digest(der)),
+        }
+    }
+
+    /// Compares against a configured fingerprint of either length, ignoring case and
+    /// colon separators.
+    pub fn matches(&self, fingerprint: &str) -> bool {
+        let fingerprint = fingerprint.replace(':', "").to_ascii_lowercase();
+        fingerprint == self.sha256 || fingerprint == self.sha512
+    }
+}
+
+/// What is known about a connection's TLS session once the handshake is done.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub certfp: Option<CertFp>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socket<S> {
+    /// Returns `None` for plaintext connections.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Socket::Plain(_) => None,
+            #[cfg(feature = "native-tls")]
+            Socket::Tls(socket) => {
+                let certfp = match socket.get_ref().peer_certificate() {
+                    Ok(Some(cert)) => cert.to_der().ok().map(|der| CertFp::new(&der)),
+                    _ => None,
+                };
+                Some(TlsInfo { certfp })
+            }
+            #[cfg(feature = "rustls")]
+            Socket::RusTls(socket) => {
+                let certfp = socket
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(|cert| CertFp::new(&cert.0));
+                Some(TlsInfo { certfp })
+            }
+            Socket::Ws(socket) => socket.tls_info(),
+            #[cfg(unix)]
+            Socket::Unix(_) => None,
+        }
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Socket<S> {
@@ -29,6 +95,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Socket<S> {
             SocketProj::Tls(socket) => socket.poll_read(cx, buf),
             #[cfg(feature = "rustls")]
             SocketProj::RusTls(socket) => socket.poll_read(cx, buf),
+            SocketProj::Ws(socket) => std::pin::Pin::new(&mut **socket).poll_read(cx, buf),
+            #[cfg(unix)]
+            SocketProj::Unix(socket) => socket.poll_read(cx, buf),
         }
     }
 }
@@ -45,6 +114,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
             SocketProj::Tls(socket) => socket.poll_write(cx, buf),
             #[cfg(feature = "rustls")]
             SocketProj::RusTls(socket) => socket.poll_write(cx, buf),
+            SocketProj::Ws(socket) => std::pin::Pin::new(&mut **socket).poll_write(cx, buf),
+            #[cfg(unix)]
+            SocketProj::Unix(socket) => socket.poll_write(cx, buf),
         }
     }
 
@@ -58,6 +130,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
             SocketProj::Tls(socket) => socket.poll_flush(cx),
             #[cfg(feature = "rustls")]
             SocketProj::RusTls(socket) => socket.poll_flush(cx),
+            SocketProj::Ws(socket) => std::pin::Pin::new(&mut **socket).poll_flush(cx),
+            #[cfg(unix)]
+            SocketProj::Unix(socket) => socket.poll_flush(cx),
         }
     }
 
@@ -71,6 +146,37 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
             SocketProj::Tls(socket) => socket.poll_shutdown(cx),
             #[cfg(feature = "rustls")]
             SocketProj::RusTls(socket) => socket.poll_shutdown(cx),
+            SocketProj::Ws(socket) => std::pin::Pin::new(&mut **socket).poll_shutdown(cx),
+            #[cfg(unix)]
+            SocketProj::Unix(socket) => socket.poll_shutdown(cx),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn certfp_matches_either_digest() {
+        let fp = CertFp::new(b"certificate");
+        assert_eq!(fp.sha256.len(), 64);
+        assert_eq!(fp.sha512.len(), 128);
+        assert!(fp.matches(&fp.sha256));
+        assert!(fp.matches(&fp.sha512.to_ascii_uppercase()));
+        assert!(!fp.matches(&fp.sha256[..32]));
+    }
+
+    #[test]
+    pub fn certfp_ignores_colons() {
+        let fp = CertFp::new(b"certificate");
+        let colons = fp
+            .sha256
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(fp.matches(&colons));
+    }
+}
@@ -1,15 +1,24 @@
 use crate::client::handle::ClientHandle;
+use crate::config::OperPrivilege;
+use crate::details::channel::ChannelUser;
+use crate::details::modes::FLAG_MODES;
 use crate::details::{Channel, ChannelError};
-use crate::proto::{Message, Prefix, Reply};
+use crate::proto::{Command, Message, Prefix, Reply};
+use crate::server::caps::{self, Capability};
+use crate::server::channels::{AccessLevel, RegisteredChannel, RegisteredChannels, RegistrationError};
+use crate::server::cloak::Host;
 use crate::server::state::ServerStateCommand::{JoinChannel, NickCheck, Register, SetNick};
+use crate::server::socket::TlsInfo;
+use crate::server::transport::Sender as ClientSender;
 use crate::server::{transport, Server, ServerError};
+use dashmap::mapref::entry::Entry;
 use dashmap::{DashMap, DashSet};
-use log::debug;
-use std::collections::{HashMap, HashSet};
+use log::{debug, error};
+use std::collections::HashMap;
 use std::iter::zip;
 use std::sync::Arc;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::oneshot::{channel, Receiver, Sender};
+use tokio::sync::oneshot::{self, channel, Receiver, Sender};
 use uuid::{Uuid, uuid};
 use crate::server::client::ServerClient;
 
@@ -118,18 +127,24 @@ pub struct ServerState {
     clients: DashMap<Uuid, ServerClient>,
     nicks: DashSet<String>,
     channels: DashMap<String, Channel>,
+    registered: RegisteredChannels,
 }
 
 impl ServerState {
-    pub fn new(prefix: Prefix) -> Self {
+    pub fn new(prefix: Prefix, registered: RegisteredChannels) -> Self {
         ServerState {
             prefix,
             clients: DashMap::new(),
             nicks: DashSet::new(),
             channels: DashMap::new(),
+            registered,
         }
     }
 
+    pub fn registered_channels(&self) -> &RegisteredChannels {
+        &self.registered
+    }
+
     pub fn contains_nick(&self, nick: &String) -> bool {
         self.nicks.contains(nick)
     }
@@ -138,36 +153,55 @@ impl ServerState {
         self.nicks.insert(nick)
     }
 
+    /// Joins `uuid` to `chans`. A channel nobody is in is created, with the first joiner
+    /// as operator unless it is registered, in which case its topic, modes and bans are
+    /// restored and only accounts on its access list get status. Those are never banned.
     pub async fn join_channel(
         &self,
         uuid: Uuid,
         chans: Vec<String>,
         keys: Option<Vec<String>>,
     ) -> Result<Vec<Reply>, ServerError> {
+        let (nick, masks, account) = match self.clients.get(&uuid) {
+            Some(client) => (client.get_nickname(), client.masks(), client.account()),
+            None => return Err(ServerError::InvalidUUID),
+        };
         let mut vec = Vec::new();
-        for channel in chans {
-            if let Some(mut channel) = self.channels.get_mut(&channel) {
-                let nick = match self.clients.get(&uuid) {
-                    Some(val) => {
-                        val.get_nickname().await
+        for name in chans {
+            let registration = self.registered.get(&name);
+            let level = registration.as_ref().and_then(|r| r.access(account.as_deref()));
+            let mut channel = match self.channels.entry(name.clone()) {
+                Entry::Occupied(entry) => entry.into_ref(),
+                Entry::Vacant(entry) => match &registration {
+                    Some(r) => {
+                        let topic = r.topic.clone();
+                        entry.insert(Channel::restore(name, topic, &r.modes, &r.bans, &r.exceptions))
                     }
-                    None => return Err(ServerError::InvalidUUID),
-                };
-                channel.add_client(uuid, nick);
-                vec.push(channel.reply_topic());
-                vec.append(&mut channel.reply_names(self.prefix.clone()));
-            } else {
-                let nick = match self.clients.get(&uuid) {
-                    Some(val) => {
-                        val.get_nickname().await
+                    None => {
+                        let channel = entry.insert(Channel::new(name.clone(), uuid, nick.clone()));
+                        vec.push(Reply::NoTopic(name));
+                        vec.append(&mut channel.reply_names(self.prefix.clone()));
+                        continue;
                     }
-                    None => return Err(ServerError::InvalidUUID),
-                };
-                let val = Channel::new(channel.clone(), uuid, nick);
-                vec.push(Reply::NoTopic(channel.to_string()));
-                vec.append(&mut val.reply_names(self.prefix.clone()));
-                self.channels.insert(channel, val);
+                },
+            };
+            if channel.is_member(&uuid) {
+                continue;
+            }
+            if level.is_none() && channel.is_banned(&masks) {
+                vec.push(Reply::ErrBannedFromChan(channel.name().to_owned()));
+                continue;
+            }
+            match level {
+                Some(level) => {
+                    let oper = level >= AccessLevel::Op;
+                    let voice = level == AccessLevel::Voice;
+                    channel.add_member(ChannelUser::with_status(uuid, nick.clone(), oper, voice));
+                }
+                None => channel.add_client(uuid, nick.clone()),
             }
+            vec.push(channel.reply_topic());
+            vec.append(&mut channel.reply_names(self.prefix.clone()));
         }
         Ok(vec)
     }
@@ -176,14 +210,63 @@ impl ServerState {
         &self,
         nick: String,
         un: String,
-        peer: String,
+        host: Host,
         real: String,
         tx: transport::Sender,
-    ) -> Option<Uuid> {
-        let handle = ServerClient::new(nick, un, peer, real, tx);
+        tls: Option<TlsInfo>,
+    ) -> Option<(Uuid, oneshot::Receiver<String>)> {
+        let (kill, killed) = oneshot::channel();
+        let handle = ServerClient::new(nick, un, host, real, tx, tls, kill);
         let uuid = Uuid::new_v4();
         self.clients.insert(uuid, handle);
-        Some(uuid)
+        Some((uuid, killed))
+    }
+
+    /// Gives a registered client another nick, telling it and everyone sharing a channel
+    /// with it.
+    pub async fn change_nick(&self, uuid: &Uuid, nick: &str) -> Result<(), Reply> {
+        let (old, old_nick) = match self.clients.get(uuid) {
+            Some(client) => (client.prefix(), client.get_nickname()),
+            None => return Err(Reply::ErrNotRegistered),
+        };
+        if old_nick == nick {
+            return Ok(());
+        }
+        if !self.nicks.insert(nick.to_owned()) {
+            return Err(Reply::ErrNicknameInUse(nick.to_owned()));
+        }
+        self.nicks.remove(&old_nick);
+        match self.clients.get(uuid) {
+            Some(client) => client.set_nickname(nick.to_owned()),
+            None => return Err(Reply::ErrNotRegistered),
+        }
+        let mut peers = vec![*uuid];
+        for mut channel in self.channels.iter_mut() {
+            if channel.is_member(uuid) {
+                channel.rename_client(uuid, nick);
+                peers.extend(channel.get_clients().iter().map(|member| *member.uuid()));
+            }
+        }
+        peers.sort();
+        peers.dedup();
+        let mut message: Message = Command::Nick(nick, None).into();
+        message.set_prefix(old);
+        for peer in peers {
+            if let Some(client) = self.clients.get(&peer) {
+                let _ = client.sender().send(message.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnects a registered client. Its nick is free to take right away.
+    pub async fn kill(&self, uuid: &Uuid, reason: String) -> bool {
+        let client = match self.clients.remove(uuid) {
+            Some((_, client)) => client,
+            None => return false,
+        };
+        self.nicks.remove(&client.get_nickname());
+        client.kill(reason)
     }
 
     pub fn get_channel_users(&self, server: &Arc<Server>, channel: &str) -> Vec<Reply> {
@@ -193,12 +276,504 @@ impl ServerState {
         Vec::new()
     }
 
+    pub fn set_oper(&self, uuid: &Uuid, privileges: &[OperPrivilege]) -> bool {
+        match self.clients.get(uuid) {
+            Some(client) => {
+                client.set_oper(true);
+                client.grant(privileges);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn prefix(&self, uuid: &Uuid) -> Option<Prefix> {
+        self.clients.get(uuid).map(|client| client.prefix())
+    }
+
+    /// Sets or unsets +x, returning the host shown from now on if it changed.
+    pub fn set_cloaked(&self, uuid: &Uuid, cloaked: bool) -> Option<String> {
+        let client = self.clients.get(uuid)?;
+        if !client.set_cloaked(cloaked) {
+            return None;
+        }
+        Some(client.displayed_host())
+    }
+
+    /// Gives a client a vhost, or takes it away with `None`. When that changes the
+    /// displayed host the client and its channels are told, and the new host returned.
+    pub async fn set_vhost(&self, uuid: &Uuid, vhost: Option<String>) -> Option<String> {
+        let old = self.prefix(uuid).await?;
+        let host = {
+            let client = self.clients.get(uuid)?;
+            if !client.set_vhost(vhost) {
+                return None;
+            }
+            let host = client.displayed_host();
+            let _ = client.sender().send(Reply::HostHidden(host.clone()));
+            host
+        };
+        self.announce_host_change(uuid, old).await;
+        Some(host)
+    }
+
+    /// Tells everyone sharing a channel with `uuid` that its username or host is no
+    /// longer the one in `old`. Clients with the chghost capability get a CHGHOST, the
+    /// others see the user quit and rejoin with its channel status restored.
+    pub async fn announce_host_change(&self, uuid: &Uuid, old: Prefix) {
+        let (new, user, host) = match self.clients.get(uuid) {
+            Some(client) => (client.prefix(), client.user_name().to_owned(), client.displayed_host()),
+            None => return,
+        };
+        if new == old {
+            return;
+        }
+        let nick = match &new {
+            Prefix::Usermask(nick, _, _) => nick.clone(),
+            Prefix::ServerOrNick(nick) => nick.clone(),
+        };
+        // Every peer along with the channels it shares with the client and the modes that
+        // give the client its status in them.
+        let mut peers: HashMap<Uuid, Vec<(String, String)>> = HashMap::new();
+        for channel in self.channels.iter() {
+            let status = match channel.member(uuid) {
+                Some(member) => member.status_modes(),
+                None => continue,
+            };
+            for member in channel.get_clients() {
+                peers
+                    .entry(*member.uuid())
+                    .or_default()
+                    .push((channel.name().to_owned(), status.clone()));
+            }
+        }
+        peers.entry(*uuid).or_default();
+        let mut chghost: Message = Command::CHGHOST(user, host).into();
+        chghost.set_prefix(old.clone());
+        for (peer, channels) in peers {
+            let client = match self.clients.get(&peer) {
+                Some(client) => client,
+                None => continue,
+            };
+            if client.has_cap(caps::CHGHOST) {
+                let _ = client.sender().send(chghost.clone());
+            } else if peer != *uuid {
+                let mut quit: Message = Command::Quit(Some("Changing host")).into();
+                quit.set_prefix(old.clone());
+                let _ = client.sender().send(quit);
+                for (channel, status) in channels {
+                    let mut join: Message = Command::JOIN(vec![channel.clone()], None).into();
+                    join.set_prefix(new.clone());
+                    let _ = client.sender().send(join);
+                    if !status.is_empty() {
+                        let mut params = vec![format!("+{}", status)];
+                        params.extend(status.chars().map(|_| nick.clone()));
+                        let _ = client.sender().send(Command::MODE(channel, params));
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn find_client(&self, nick: &str) -> Option<Uuid> {
+        for entry in self.clients.iter() {
+            if entry.get_nickname().eq_ignore_ascii_case(nick) {
+                return Some(*entry.key());
+            }
+        }
+        None
+    }
+
+    pub fn set_caps(&self, uuid: &Uuid, caps: &[&'static str], version: u32) {
+        if let Some(client) = self.clients.get(uuid) {
+            client.set_caps(caps, version);
+        }
+    }
+
+    /// Tells every client with cap-notify that `cap` is now on offer.
+    pub async fn cap_new(&self, cap: &Capability) {
+        for client in self.clients.iter() {
+            if client.has_cap(caps::CAP_NOTIFY) {
+                let nick = client.get_nickname();
+                let list = cap.format(client.cap_version());
+                let _ = client.sender().send(Command::Cap(Some(nick), "NEW".to_owned(), Some(list)));
+            }
+        }
+    }
+
+    pub fn user_modes(&self, uuid: &Uuid) -> String {
+        let mut modes = "+".to_string();
+        if let Some(client) = self.clients.get(uuid) {
+            if client.is_oper() {
+                modes.push('o');
+            }
+            if client.is_cloaked() {
+                modes.push('x');
+            }
+        }
+        modes
+    }
+
+    /// Sends `message` to each of `members` still connected.
+    fn send_to_members(&self, members: &[Uuid], message: &Message) {
+        for member in members {
+            if let Some(client) = self.clients.get(member) {
+                let _ = client.sender().send(message.clone());
+            }
+        }
+    }
+
+    /// Keeps a change to channel `name` if it is registered.
+    async fn store_channel<F: FnOnce(&mut RegisteredChannel)>(&self, name: &str, change: F) {
+        match self.registered.update(name, change).await {
+            Ok(_) | Err(RegistrationError::NotRegistered(_)) => {}
+            Err(e) => error!("Failed to store {}: {}", name, e),
+        }
+    }
+
+    /// MODE on a channel. Operators may change the ban and exception lists and
+    /// `FLAG_MODES`, except for flags the mode lock of a registered channel keeps. Changes
+    /// are sent to every member, the replies only to `uuid`.
+    pub async fn channel_mode(
+        &self,
+        uuid: &Uuid,
+        name: &str,
+        params: Vec<String>,
+    ) -> Result<Vec<Reply>, Reply> {
+        let prefix = match self.clients.get(uuid) {
+            Some(client) => client.prefix(),
+            None => return Err(Reply::ErrNotRegistered),
+        };
+        let mut channel = self
+            .channels
+            .get_mut(name)
+            .ok_or_else(|| Reply::ErrNoSuchChannel(name.to_owned()))?;
+        let name = channel.name().to_owned();
+        let mut params = params.into_iter();
+        let modes = match params.next() {
+            Some(modes) => modes,
+            None => return Ok(vec![Reply::ChannelModeIs(name, channel.mode().flags())]),
+        };
+        let registration = self.registered.get(&name);
+        let mut replies = Vec::new();
+        let mut changes = Vec::new();
+        let mut adding = true;
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                'b' => match params.next() {
+                    None => {
+                        for ban in channel.mode().bans() {
+                            replies.push(Reply::BanList(name.clone(), ban.clone()));
+                        }
+                        replies.push(Reply::EndOfBanList(name.clone()));
+                    }
+                    Some(_) if !channel.is_operator(uuid) => {
+                        replies.push(Reply::ErrChanOPrivsNeeded(name.clone()));
+                    }
+                    Some(mask) => {
+                        let changed = if adding {
+                            channel.mode_mut().add_ban(mask.clone())
+                        } else {
+                            channel.mode_mut().remove_ban(&mask)
+                        };
+                        if changed {
+                            changes.push((adding, 'b', Some(mask)));
+                        }
+                    }
+                },
+                'e' => match params.next() {
+                    None => {
+                        for exception in channel.mode().exceptions() {
+                            replies.push(Reply::ExceptList(name.clone(), exception.clone()));
+                        }
+                        replies.push(Reply::EndOfExceptList(name.clone()));
+                    }
+                    Some(_) if !channel.is_operator(uuid) => {
+                        replies.push(Reply::ErrChanOPrivsNeeded(name.clone()));
+                    }
+                    Some(mask) => {
+                        let changed = if adding {
+                            channel.mode_mut().add_exception(mask.clone())
+                        } else {
+                            channel.mode_mut().remove_exception(&mask)
+                        };
+                        if changed {
+                            changes.push((adding, 'e', Some(mask)));
+                        }
+                    }
+                },
+                flag if FLAG_MODES.contains(flag) => {
+                    if !channel.is_operator(uuid) {
+                        replies.push(Reply::ErrChanOPrivsNeeded(name.clone()));
+                    } else if let Some(r) = registration.as_ref().filter(|r| r.is_locked(flag, adding)) {
+                        replies.push(Reply::ErrMlockRestricted(name.clone(), flag, r.mlock.clone()));
+                    } else if channel.mode_mut().set_flag(flag, adding) {
+                        changes.push((adding, flag, None));
+                    }
+                }
+                other => replies.push(Reply::ErrUnknownMode(other)),
+            }
+        }
+        if !changes.is_empty() {
+            let mut modes = String::new();
+            let mut direction = None;
+            for (adding, mode, _) in &changes {
+                if direction != Some(*adding) {
+                    modes.push(if *adding { '+' } else { '-' });
+                    direction = Some(*adding);
+                }
+                modes.push(*mode);
+            }
+            let mut args = vec![modes];
+            args.extend(changes.into_iter().filter_map(|(_, _, mask)| mask));
+            let mut message: Message = Command::MODE(name.clone(), args).into();
+            message.set_prefix(prefix);
+            let members: Vec<Uuid> = channel.get_clients().iter().map(|c| *c.uuid()).collect();
+            let mode = channel.mode();
+            let flags = mode.flags();
+            let (bans, exceptions) = (mode.bans().to_vec(), mode.exceptions().to_vec());
+            drop(channel);
+            self.send_to_members(&members, &message);
+            if registration.is_some() {
+                self.store_channel(&name, |r| {
+                    r.modes = flags;
+                    r.bans = bans;
+                    r.exceptions = exceptions;
+                })
+                .await;
+            }
+        }
+        Ok(replies)
+    }
+
+    /// TOPIC, which shows the topic of a channel or, given `topic`, sets it for every
+    /// member. An empty topic unsets it. Only operators may set it on channels with +t.
+    pub async fn topic(
+        &self,
+        uuid: &Uuid,
+        name: &str,
+        topic: Option<String>,
+    ) -> Result<Vec<Reply>, Reply> {
+        let prefix = match self.clients.get(uuid) {
+            Some(client) => client.prefix(),
+            None => return Err(Reply::ErrNotRegistered),
+        };
+        let mut channel = self
+            .channels
+            .get_mut(name)
+            .ok_or_else(|| Reply::ErrNoSuchChannel(name.to_owned()))?;
+        let name = channel.name().to_owned();
+        let topic = match topic {
+            Some(topic) => topic,
+            None => return Ok(vec![channel.reply_topic()]),
+        };
+        if !channel.is_member(uuid) {
+            return Err(Reply::ErrNotOnChannel(name));
+        }
+        if channel.mode().topic_oper_only() && !channel.is_operator(uuid) {
+            return Err(Reply::ErrChanOPrivsNeeded(name));
+        }
+        channel.set_topic(Some(topic.clone()).filter(|topic| !topic.is_empty()));
+        let mut message: Message = Command::TOPIC(name.clone(), Some(topic)).into();
+        message.set_prefix(prefix);
+        let members: Vec<Uuid> = channel.get_clients().iter().map(|c| *c.uuid()).collect();
+        let topic = channel.topic().map(str::to_owned);
+        drop(channel);
+        self.send_to_members(&members, &message);
+        self.store_channel(&name, |r| r.topic = topic).await;
+        Ok(Vec::new())
+    }
+
+    /// What registering channel `name` to `founder` keeps of it, if `uuid` is one of its
+    /// operators.
+    pub fn registration_for(&self, uuid: &Uuid, name: &str, founder: &str) -> Option<RegisteredChannel> {
+        let channel = self.channels.get(name).filter(|channel| channel.is_operator(uuid))?;
+        let mut registration = RegisteredChannel::new(channel.name(), founder);
+        registration.topic = channel.topic().map(str::to_owned);
+        registration.modes = channel.mode().flags();
+        registration.bans = channel.mode().bans().to_vec();
+        registration.exceptions = channel.mode().exceptions().to_vec();
+        Some(registration)
+    }
+
+    /// Brings the modes of a registered channel in line with its mode lock, telling the
+    /// members about any change, and keeps the result.
+    pub async fn apply_mode_lock(&self, registration: &RegisteredChannel) {
+        let mut channel = match self.channels.get_mut(&registration.name) {
+            Some(channel) => channel,
+            None => return,
+        };
+        let mut modes = String::new();
+        let mut direction = None;
+        for (flag, set) in registration.locked_flags() {
+            if channel.mode_mut().set_flag(flag, set) {
+                if direction != Some(set) {
+                    modes.push(if set { '+' } else { '-' });
+                    direction = Some(set);
+                }
+                modes.push(flag);
+            }
+        }
+        if modes.is_empty() {
+            return;
+        }
+        let mut message: Message = Command::MODE(channel.name().to_owned(), vec![modes]).into();
+        message.set_prefix(self.prefix.clone());
+        let members: Vec<Uuid> = channel.get_clients().iter().map(|c| *c.uuid()).collect();
+        let flags = channel.mode().flags();
+        drop(channel);
+        self.send_to_members(&members, &message);
+        self.store_channel(&registration.name, |r| r.modes = flags).await;
+    }
+
+    pub fn mark(&self, uuid: &Uuid, reason: String) {
+        if let Some(client) = self.clients.get(uuid) {
+            client.mark(reason);
+        }
+    }
+
+    pub fn set_account(&self, uuid: &Uuid, account: Option<String>) {
+        if let Some(client) = self.clients.get(uuid) {
+            client.set_account(account);
+        }
+    }
+
+    pub fn account(&self, uuid: &Uuid) -> Option<String> {
+        self.clients.get(uuid).and_then(|client| client.account())
+    }
+
+    /// Logs every client out of `account`, for when it no longer exists.
+    pub async fn log_out_everywhere(&self, account: &str) {
+        for client in self.clients.iter() {
+            if client.account().as_deref() != Some(account) {
+                continue;
+            }
+            client.set_account(None);
+            let mask = client.prefix().to_string();
+            let _ = client.sender().send(Reply::LoggedOut(mask.trim_start_matches(':').to_owned()));
+        }
+    }
+
+    pub fn is_oper(&self, uuid: &Uuid) -> bool {
+        self.clients.get(uuid).is_some_and(|c| c.is_oper())
+    }
+
+    pub fn has_privilege(&self, uuid: &Uuid, privilege: OperPrivilege) -> bool {
+        self.clients.get(uuid).is_some_and(|c| c.has_privilege(privilege))
+    }
+
+    /// WHOIS replies for `nick`. The certificate fingerprint is only shown to the client
+    /// itself and to operators, marks only to operators, and the real host of a cloaked
+    /// client or one with a vhost only to itself and operators who may see hidden hosts.
+    pub async fn whois(&self, requester: &Uuid, server: &str, nick: &str) -> Vec<Reply> {
+        let privileged = self.is_oper(requester);
+        let sees_hidden = self.has_privilege(requester, OperPrivilege::SeeHidden);
+        for entry in self.clients.iter() {
+            let client = entry.value();
+            let name = client.get_nickname();
+            if !name.eq_ignore_ascii_case(nick) {
+                continue;
+            }
+            let mut replies = vec![
+                Reply::WhoisUser(
+                    name.clone(),
+                    client.user_name().to_owned(),
+                    client.displayed_host(),
+                    client.realname().to_owned(),
+                ),
+                Reply::WhoisServer(name.clone(), server.to_owned()),
+            ];
+            if client.displayed_host() != client.hostname() && (sees_hidden || entry.key() == requester) {
+                let host = client.host();
+                replies.push(Reply::WhoisHost(
+                    name.clone(),
+                    host.hostname.clone(),
+                    host.ip.to_string(),
+                ));
+            }
+            if let Some(account) = client.account() {
+                replies.push(Reply::WhoisAccount(name.clone(), account));
+            }
+            if client.is_oper() {
+                replies.push(Reply::WhoisOperator(name.clone()));
+            }
+            if client.is_secure() {
+                replies.push(Reply::WhoisSecure(name.clone()));
+            }
+            if let Some(certfp) = client.certfp() {
+                if privileged || entry.key() == requester {
+                    replies.push(Reply::WhoisCertFp(name.clone(), certfp.sha256.clone()));
+                }
+            }
+            if privileged {
+                for mark in client.marks() {
+                    replies.push(Reply::WhoisSpecial(name.clone(), format!("is marked: {}", mark)));
+                }
+            }
+            replies.push(Reply::EndOfWhois(name));
+            return replies;
+        }
+        vec![
+            Reply::ErrNoSuchNick(nick.to_owned()),
+            Reply::EndOfWhois(nick.to_owned()),
+        ]
+    }
+
+    /// Sends a server notice to every operator.
+    pub async fn notice_opers(&self, message: &str) {
+        for client in self.clients.iter() {
+            if client.is_oper() {
+                let nick = client.get_nickname();
+                let _ = client
+                    .sender()
+                    .send(Command::Notice(nick, format!("*** Notice -- {}", message)));
+            }
+        }
+    }
+
+    /// Tells operators which client's send queue has grown past its soft limit.
+    pub async fn sendq_warning(&self, sender: &ClientSender) {
+        let mut name = "*".to_string();
+        for client in self.clients.iter() {
+            if Arc::ptr_eq(client.sender().queue(), sender.queue()) {
+                name = format!("{} ({})", client.get_nickname(), client.hostname());
+                break;
+            }
+        }
+        let queue = sender.queue();
+        self.notice_opers(&format!(
+            "SendQ for {} is above its soft limit ({}/{} bytes)",
+            name,
+            queue.queued(),
+            queue.max()
+        ))
+        .await;
+    }
+
+    /// Queued and maximum send queue bytes for every registered client.
+    pub async fn sendq_stats(&self) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        for client in self.clients.iter() {
+            let queue = client.sender().queue();
+            replies.push(Reply::StatsLinkInfo(
+                client.get_nickname(),
+                queue.queued(),
+                queue.max(),
+            ));
+        }
+        replies
+    }
+
     pub fn drop_client(&self, nick: String, uuid: Uuid) {
         if nick.is_empty() || uuid.is_nil() {
             return;
         }
         debug!("client with nick: {} uuid: {} is being dropped", nick, uuid);
-        self.nicks.remove(&nick);
-        self.clients.remove(&uuid);
+        // A killed client is already gone, and someone else may have its nick by now.
+        if self.clients.remove(&uuid).is_some() {
+            self.nicks.remove(&nick);
+        }
     }
 }
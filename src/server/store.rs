@@ -0,0 +1,364 @@
+use crate::config::StoreConfig;
+use crate::server::accounts::Account;
+use crate::server::channels::RegisteredChannel;
+use dashmap::iter::Iter;
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+const SNAPSHOT: &str = "snapshot.yml";
+const LOG: &str = "log.jsonl";
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("store IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("store snapshot is unreadable: {source}")]
+    Snapshot {
+        #[from]
+        source: serde_yaml::Error,
+    },
+    #[error("store log is unreadable at line {line}: {source}")]
+    Log { line: usize, source: serde_json::Error },
+}
+
+/// Everything the server keeps across restarts, by lowercased name.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Account>,
+    #[serde(default)]
+    pub channels: BTreeMap<String, RegisteredChannel>,
+    /// Keys the SCRAM salts of accounts that don't exist, empty until one is made.
+    #[serde(default, with = "hex")]
+    pub secret: Vec<u8>,
+}
+
+/// One change to what the server keeps, replacing the record under `key` or removing it
+/// given `None`. Replaying a change twice leaves the same state behind.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Change {
+    Account { key: String, account: Option<Account> },
+    Channel { key: String, channel: Option<RegisteredChannel> },
+    Secret {
+        #[serde(with = "hex")]
+        secret: Vec<u8>,
+    },
+}
+
+impl Snapshot {
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Account { key, account: Some(account) } => {
+                self.accounts.insert(key, account);
+            }
+            Change::Account { key, account: None } => {
+                self.accounts.remove(&key);
+            }
+            Change::Channel { key, channel: Some(channel) } => {
+                self.channels.insert(key, channel);
+            }
+            Change::Channel { key, channel: None } => {
+                self.channels.remove(&key);
+            }
+            Change::Secret { secret } => {
+                self.secret = secret;
+            }
+        }
+    }
+}
+
+/// Where the server keeps what has to outlive it.
+pub trait Store: Send + Sync + fmt::Debug {
+    /// Everything kept so far.
+    fn load(&self) -> Snapshot;
+
+    /// Keeps `change`, which is safe from a crash once this returns.
+    fn record(&self, change: Change) -> Result<(), StoreError>;
+}
+
+/// Keeps everything in memory, for servers without a store configured and for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    snapshot: Mutex<Snapshot>,
+}
+
+impl Store for MemoryStore {
+    fn load(&self) -> Snapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    fn record(&self, change: Change) -> Result<(), StoreError> {
+        self.snapshot.lock().unwrap().apply(change);
+        Ok(())
+    }
+}
+
+/// Records kept in memory by lowercased name as well as in a `Store`, for what the server
+/// has many of like accounts and registered channels.
+#[derive(Debug)]
+pub struct StoredMap<T> {
+    records: DashMap<String, T>,
+    store: Arc<dyn Store>,
+    /// Held while a record changes, which waits for the store.
+    changing: AsyncMutex<()>,
+    /// The change that replaces or removes a record.
+    change: fn(String, Option<T>) -> Change,
+}
+
+impl<T: Clone + Send + 'static> StoredMap<T> {
+    pub fn new(
+        records: DashMap<String, T>,
+        store: Arc<dyn Store>,
+        change: fn(String, Option<T>) -> Change,
+    ) -> Self {
+        StoredMap {
+            records,
+            store,
+            changing: AsyncMutex::new(()),
+            change,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Ref<'_, String, T>> {
+        self.records.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.records.contains_key(key)
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, T> {
+        self.records.iter()
+    }
+
+    /// Keeps other changes waiting, for as long as a record is checked before it changes.
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.changing.lock().await
+    }
+
+    /// Replaces the record under `key`, or removes it given `None`, and stores the change.
+    /// The change is undone if it can't be stored. Storing it may have to wait for the
+    /// disk, so it happens on a blocking thread.
+    pub async fn commit(&self, key: String, record: Option<T>) -> Result<(), StoreError> {
+        let old = match record.clone() {
+            Some(record) => self.records.insert(key.clone(), record),
+            None => self.records.remove(&key).map(|(_, record)| record),
+        };
+        let store = self.store.clone();
+        let change = (self.change)(key.clone(), record);
+        let stored = tokio::task::spawn_blocking(move || store.record(change))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e).into()));
+        if let Err(e) = stored {
+            match old {
+                Some(old) => self.records.insert(key, old),
+                None => self.records.remove(&key).map(|(_, record)| record),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct FileState {
+    snapshot: Snapshot,
+    log: File,
+    /// Bytes in the log that hold complete changes.
+    length: u64,
+    /// Changes in the log since the last snapshot.
+    logged: usize,
+}
+
+/// Keeps a snapshot in a directory along with a log every change is appended to. Once
+/// enough changes are logged they are folded into a new snapshot and the log starts over.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    snapshot_every: usize,
+    state: Mutex<FileState>,
+}
+
+impl FileStore {
+    /// Reads what the directory holds, creating it if needed, and folds the log into a
+    /// fresh snapshot. A change cut short by a crash while it was appended is dropped.
+    pub fn open(config: &StoreConfig) -> Result<Self, StoreError> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+        let mut snapshot: Snapshot = match fs::read_to_string(dir.join(SNAPSHOT)) {
+            Ok(contents) => serde_yaml::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let log = match fs::read_to_string(dir.join(LOG)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let complete = log.ends_with('\n');
+        let lines: Vec<&str> = log.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(change) => snapshot.apply(change),
+                Err(e) if i + 1 == lines.len() && !complete => {
+                    error!("Dropping the last change in the store log, it was cut short: {}", e);
+                }
+                Err(source) => return Err(StoreError::Log { line: i + 1, source }),
+            }
+        }
+        let log = Self::options().create(true).append(true).open(dir.join(LOG))?;
+        let mut state = FileState {
+            snapshot,
+            log,
+            length: 0,
+            logged: lines.len(),
+        };
+        Self::write_snapshot(&dir, &mut state)?;
+        info!(
+            "Opened store in {} with {} accounts and {} channels",
+            dir.display(),
+            state.snapshot.accounts.len(),
+            state.snapshot.channels.len()
+        );
+        Ok(FileStore {
+            dir,
+            snapshot_every: config.snapshot_every.max(1),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Options for the files the store creates, which only the server's user may read as
+    /// they hold password hashes.
+    fn options() -> OpenOptions {
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+    }
+
+    /// Replaces the snapshot with one of `state` and empties the log. A crash before the
+    /// new snapshot is renamed into place leaves the old one and the log, a crash after
+    /// leaves changes in the log that the new snapshot already has, which replay to the
+    /// same state.
+    fn write_snapshot(dir: &Path, state: &mut FileState) -> Result<(), StoreError> {
+        let temporary = dir.join(format!("{}.tmp", SNAPSHOT));
+        let mut file = Self::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary)?;
+        file.write_all(serde_yaml::to_string(&state.snapshot)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, dir.join(SNAPSHOT))?;
+        // Makes the rename itself durable, which directories can only be synced for on unix.
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+        state.log.set_len(0)?;
+        state.log.sync_all()?;
+        state.length = 0;
+        state.logged = 0;
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn load(&self) -> Snapshot {
+        self.state.lock().unwrap().snapshot.clone()
+    }
+
+    fn record(&self, change: Change) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let mut line = serde_json::to_string(&change).map_err(|source| StoreError::Log {
+            line: state.logged + 1,
+            source,
+        })?;
+        line.push('\n');
+        let written = state.log.write_all(line.as_bytes()).and_then(|_| state.log.sync_data());
+        if let Err(e) = written {
+            // Whatever made it into the log would be taken for a change cut short by a
+            // crash, unless changes come after it.
+            let _ = state.log.set_len(state.length);
+            return Err(e.into());
+        }
+        state.length += line.len() as u64;
+        state.snapshot.apply(change);
+        state.logged += 1;
+        if state.logged >= self.snapshot_every {
+            // The change is safe in the log already, a snapshot can be taken next time.
+            if let Err(e) = Self::write_snapshot(&self.dir, &mut state) {
+                error!("Failed to write a store snapshot: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str) -> Change {
+        Change::Channel {
+            key: name.to_lowercase(),
+            channel: Some(RegisteredChannel::new(name, "alice")),
+        }
+    }
+
+    #[test]
+    pub fn changes_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("pawpaw-store-{}", uuid::Uuid::new_v4()));
+        let config = StoreConfig {
+            path: dir.to_str().unwrap().to_owned(),
+            snapshot_every: 3,
+        };
+        let store = FileStore::open(&config).unwrap();
+        for name in ["#a", "#b", "#c", "#d"] {
+            store.record(channel(name)).unwrap();
+        }
+        store
+            .record(Change::Channel {
+                key: "#b".to_owned(),
+                channel: None,
+            })
+            .unwrap();
+        // Three changes went into the snapshot, the other two are still logged.
+        let log = fs::read_to_string(dir.join(LOG)).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        drop(store);
+        #[cfg(unix)]
+        for file in [LOG, SNAPSHOT] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(file)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", file);
+        }
+
+        // A crash while appending leaves half a line behind.
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG)).unwrap();
+        log.write_all(br##"{"kind":"channel","key":"#e","chan"##).unwrap();
+        drop(log);
+        let store = FileStore::open(&config).unwrap();
+        let names: Vec<String> = store.load().channels.into_keys().collect();
+        assert_eq!(names, vec!["#a", "#c", "#d"]);
+        assert_eq!(fs::read_to_string(dir.join(LOG)).unwrap(), "");
+
+        fs::write(dir.join(LOG), "not json\n").unwrap();
+        assert!(matches!(FileStore::open(&config), Err(StoreError::Log { line: 1, .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
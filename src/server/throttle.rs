@@ -0,0 +1,289 @@
+use crate::config::ClassConfig;
+use ipnet::IpNet;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Above this many tracked addresses stale connect history is swept on every admission.
+const RECENT_SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ThrottleError {
+    #[error("Sorry, server is full - try later")]
+    ServerFull,
+    #[error("Too many connections from your host")]
+    TooManyFromHost,
+    #[error("Too many connections from your network")]
+    TooManyFromNetwork,
+    #[error("Throttled: reconnecting too fast")]
+    Throttled,
+}
+
+#[derive(Debug, Default)]
+struct ThrottleState {
+    per_ip: HashMap<IpAddr, usize>,
+    per_cidr: HashMap<IpNet, usize>,
+    per_class: HashMap<String, usize>,
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+/// Connection limits checked as soon as a TCP connection is accepted, before any TLS or
+/// DNS work is done for it.
+#[derive(Debug)]
+pub struct Throttle {
+    exempt: Vec<IpNet>,
+    state: Mutex<ThrottleState>,
+}
+
+impl Throttle {
+    pub fn new(exempt: Vec<IpNet>) -> Self {
+        Self {
+            exempt,
+            state: Mutex::new(ThrottleState::default()),
+        }
+    }
+
+    fn is_exempt(&self, addr: &IpAddr) -> bool {
+        self.exempt.iter().any(|net| net.contains(addr))
+    }
+
+    fn network(addr: IpAddr, class: &ClassConfig) -> IpNet {
+        let prefix = match addr {
+            IpAddr::V4(_) => class.cidr_v4.min(32),
+            IpAddr::V6(_) => class.cidr_v6.min(128),
+        };
+        IpNet::new(addr, prefix)
+            .expect("prefix length clamped to address size")
+            .trunc()
+    }
+
+    /// Admits a new connection from `addr`, the returned guard releases its slot on drop.
+    pub fn admit(
+        self: &Arc<Self>,
+        addr: IpAddr,
+        class: &ClassConfig,
+        now: Instant,
+    ) -> Result<ConnectionGuard, ThrottleError> {
+        let mut state = self.state.lock().expect("throttle state poisoned");
+        let network = Self::network(addr, class);
+
+        Self::check_class(&state, class)?;
+        if !self.is_exempt(&addr) {
+            let per_ip = state.per_ip.get(&addr).copied().unwrap_or(0);
+            if class.max_per_ip.is_some_and(|max| per_ip >= max) {
+                return Err(ThrottleError::TooManyFromHost);
+            }
+            let per_cidr = state.per_cidr.get(&network).copied().unwrap_or(0);
+            if class.max_per_cidr.is_some_and(|max| per_cidr >= max) {
+                return Err(ThrottleError::TooManyFromNetwork);
+            }
+            if let Some(throttle_count) = class.throttle_count {
+                let period = class.throttle_period();
+                if state.recent.len() > RECENT_SWEEP_THRESHOLD {
+                    state.recent.retain(|_, times| {
+                        times.back().is_some_and(|last| now.duration_since(*last) < period)
+                    });
+                }
+                let recent = state.recent.entry(addr).or_default();
+                while recent
+                    .front()
+                    .is_some_and(|first| now.duration_since(*first) >= period)
+                {
+                    recent.pop_front();
+                }
+                if recent.len() >= throttle_count {
+                    return Err(ThrottleError::Throttled);
+                }
+                recent.push_back(now);
+            }
+        }
+
+        *state.per_ip.entry(addr).or_default() += 1;
+        *state.per_cidr.entry(network).or_default() += 1;
+        *state.per_class.entry(class.name.clone()).or_default() += 1;
+        Ok(ConnectionGuard {
+            throttle: self.clone(),
+            source: Some((addr, network)),
+            class: class.name.clone(),
+        })
+    }
+
+    /// Admits a connection made on this host, which has no address of its own and is only
+    /// held to the class limit.
+    pub fn admit_local(
+        self: &Arc<Self>,
+        class: &ClassConfig,
+    ) -> Result<ConnectionGuard, ThrottleError> {
+        let mut state = self.state.lock().expect("throttle state poisoned");
+        Self::check_class(&state, class)?;
+        *state.per_class.entry(class.name.clone()).or_default() += 1;
+        Ok(ConnectionGuard {
+            throttle: self.clone(),
+            source: None,
+            class: class.name.clone(),
+        })
+    }
+
+    fn check_class(state: &ThrottleState, class: &ClassConfig) -> Result<(), ThrottleError> {
+        let in_class = state.per_class.get(&class.name).copied().unwrap_or(0);
+        if class.max_clients.is_some_and(|max| in_class >= max) {
+            return Err(ThrottleError::ServerFull);
+        }
+        Ok(())
+    }
+
+    fn release(&self, guard: &ConnectionGuard) {
+        let mut state = self.state.lock().expect("throttle state poisoned");
+        if let Some((addr, network)) = &guard.source {
+            decrement(&mut state.per_ip, addr);
+            decrement(&mut state.per_cidr, network);
+        }
+        decrement(&mut state.per_class, &guard.class);
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+/// Holds a connection's slot in the throttle for as long as the client is connected.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    throttle: Arc<Throttle>,
+    /// The address and network the connection counts against, none for local ones.
+    source: Option<(IpAddr, IpNet)>,
+    class: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.throttle.release(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn class() -> ClassConfig {
+        ClassConfig {
+            max_clients: Some(4),
+            max_per_ip: Some(2),
+            max_per_cidr: Some(3),
+            throttle_count: Some(10),
+            ..ClassConfig::default()
+        }
+    }
+
+    #[test]
+    pub fn per_ip_limit() {
+        let throttle = Arc::new(Throttle::new(Vec::new()));
+        let class = class();
+        let now = Instant::now();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = throttle.admit(addr, &class, now).unwrap();
+        let _second = throttle.admit(addr, &class, now).unwrap();
+        assert_eq!(
+            throttle.admit(addr, &class, now).unwrap_err(),
+            ThrottleError::TooManyFromHost
+        );
+        drop(first);
+        assert!(throttle.admit(addr, &class, now).is_ok());
+    }
+
+    #[test]
+    pub fn unlimited_by_default() {
+        let throttle = Arc::new(Throttle::new(Vec::new()));
+        let class = ClassConfig::default();
+        let now = Instant::now();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let _guards: Vec<_> = (0..100)
+            .map(|_| throttle.admit(addr, &class, now).unwrap())
+            .collect();
+    }
+
+    #[test]
+    pub fn ipv6_networks_share_a_limit() {
+        let throttle = Arc::new(Throttle::new(Vec::new()));
+        let class = class();
+        let now = Instant::now();
+        let _guards: Vec<_> = (1..=3)
+            .map(|i| {
+                let addr: IpAddr = format!("2001:db8::{}", i).parse().unwrap();
+                throttle.admit(addr, &class, now).unwrap()
+            })
+            .collect();
+        let addr: IpAddr = "2001:db8::ffff".parse().unwrap();
+        assert_eq!(
+            throttle.admit(addr, &class, now).unwrap_err(),
+            ThrottleError::TooManyFromNetwork
+        );
+    }
+
+    #[test]
+    pub fn connect_rate() {
+        let throttle = Arc::new(Throttle::new(Vec::new()));
+        let class = ClassConfig {
+            throttle_count: Some(2),
+            throttle_period: 10,
+            ..class()
+        };
+        let now = Instant::now();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        drop(throttle.admit(addr, &class, now).unwrap());
+        drop(throttle.admit(addr, &class, now).unwrap());
+        assert_eq!(
+            throttle.admit(addr, &class, now).unwrap_err(),
+            ThrottleError::Throttled
+        );
+        assert!(throttle
+            .admit(addr, &class, now + Duration::from_secs(10))
+            .is_ok());
+    }
+
+    #[test]
+    pub fn exempt_still_counts_towards_class() {
+        let throttle = Arc::new(Throttle::new(vec!["127.0.0.0/8".parse().unwrap()]));
+        let class = class();
+        let now = Instant::now();
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        let _guards: Vec<_> = (0..4)
+            .map(|_| throttle.admit(addr, &class, now).unwrap())
+            .collect();
+        assert_eq!(
+            throttle.admit(addr, &class, now).unwrap_err(),
+            ThrottleError::ServerFull
+        );
+    }
+
+    #[test]
+    pub fn local_connections_only_count_towards_class() {
+        let throttle = Arc::new(Throttle::new(Vec::new()));
+        let class = class();
+        let now = Instant::now();
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let local: Vec<_> = (0..2).map(|_| throttle.admit_local(&class).unwrap()).collect();
+        // Loopback TCP clients keep their own slots.
+        let _tcp: Vec<_> = (0..2)
+            .map(|_| throttle.admit(loopback, &class, now).unwrap())
+            .collect();
+        assert_eq!(
+            throttle.admit_local(&class).unwrap_err(),
+            ThrottleError::ServerFull
+        );
+        drop(local);
+        assert!(throttle.admit_local(&class).is_ok());
+        assert_eq!(
+            throttle.admit(loopback, &class, now).unwrap_err(),
+            ThrottleError::TooManyFromHost
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use crate::config::{ListenConfig, TlsBackend, TlsVersion};
+use crate::server::listener::ListenerError;
+use crate::server::socket::Socket;
+use crate::server::ServerError;
+use log::warn;
+use std::fmt;
+#[cfg(feature = "rustls")]
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use tokio::fs::read;
+use tokio::net::TcpStream;
+
+/// An acceptor shared between a listener's accept loop and its `TlsReloader`, each new
+/// handshake takes whatever acceptor is current.
+pub type SharedAcceptor<T> = Arc<RwLock<T>>;
+
+#[derive(Clone)]
+pub enum AcceptorHandle {
+    #[cfg(feature = "native-tls")]
+    Native(SharedAcceptor<tokio_native_tls::TlsAcceptor>),
+    #[cfg(feature = "rustls")]
+    Rustls(SharedAcceptor<tokio_rustls::TlsAcceptor>),
+}
+
+impl AcceptorHandle {
+    /// Builds an acceptor for the listener's configured backend.
+    pub async fn build(name: &str, listener: &ListenConfig) -> Result<Self, ServerError> {
+        let (cert, key) = read_identity(name, listener).await?;
+        match listener.tls_backend.unwrap_or_default() {
+            #[cfg(feature = "native-tls")]
+            TlsBackend::NativeTls => Ok(AcceptorHandle::Native(Arc::new(RwLock::new(
+                native_tls_acceptor(name, listener, &cert, &key)?,
+            )))),
+            #[cfg(feature = "rustls")]
+            TlsBackend::Rustls => Ok(AcceptorHandle::Rustls(Arc::new(RwLock::new(
+                rustls_acceptor(name, listener, &cert, &key)?,
+            )))),
+            #[allow(unreachable_patterns)]
+            _ => Err(ServerError::TLSBackendUnavailable(name.to_owned())),
+        }
+    }
+
+    /// Runs a handshake with whatever acceptor is current.
+    pub async fn accept(&self, socket: TcpStream) -> Result<Socket<TcpStream>, ListenerError> {
+        match self {
+            #[cfg(feature = "native-tls")]
+            AcceptorHandle::Native(shared) => {
+                let accept = shared.read().expect("tls acceptor lock poisoned").clone();
+                let stream = accept
+                    .accept(socket)
+                    .await
+                    .map_err(|source| ListenerError::TlsError { source })?;
+                Ok(Socket::Tls(stream))
+            }
+            #[cfg(feature = "rustls")]
+            AcceptorHandle::Rustls(shared) => {
+                let accept = shared.read().expect("tls acceptor lock poisoned").clone();
+                let stream = accept
+                    .accept(socket)
+                    .await
+                    .map_err(|source| ListenerError::RusTlsError { source })?;
+                Ok(Socket::RusTls(stream))
+            }
+        }
+    }
+}
+
+/// Rebuilds a listener's acceptor from its certificate and key files. Sessions that
+/// already finished their handshake are not affected.
+pub struct TlsReloader {
+    name: String,
+    listener: ListenConfig,
+    acceptor: AcceptorHandle,
+}
+
+impl TlsReloader {
+    pub fn new(name: String, listener: ListenConfig, acceptor: AcceptorHandle) -> Self {
+        Self {
+            name,
+            listener,
+            acceptor,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The certificate and key files backing this listener.
+    pub fn files(&self) -> impl Iterator<Item = &String> {
+        self.listener
+            .tls_cert
+            .iter()
+            .chain(self.listener.tls_key.iter())
+    }
+
+    /// Swaps in a freshly loaded acceptor. On error the current acceptor stays in place.
+    pub async fn reload(&self) -> Result<(), ServerError> {
+        let (cert, key) = read_identity(&self.name, &self.listener).await?;
+        match &self.acceptor {
+            #[cfg(feature = "native-tls")]
+            AcceptorHandle::Native(shared) => {
+                let acceptor = native_tls_acceptor(&self.name, &self.listener, &cert, &key)?;
+                *shared.write().expect("tls acceptor lock poisoned") = acceptor;
+            }
+            #[cfg(feature = "rustls")]
+            AcceptorHandle::Rustls(shared) => {
+                let acceptor = rustls_acceptor(&self.name, &self.listener, &cert, &key)?;
+                *shared.write().expect("tls acceptor lock poisoned") = acceptor;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TlsReloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsReloader")
+            .field("name", &self.name)
+            .field("tls_cert", &self.listener.tls_cert)
+            .field("tls_key", &self.listener.tls_key)
+            .finish()
+    }
+}
+
+async fn read_identity(name: &str, listener: &ListenConfig) -> Result<(Vec<u8>, Vec<u8>), ServerError> {
+    let cert = if let Some(cert) = &listener.tls_cert {
+        read(cert)
+            .await
+            .map_err(|source| ServerError::Io { source })?
+    } else {
+        return Err(ServerError::TLSMissingCert(name.to_owned()));
+    };
+    let key = if let Some(key) = &listener.tls_key {
+        read(key)
+            .await
+            .map_err(|source| ServerError::Io { source })?
+    } else {
+        return Err(ServerError::TLSMissingKey(name.to_owned()));
+    };
+    Ok((cert, key))
+}
+
+#[cfg(feature = "native-tls")]
+fn native_tls_acceptor(
+    name: &str,
+    listener: &ListenConfig,
+    cert: &[u8],
+    key: &[u8],
+) -> Result<tokio_native_tls::TlsAcceptor, ServerError> {
+    use tokio_native_tls::native_tls::{Identity, Protocol, TlsAcceptor};
+
+    if !listener.tls_alpn.is_empty() {
+        warn!("listener {} ignoring ALPN, native-tls doesn't support it", name);
+    }
+    if listener.tls_min_version > TlsVersion::Tls12 {
+        warn!(
+            "listener {} can't require TLS 1.3 with native-tls, using TLS 1.2",
+            name
+        );
+    }
+    let ident = Identity::from_pkcs8(cert, key)?;
+    let acceptor = TlsAcceptor::builder(ident)
+        .min_protocol_version(Some(Protocol::Tlsv12))
+        .build()?;
+    Ok(tokio_native_tls::TlsAcceptor::from(acceptor))
+}
+
+#[cfg(feature = "rustls")]
+fn load_certs(name: &str, cert: &[u8]) -> Result<Vec<tokio_rustls::rustls::Certificate>, ServerError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert))
+        .map_err(|_| ServerError::TLSInvalidCert(name.to_owned()))?;
+    if certs.is_empty() {
+        return Err(ServerError::TLSInvalidCert(name.to_owned()));
+    }
+    Ok(certs
+        .into_iter()
+        .map(tokio_rustls::rustls::Certificate)
+        .collect())
+}
+
+/// Finds the first private key in a PEM file, PKCS#8, PKCS#1 (RSA) and SEC1 (EC) are accepted.
+#[cfg(feature = "rustls")]
+fn load_key(name: &str, key: &[u8]) -> Result<tokio_rustls::rustls::PrivateKey, ServerError> {
+    use rustls_pemfile::Item;
+
+    let mut reader = BufReader::new(key);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|_| ServerError::TLSInvalidKey(name.to_owned()))?
+        {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(tokio_rustls::rustls::PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(ServerError::TLSInvalidKey(name.to_owned())),
+        }
+    }
+}
+
+/// Asks for a client certificate without requiring or validating one, the certificate is
+/// only used for its fingerprint.
+#[cfg(feature = "rustls")]
+struct AnyClientCert;
+
+#[cfg(feature = "rustls")]
+impl rustls::server::ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::ClientCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_acceptor(
+    name: &str,
+    listener: &ListenConfig,
+    cert: &[u8],
+    key: &[u8],
+) -> Result<tokio_rustls::TlsAcceptor, ServerError> {
+    use tokio_rustls::rustls::{version, ServerConfig};
+
+    let certs = load_certs(name, cert)?;
+    let key = load_key(name, key)?;
+    let versions: &[&'static tokio_rustls::rustls::SupportedProtocolVersion] =
+        match listener.tls_min_version {
+            TlsVersion::Tls12 => &[&version::TLS13, &version::TLS12],
+            TlsVersion::Tls13 => &[&version::TLS13],
+        };
+    let mut config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
+        .with_client_cert_verifier(Arc::new(AnyClientCert))
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = listener
+        .tls_alpn
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
@@ -1,42 +1,141 @@
+use crate::config::ClassConfig;
 use crate::proto::Command;
 use crate::proto::MessageCodec;
 use crate::proto::ProtocolError;
 use crate::proto::{Message, MessageContents};
+use crate::server::listener::{Handshake, ListenerError};
+use crate::server::socket::{Socket, TlsInfo};
 use crate::server::Server;
+use futures::task::AtomicWaker;
 use futures::{sink::Sink, stream::Stream};
 use pin_project::pin_project;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 use std::time::Duration;
 use thiserror::Error;
+use std::io;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::time;
 use tokio::time::{Interval, Sleep};
 use tokio_util::codec::Framed;
+use uuid::Uuid;
+
+/// Byte accounting for a client's outgoing queue, shared between every `Sender`
+/// clone and the `Outgoing` half that drains it.
+#[derive(Debug)]
+pub struct SendQueue {
+    queued: AtomicUsize,
+    max: usize,
+    soft: usize,
+    exceeded: AtomicBool,
+    warned: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl SendQueue {
+    pub fn new(class: &ClassConfig) -> Self {
+        Self {
+            queued: AtomicUsize::new(0),
+            max: class.sendq,
+            soft: class.sendq_soft,
+            exceeded: AtomicBool::new(false),
+            warned: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Bytes a message occupies on the wire, including the line terminator.
+    pub fn message_len(message: &Message) -> usize {
+        message.to_string().len() + 2
+    }
+
+    /// Accounts for a newly queued message. Returns true the first time the queue
+    /// grows past the soft limit.
+    fn push(&self, len: usize) -> Result<bool, ProtocolError> {
+        if self.exceeded.load(Ordering::Acquire) {
+            return Err(ProtocolError::SendQExceeded);
+        }
+        let queued = self.queued.fetch_add(len, Ordering::AcqRel) + len;
+        if queued > self.max {
+            self.exceeded.store(true, Ordering::Release);
+            self.waker.wake();
+            return Err(ProtocolError::SendQExceeded);
+        }
+        Ok(queued > self.soft && !self.warned.swap(true, Ordering::AcqRel))
+    }
+
+    /// Accounts for a message leaving the queue for the socket.
+    pub fn pop(&self, len: usize) {
+        let before = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                Some(queued.saturating_sub(len))
+            })
+            .unwrap_or_default();
+        debug_assert!(before >= len, "popped {} bytes with only {} queued", len, before);
+        let queued = before.saturating_sub(len);
+        if queued <= self.soft {
+            self.warned.store(false, Ordering::Release);
+        }
+    }
+
+    /// Registers the draining task and reports whether the hard limit was hit.
+    pub fn poll_exceeded(&self, cx: &mut Context<'_>) -> bool {
+        self.waker.register(cx.waker());
+        self.exceeded.load(Ordering::Acquire)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Sender {
     server: Arc<Server>,
     sender: UnboundedSender<Message>,
+    queue: Arc<SendQueue>,
 }
 
 impl Sender {
     pub fn send<M: Into<Message>>(&self, msg: M) -> Result<(), ProtocolError> {
         let mut m = msg.into();
-        m.set_prefix(self.server.prefix());
+        if m.prefix.is_none() {
+            m.set_prefix(self.server.prefix());
+        }
+        let soft = self.queue.push(SendQueue::message_len(&m))?;
         self.sender.send(m).map_err(|_| ProtocolError::SendError)?;
+        if soft {
+            let state = self.server.state();
+            let sender = self.clone();
+            tokio::spawn(async move { state.sendq_warning(&sender).await });
+        }
         Ok(())
     }
 
-    pub fn new(server: Arc<Server>, sender: UnboundedSender<Message>) -> Self {
-        Self { server, sender }
+    pub fn new(
+        server: Arc<Server>,
+        sender: UnboundedSender<Message>,
+        queue: Arc<SendQueue>,
+    ) -> Self {
+        Self {
+            server,
+            sender,
+            queue,
+        }
     }
 
-    pub fn tx(&self) -> UnboundedSender<Message> {
-        self.sender.clone()
+    pub fn queue(&self) -> &Arc<SendQueue> {
+        &self.queue
     }
 
     fn server(&self) -> &Arc<Server> {
@@ -56,60 +155,73 @@ struct Pinger {
     tx: Sender,
     enabled: bool,
     ping_timeout: Duration,
-    #[pin]
-    ping_deadline: Option<Sleep>,
+    /// Outstanding registration cookie, cleared once the client echoes it back.
+    cookie: Option<String>,
+    ping_deadline: Option<Pin<Box<Sleep>>>,
     #[pin]
     ping_interval: Interval,
 }
 
 impl Pinger {
-    pub fn new(tx: Sender) -> Pinger {
-        let ping_time = Duration::from_secs(120);
-        let ping_timeout = Duration::from_secs(30);
+    pub fn new(tx: Sender, class: &ClassConfig) -> Result<Pinger, ProtocolError> {
         let mut ret = Self {
             tx,
             enabled: true,
-            ping_timeout,
+            ping_timeout: class.ping_timeout(),
+            cookie: None,
             ping_deadline: None,
-            ping_interval: time::interval(ping_time),
+            ping_interval: time::interval(class.ping_interval()),
         };
         ret.ping_interval.reset();
-        ret
+        if class.ping_cookie {
+            let cookie = Uuid::new_v4().simple().to_string();
+            ret.tx.send(Command::Ping(cookie.clone(), None))?;
+            ret.ping_deadline = Some(Box::pin(time::sleep(class.ping_cookie_timeout())));
+            ret.cookie = Some(cookie);
+        }
+        Ok(ret)
     }
 
+    /// Returns true if the message was consumed by the pinger.
     fn handle_message(self: Pin<&mut Self>, message: &Message) -> Result<bool, ProtocolError> {
-        if let MessageContents::Command(command) = &message.contents {
-            match command {
-                Command::PING(ref data, _) => {
-                    self.send_pong(data)?;
-                    return Ok(true);
-                }
-                Command::PONG(_, None) | Command::PONG(_, Some(_)) => {
-                    self.project().ping_deadline.set(None);
-                    return Ok(true);
+        let mut this = self.project();
+        let command = match &message.contents {
+            MessageContents::Command(command) => Some(command),
+            _ => None,
+        };
+        if let Some(cookie) = this.cookie.as_ref() {
+            // Until the cookie is echoed only the matching PONG counts as proof of life.
+            if let Some(Command::PONG(data, _)) = command {
+                if data == cookie {
+                    *this.cookie = None;
+                    *this.ping_deadline = None;
+                    this.ping_interval.reset();
+                    return Ok(false);
                 }
-                _ => (),
             }
+        } else {
+            *this.ping_deadline = None;
+            this.ping_interval.reset();
+        }
+        match command {
+            Some(Command::PING(ref data, _)) => {
+                this.tx
+                    .send(Command::Pong(data.to_owned(), None))
+                    .map_err(|_| ProtocolError::SendError)?;
+                Ok(true)
+            }
+            Some(Command::PONG(..)) => Ok(true),
+            _ => Ok(false),
         }
-        Ok(false)
-    }
-
-    fn send_pong(self: Pin<&mut Self>, data: &str) -> Result<(), ProtocolError> {
-        self.project()
-            .tx
-            .send(Command::Pong(data.to_owned(), None))
-            .map_err(|_| ProtocolError::SendError)?;
-        Ok(())
     }
 
     fn send_ping(self: Pin<&mut Self>) -> Result<(), ProtocolError> {
-        let mut this = self.project();
+        let this = self.project();
         this.tx
             .send(Command::Ping(this.tx.server().name(), None))
             .map_err(|_| ProtocolError::SendError)?;
         if this.ping_deadline.is_none() {
-            let ping_deadline = time::sleep(*this.ping_timeout);
-            this.ping_deadline.set(Some(ping_deadline));
+            *this.ping_deadline = Some(Box::pin(time::sleep(*this.ping_timeout)));
         }
         Ok(())
     }
@@ -118,18 +230,21 @@ impl Pinger {
 impl Future for Pinger {
     type Output = Result<(), ProtocolError>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Some(ping_deadline) = self.as_mut().project().ping_deadline.as_pin_mut() {
-            match ping_deadline.poll(cx) {
-                Poll::Ready(()) => return Poll::Ready(Err(ProtocolError::PingTimeout)),
-                Poll::Pending => (),
+        // Sending a ping starts a deadline, which has to be polled before returning for its
+        // timer to wake the task.
+        loop {
+            if let Some(ping_deadline) = self.as_mut().project().ping_deadline.as_mut() {
+                if ping_deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Err(ProtocolError::PingTimeout));
+                }
             }
-        }
-        if let Poll::Ready(_) = self.as_mut().project().ping_interval.poll_tick(cx) {
-            if *self.as_mut().project().enabled {
+            if self.as_mut().project().ping_interval.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+            if *self.as_mut().project().enabled && self.cookie.is_none() {
                 self.as_mut().send_ping()?;
             }
         }
-        Poll::Pending
     }
 }
 
@@ -146,9 +261,18 @@ impl<T> Transport<T>
 where
     T: Unpin + AsyncRead + AsyncWrite,
 {
-    pub fn new(inner: Framed<T, MessageCodec>, tx: Sender) -> Transport<T> {
-        let pinger = Some(Pinger::new(tx));
-        Transport { inner, pinger }
+    pub fn new(
+        inner: Framed<T, MessageCodec>,
+        tx: Sender,
+        class: &ClassConfig,
+    ) -> Result<Transport<T>, ProtocolError> {
+        let pinger = Some(Pinger::new(tx, class)?);
+        Ok(Transport { inner, pinger })
+    }
+
+    /// The PING cookie the client still has to echo before it may register.
+    pub fn cookie(&self) -> Option<String> {
+        self.pinger.as_ref().and_then(|p| p.cookie.clone())
     }
 
     pub fn into_inner(self) -> Framed<T, MessageCodec> {
@@ -156,6 +280,35 @@ where
     }
 }
 
+impl Transport<Socket<TcpStream>> {
+    /// Upgrades a plaintext connection in place for STARTTLS. The read buffer is dropped
+    /// instead of being carried over, so nothing the client sent before the handshake can
+    /// be replayed inside the TLS session.
+    pub async fn starttls(self, handshake: Handshake) -> Result<Self, ListenerError> {
+        let Transport { inner, pinger } = self;
+        let parts = inner.into_parts();
+        let socket = match parts.io {
+            Socket::Plain(socket) => socket,
+            _ => {
+                return Err(ListenerError::ConnectionError {
+                    source: io::Error::other("connection already uses TLS"),
+                })
+            }
+        };
+        let socket = handshake.run(socket).await?;
+        let mut codec = parts.codec;
+        codec.reset();
+        Ok(Transport {
+            inner: Framed::new(socket, codec),
+            pinger,
+        })
+    }
+
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.inner.get_ref().tls_info()
+    }
+}
+
 impl<T> Stream for Transport<T>
 where
     T: Unpin + AsyncRead + AsyncWrite,
@@ -169,25 +322,27 @@ where
                 Poll::Pending => (),
             }
         }
-        let result: Option<Result<Result<Message, ProtocolError>, ProtocolError>> =
-            ready!(self.as_mut().project().inner.poll_next(cx));
-        let message: Message = match result {
-            None => return Poll::Ready(None),
-            Some(message) => match message {
-                Ok(msg) => match msg {
-                    Ok(v) => v,
+        loop {
+            let result: Option<Result<Result<Message, ProtocolError>, ProtocolError>> =
+                ready!(self.as_mut().project().inner.poll_next(cx));
+            let message: Message = match result {
+                None => return Poll::Ready(None),
+                Some(message) => match message {
+                    Ok(msg) => match msg {
+                        Ok(v) => v,
+                        Err(v) => return Poll::Ready(Some(Err(v))),
+                    },
                     Err(v) => return Poll::Ready(Some(Err(v))),
                 },
-                Err(v) => return Poll::Ready(Some(Err(v))),
-            },
-        };
+            };
 
-        if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
-            if pinger.handle_message(&message)? {
-                return Poll::Pending;
+            if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
+                if pinger.handle_message(&message)? {
+                    continue;
+                }
             }
+            return Poll::Ready(Some(Ok(message)));
         }
-        Poll::Ready(Some(Ok(message)))
     }
 }
 
@@ -216,3 +371,34 @@ where
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(sendq: usize, sendq_soft: usize) -> SendQueue {
+        SendQueue::new(&ClassConfig {
+            sendq,
+            sendq_soft,
+            ..ClassConfig::default()
+        })
+    }
+
+    #[test]
+    pub fn sendq_soft_limit_warns_once() {
+        let queue = queue(100, 50);
+        assert!(!queue.push(40).unwrap());
+        assert!(queue.push(20).unwrap());
+        assert!(!queue.push(20).unwrap());
+        queue.pop(60);
+        assert!(queue.push(40).unwrap());
+    }
+
+    #[test]
+    pub fn sendq_hard_limit_is_terminal() {
+        let queue = queue(100, 50);
+        assert!(queue.push(101).is_err());
+        queue.pop(101);
+        assert!(matches!(queue.push(1), Err(ProtocolError::SendQExceeded)));
+    }
+}
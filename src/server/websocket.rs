@@ -0,0 +1,279 @@
+use crate::proto::codec::message::{MESSAGE_LINE_LENGTH, TAG_LENGTH};
+use crate::server::listener::ListenerError;
+use crate::server::socket::TlsInfo;
+use bytes::BytesMut;
+use futures::{Sink, Stream};
+use ipnet::IpNet;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message as Frame;
+use tokio_tungstenite::WebSocketStream;
+
+const TEXT_PROTOCOL: &str = "text.ircv3.net";
+const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+/// Largest message a client may send, the longest line `MessageCodec` accepts. Frames are
+/// read whole, so anything bigger would be buffered before the codec could refuse it.
+const MAX_MESSAGE: usize = TAG_LENGTH + MESSAGE_LINE_LENGTH;
+
+/// Any byte stream a WebSocket can run over, plain or TLS.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketSettings {
+    /// Origins browsers may connect from, any origin is accepted when empty.
+    pub origins: Vec<String>,
+}
+
+impl WebSocketSettings {
+    /// Only browsers send an Origin, other clients are let through.
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) => {
+                self.origins.is_empty()
+                    || self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+            }
+            None => true,
+        }
+    }
+}
+
+/// Walks X-Forwarded-For from the right, skipping our own proxies, to find the client.
+fn forwarded_for(header: &str, trusted: &[IpNet]) -> Option<IpAddr> {
+    for entry in header.rsplit(',') {
+        let ip: IpAddr = entry.trim().parse().ok()?;
+        if !trusted.iter().any(|net| net.contains(&ip)) {
+            return Some(ip);
+        }
+    }
+    None
+}
+
+/// The first subprotocol offered by the client that we speak.
+fn negotiate(request: &Request) -> Option<&'static str> {
+    request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| match protocol.trim() {
+            TEXT_PROTOCOL => Some(TEXT_PROTOCOL),
+            BINARY_PROTOCOL => Some(BINARY_PROTOCOL),
+            _ => None,
+        })
+}
+
+fn error_response(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_owned()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Completes the WebSocket handshake. Returns the stream along with the client's address,
+/// which comes from X-Forwarded-For when `addr` is one of the `trusted` proxies.
+#[allow(clippy::result_large_err)]
+pub async fn accept(
+    io: Box<dyn Io>,
+    tls: Option<TlsInfo>,
+    addr: SocketAddr,
+    settings: &WebSocketSettings,
+    trusted: &[IpNet],
+) -> Result<(WsStream, SocketAddr), ListenerError> {
+    let mut binary = false;
+    let mut client = addr;
+    let callback = |request: &Request, mut response: Response| {
+        let origin = request
+            .headers()
+            .get("Origin")
+            .and_then(|origin| origin.to_str().ok());
+        if !settings.origin_allowed(origin) {
+            return Err(error_response(StatusCode::FORBIDDEN, "Origin not allowed"));
+        }
+        if let Some(protocol) = negotiate(request) {
+            binary = protocol == BINARY_PROTOCOL;
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
+        }
+        if trusted.iter().any(|net| net.contains(&addr.ip())) {
+            let header = request
+                .headers()
+                .get_all("X-Forwarded-For")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            if let Some(ip) = forwarded_for(&header, trusted) {
+                client = SocketAddr::new(ip, addr.port());
+            }
+        }
+        Ok(response)
+    };
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE),
+        max_frame_size: Some(MAX_MESSAGE),
+        ..WebSocketConfig::default()
+    };
+    let inner = tokio_tungstenite::accept_hdr_async_with_config(io, callback, Some(config))
+        .await
+        .map_err(|source| ListenerError::WebSocket {
+            source: Box::new(source),
+        })?;
+    Ok((WsStream::new(inner, binary, tls), client))
+}
+
+/// Carries IRC lines over WebSocket frames, one line per frame, so the usual
+/// `MessageCodec` framing can sit on top.
+pub struct WsStream {
+    inner: WebSocketStream<Box<dyn Io>>,
+    binary: bool,
+    tls: Option<TlsInfo>,
+    /// Received lines not yet handed to the reader, each terminated with CRLF.
+    read_buf: BytesMut,
+    /// The start of an outgoing line whose end hasn't been written yet.
+    line: BytesMut,
+}
+
+impl WsStream {
+    fn new(inner: WebSocketStream<Box<dyn Io>>, binary: bool, tls: Option<TlsInfo>) -> Self {
+        Self {
+            inner,
+            binary,
+            tls,
+            read_buf: BytesMut::new(),
+            line: BytesMut::new(),
+        }
+    }
+
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls.clone()
+    }
+}
+
+impl fmt::Debug for WsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsStream")
+            .field("binary", &self.binary)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            let frame = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            };
+            let payload = match frame {
+                Frame::Text(text) => text.into_bytes(),
+                Frame::Binary(data) => data,
+                Frame::Close(_) => return Poll::Ready(Ok(())),
+                // Pings are answered by tungstenite itself.
+                _ => continue,
+            };
+            let line = payload
+                .strip_suffix(b"\n")
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .unwrap_or(&payload);
+            if !line.is_empty() {
+                self.read_buf.extend_from_slice(line);
+                self.read_buf.extend_from_slice(b"\r\n");
+            }
+        }
+        let len = buf.remaining().min(self.read_buf.len());
+        buf.put_slice(&self.read_buf.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io::Error::other)?;
+        match memchr::memchr(b'\n', buf) {
+            Some(end) => {
+                self.line.extend_from_slice(&buf[..end]);
+                let mut line = self.line.split();
+                if line.last() == Some(&b'\r') {
+                    line.truncate(line.len() - 1);
+                }
+                let frame = if self.binary {
+                    Frame::Binary(line.to_vec())
+                } else {
+                    Frame::Text(String::from_utf8_lossy(&line).into_owned())
+                };
+                Pin::new(&mut self.inner)
+                    .start_send(frame)
+                    .map_err(io::Error::other)?;
+                Poll::Ready(Ok(end + 1))
+            }
+            None => {
+                self.line.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(origins: &[&str]) -> WebSocketSettings {
+        WebSocketSettings {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    pub fn origin_allowlist() {
+        let open = settings(&[]);
+        assert!(open.origin_allowed(Some("https://anywhere.example")));
+        let strict = settings(&["https://web.example"]);
+        assert!(strict.origin_allowed(Some("https://WEB.example")));
+        assert!(!strict.origin_allowed(Some("https://evil.example")));
+        assert!(strict.origin_allowed(None));
+    }
+
+    #[test]
+    pub fn forwarded_for_skips_trusted_hops() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        assert_eq!(
+            forwarded_for("203.0.113.9, 198.51.100.7, 10.0.0.2", &trusted),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(forwarded_for("10.0.0.3", &trusted), None);
+        assert_eq!(forwarded_for("not-an-ip", &trusted), None);
+    }
+}
@@ -0,0 +1,63 @@
+//! Starts the server binary and negotiates capabilities the way IRCv3 clients do.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::net::SocketAddr;
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain("cap", name, "")
+}
+
+#[tokio::test]
+async fn registration_waits_for_cap_end() {
+    let (_server, addr) = start_server("end");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP LS 302\r\nNICK capper\r\nUSER capper 0 * :Cap").await;
+    let lines = client.read_until(" LS ").await;
+    let ls = lines.last().unwrap();
+    assert!(ls.contains("cap-notify") && ls.contains("chghost"), "{}", ls);
+    assert!(!ls.contains(" tls"), "{}", ls);
+
+    // A 302 client has cap-notify without asking and can't turn it off.
+    client.send("CAP LIST").await;
+    let lines = client.read_until(" LIST ").await;
+    assert!(lines.last().unwrap().contains("cap-notify"), "{:?}", lines);
+    client.send("CAP REQ :-cap-notify").await;
+    client.read_until(" NAK ").await;
+
+    client.send("CAP REQ :").await;
+    client.read_until(" NAK ").await;
+    client.send("CAP REQ :chghost unknown").await;
+    client.read_until(" NAK ").await;
+    client.send("CAP REQ chghost").await;
+    let lines = client.read_until(" ACK ").await;
+    assert!(
+        !lines.iter().any(|line| line.contains(" 001 ")),
+        "registered during negotiation: {:?}",
+        lines
+    );
+    client.send("CAP END").await;
+    client.read_until(" 376 ").await;
+
+    client.send("CAP LIST").await;
+    let lines = client.read_until(" LIST ").await;
+    let list = lines.last().unwrap();
+    assert!(list.starts_with(":irc.test CAP capper LIST "), "{}", list);
+    assert!(list.contains("chghost") && list.contains("cap-notify"), "{}", list);
+    client.send("CAP BOGUS").await;
+    client.read_until(" 410 ").await;
+}
+
+#[tokio::test]
+async fn registration_without_cap_is_unaffected() {
+    let (_server, addr) = start_server("plain");
+    let mut client = TestClient::connect(addr).await;
+    client.send("NICK plain\r\nUSER plain 0 * :Cap").await;
+    client.read_until(" 376 ").await;
+    client.send("CAP LS").await;
+    let lines = client.read_until(" LS ").await;
+    let ls = lines.last().unwrap();
+    assert!(ls.starts_with(":irc.test CAP plain LS "), "{}", ls);
+    assert!(!ls.contains(" * "), "{}", ls);
+}
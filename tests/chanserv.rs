@@ -0,0 +1,116 @@
+//! Starts the server binary with a store and uses ChanServ, restarting the server to check
+//! registered channels outlive it.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::net::SocketAddr;
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain(
+        "chanserv",
+        name,
+        "store:\n  path: \"{dir}/data\"\n  snapshot_every: 4\n",
+    )
+}
+
+#[tokio::test]
+async fn registered_channels_outlive_the_server() {
+    let (mut server, addr) = start_server("restart");
+    let mut alice = TestClient::register(addr, "alice").await;
+    alice.send("PRIVMSG NickServ :REGISTER password1").await;
+    alice.read_until("alice is now registered").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    bob.send("PRIVMSG NickServ :REGISTER password2").await;
+    bob.read_until("bob is now registered").await;
+
+    alice.send("JOIN #fruit").await;
+    alice.read_until(" 366 ").await;
+    alice.send("TOPIC #fruit :Pawpaws are in season").await;
+    alice.read_until(" TOPIC #fruit :Pawpaws are in season").await;
+    alice.send("MODE #fruit +b *!*@spam.example").await;
+    alice.read_until(" MODE #fruit +b ").await;
+    alice.send("MODE #fruit +e *!*@friend.example").await;
+    alice.read_until(" MODE #fruit +e ").await;
+    alice.send("PRIVMSG ChanServ :REGISTER #fruit").await;
+    let lines = alice.read_until("NOTICE alice").await;
+    assert!(
+        lines.last().unwrap().starts_with(":ChanServ!ChanServ@irc.test NOTICE alice :#fruit is now registered to alice"),
+        "{:?}",
+        lines
+    );
+
+    bob.send("PRIVMSG ChanServ :ACCESS #fruit ADD bob op").await;
+    bob.read_until("Access denied for #fruit").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #fruit ADD bob op").await;
+    alice.read_until("bob now gets op in #fruit").await;
+    bob.send("NICK bobby\r\nPRIVMSG NickServ :GROUP").await;
+    bob.read_until("bobby is now grouped to bob").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #fruit DEL bobby").await;
+    alice.read_until("bob no longer gets status in #fruit").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #fruit ADD bobby op").await;
+    alice.read_until("bob now gets op in #fruit").await;
+    alice.send("PRIVMSG ChanServ :SET #fruit MLOCK +s-i").await;
+    let lines = alice.read_until("The mode lock of #fruit is now +s-i").await;
+    assert!(lines.iter().any(|l| l.starts_with(":irc.test MODE #fruit +s")), "{:?}", lines);
+    alice.send("MODE #fruit -s").await;
+    alice.read_until(" 742 #fruit s +s-i ").await;
+
+    server.restart();
+    let mut bob = TestClient::register(addr, "bob").await;
+    bob.send("PRIVMSG NickServ :IDENTIFY password2").await;
+    bob.read_until("You are now identified for bob").await;
+    bob.send("JOIN #fruit").await;
+    let lines = bob.read_until(" 366 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 332 #fruit :Pawpaws are in season")), "{:?}", lines);
+    assert!(lines.iter().any(|l| l.contains(" 353 #fruit :@bob")), "{:?}", lines);
+    bob.send("MODE #fruit b").await;
+    let lines = bob.read_until(" 368 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 367 #fruit *!*@spam.example")), "{:?}", lines);
+    bob.send("MODE #fruit e").await;
+    let lines = bob.read_until(" 349 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 348 #fruit *!*@friend.example")), "{:?}", lines);
+    bob.send("MODE #fruit").await;
+    bob.read_until(" 324 #fruit +nst").await;
+
+    let mut carol = TestClient::register(addr, "carol").await;
+    carol.send("JOIN #fruit").await;
+    let lines = carol.read_until(" 366 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 353 #fruit :@bob carol")), "{:?}", lines);
+    carol.send("TOPIC #fruit :Mine now").await;
+    carol.read_until(" 482 #fruit ").await;
+    carol.send("PRIVMSG ChanServ :REGISTER #fruit").await;
+    carol.read_until("You are not logged in").await;
+}
+
+#[tokio::test]
+async fn dropped_accounts_leave_no_status_behind() {
+    let (_server, addr) = start_server("drop");
+    let mut alice = TestClient::register(addr, "alice").await;
+    alice.send("PRIVMSG NickServ :REGISTER password1").await;
+    alice.read_until("alice is now registered").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    bob.send("PRIVMSG NickServ :REGISTER password2").await;
+    bob.read_until("bob is now registered").await;
+    alice.send("JOIN #fruit").await;
+    alice.read_until(" 366 ").await;
+    alice.send("PRIVMSG ChanServ :REGISTER #fruit").await;
+    alice.read_until("#fruit is now registered").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #fruit ADD bob op").await;
+    alice.read_until("bob now gets op in #fruit").await;
+
+    alice.send("PRIVMSG NickServ :DROP password1").await;
+    alice.read_until("alice still founds #fruit").await;
+    bob.send("PRIVMSG NickServ :DROP password2").await;
+    bob.read_until("bob has been dropped").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #fruit LIST").await;
+    let lines = alice.read_until("End of the access list").await;
+    assert!(!lines.iter().any(|line| line.contains(":bob op")), "{:?}", lines);
+
+    // Registering the name again doesn't bring the status back.
+    bob.send("PRIVMSG NickServ :REGISTER password3").await;
+    bob.read_until("bob is now registered").await;
+    bob.send("JOIN #fruit").await;
+    let lines = bob.read_until(" 366 ").await;
+    assert!(!lines.iter().any(|line| line.contains("@bob")), "{:?}", lines);
+}
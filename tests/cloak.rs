@@ -0,0 +1,112 @@
+//! Starts the server binary with cloaking enabled and checks what other users and
+//! operators get to see.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::net::SocketAddr;
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain(
+        "cloak",
+        name,
+        "cloak:\n  keys: [\"first secret\", \"second secret\"]\n\
+         opers:\n  plain:\n    password: \"secret\"\n  \
+         staff:\n    password: \"secret\"\n    privileges: [see-hidden]\n",
+    )
+}
+
+/// Registers as `nick` and returns the client along with the cloak it was given.
+async fn connect(addr: SocketAddr, nick: &str) -> (TestClient, String) {
+    let mut client = TestClient::connect(addr).await;
+    client
+        .send(&format!("NICK {0}\r\nUSER {0} 0 * :Cloak", nick))
+        .await;
+    let lines = client.read_until(" 376 ").await;
+    let hidden = lines
+        .iter()
+        .find(|line| line.contains(" 396 "))
+        .unwrap_or_else(|| panic!("no cloak in {:?}", lines));
+    let cloak = hidden.split(' ').nth(2).unwrap().to_owned();
+    (client, cloak)
+}
+
+#[tokio::test]
+async fn real_host_is_hidden_from_others() {
+    let (_server, addr) = start_server("whois");
+    let (mut alice, cloak) = connect(addr, "alice").await;
+    assert!(!cloak.contains("127.0.0.1"));
+    let (mut bob, _) = connect(addr, "bob").await;
+
+    bob.send("WHOIS alice").await;
+    let lines = bob.read_until(" 318 ").await;
+    assert!(lines.iter().any(|line| line.contains(" 311 ") && line.contains(&cloak)));
+    assert!(!lines.iter().any(|line| line.contains(" 378 ")), "{:?}", lines);
+
+    alice.send("WHOIS alice").await;
+    let lines = alice.read_until(" 318 ").await;
+    assert!(lines.iter().any(|line| line.contains(" 378 ")), "{:?}", lines);
+
+    bob.send("OPER plain secret").await;
+    bob.read_until(" 381 ").await;
+    bob.send("WHOIS alice").await;
+    let lines = bob.read_until(" 318 ").await;
+    assert!(!lines.iter().any(|line| line.contains(" 378 ")), "{:?}", lines);
+
+    let (mut carol, _) = connect(addr, "carol").await;
+    carol.send("OPER staff secret").await;
+    carol.read_until(" 381 ").await;
+    carol.send("WHOIS alice").await;
+    let lines = carol.read_until(" 318 ").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(" 378 ") && line.contains("127.0.0.1")),
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test]
+async fn unsetting_x_shows_the_real_host() {
+    let (_server, addr) = start_server("umode");
+    let (mut alice, _) = connect(addr, "alice").await;
+    alice.send("MODE alice").await;
+    let lines = alice.read_until(" 221 ").await;
+    assert!(lines.last().unwrap().contains("+x"));
+    alice.send("MODE alice -x").await;
+    let lines = alice.read_until(" 396 ").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(":alice!~alice@") && line.contains(" MODE alice -x")),
+        "{:?}",
+        lines
+    );
+    assert!(!lines.last().unwrap().contains(".IP"));
+}
+
+#[tokio::test]
+async fn bans_match_real_and_cloaked_hosts() {
+    let (_server, addr) = start_server("bans");
+    let (mut alice, _) = connect(addr, "alice").await;
+    let (mut bob, cloak) = connect(addr, "bob").await;
+    alice.send("JOIN #room").await;
+    alice.read_until(" 366 ").await;
+
+    for ban in ["*!*@127.0.0.1".to_string(), format!("*!*@{}", cloak)] {
+        alice.send(&format!("MODE #room +b {}", ban)).await;
+        alice.read_until(" MODE #room ").await;
+        bob.send("JOIN #room").await;
+        let lines = bob.read_until(" 474 ").await;
+        assert!(lines.last().unwrap().contains("#room"));
+        alice.send(&format!("MODE #room -b {}", ban)).await;
+        alice.read_until(" MODE #room ").await;
+    }
+
+    alice.send("MODE #room b").await;
+    let lines = alice.read_until(" 368 ").await;
+    assert!(!lines.iter().any(|line| line.contains(" 367 ")), "{:?}", lines);
+    bob.send("MODE #room +b *!*@*").await;
+    bob.read_until(" 482 ").await;
+}
@@ -0,0 +1,167 @@
+//! What the integration tests share: starting the server binary and talking IRC to it.
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// The server binary, killed along with its directory when dropped.
+pub struct TestServer {
+    child: Child,
+    pub dir: PathBuf,
+}
+
+impl TestServer {
+    /// A fresh directory for the server of test `name` in the `kind` test file.
+    pub fn dir(kind: &str, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pawpaw-{}-{}-{}", kind, name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `config` into `dir` and starts the server with it.
+    pub fn start(dir: PathBuf, config: &str) -> Self {
+        fs::write(dir.join("config.yml"), config).unwrap();
+        let child = Self::spawn(&dir);
+        TestServer { child, dir }
+    }
+
+    /// Starts the server of test `name` in the `suite` test file with one plain listener, ident
+    /// lookups off unless `extra` configures them, and the rest of its config from `extra`, where
+    /// `{dir}` stands for the server's directory.
+    pub fn plain(suite: &str, name: &str, extra: &str) -> (Self, SocketAddr) {
+        let dir = Self::dir(suite, name);
+        let addr = free_port();
+        let mut config = format!(
+            "hostname: \"irc.test\"\nmotd: \"hi\"\nlisteners:\n  plain:\n    address: {}\n",
+            addr,
+        );
+        if !extra.starts_with("ident:") && !extra.contains("\nident:") {
+            config.push_str("ident:\n  enabled: false\n");
+        }
+        config.push_str(&extra.replace("{dir}", &dir.display().to_string()));
+        (Self::start(dir, &config), addr)
+    }
+
+    fn spawn(dir: &Path) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_pawpaw"))
+            .arg("-c")
+            .arg(dir.join("config.yml"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    /// Kills the server without letting it shut down and starts it again.
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.child = Self::spawn(&self.dir);
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Connects to `addr`, waiting for the server to start listening.
+pub async fn connect_tcp(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(tcp) = TcpStream::connect(addr).await {
+            return tcp;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never started listening on {}", addr);
+}
+
+pub struct TestClient<S = TcpStream> {
+    stream: BufReader<S>,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        Self::new(connect_tcp(addr).await)
+    }
+
+    /// Connects and registers as `nick`, up to the end of the MOTD.
+    pub async fn register(addr: SocketAddr, nick: &str) -> Self {
+        let mut client = Self::connect(addr).await;
+        client.send(&format!("NICK {0}\r\nUSER {0} 0 * :{0}", nick)).await;
+        client.read_until(" 376 ").await;
+        client
+    }
+}
+
+impl<S> TestClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        TestClient {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        let line = format!("{}\r\n", line);
+        self.stream.get_mut().write_all(line.as_bytes()).await.unwrap();
+    }
+
+    /// Every line up to and including the first one containing `until`.
+    pub async fn read_until(&mut self, until: &str) -> Vec<String> {
+        let read = async {
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                self.stream.read_line(&mut line).await.unwrap();
+                assert!(!line.is_empty(), "connection closed after {:?}", lines);
+                let done = line.contains(until);
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), read)
+            .await
+            .unwrap()
+    }
+
+    /// Like `read_until`, but stops early if the server closes the connection.
+    pub async fn read_until_closed(&mut self, until: &str) -> Vec<String> {
+        let read = async {
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                if self.stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return lines;
+                }
+                let done = line.contains(until);
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), read)
+            .await
+            .unwrap()
+    }
+}
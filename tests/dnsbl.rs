@@ -0,0 +1,154 @@
+//! Starts the server binary with its resolver pointed at a local stand-in DNS server that
+//! lists 127.0.0.1 and 192.0.2.1 in a blocklist zone.
+
+mod common;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::{TestClient, TestServer};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, Record};
+use trust_dns_resolver::proto::serialize::binary::{BinDecodable, BinEncodable};
+
+const ZONE: &str = "dnsbl.test";
+const LISTED: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+/// Answers `1.0.0.127.dnsbl.test` and `1.2.0.192.dnsbl.test` with [`LISTED`] and
+/// everything else with NXDOMAIN.
+async fn stand_in_dns() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let listed = ["1.0.0.127", "1.2.0.192"]
+        .map(|ip| Name::from_ascii(format!("{}.{}.", ip, ZONE)).unwrap());
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = match Message::from_bytes(&buf[..len]) {
+                Ok(query) => query,
+                Err(_) => continue,
+            };
+            let mut reply = Message::new();
+            reply
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_desired(query.recursion_desired())
+                .set_recursion_available(true)
+                .add_queries(query.queries().to_vec());
+            match query.queries().first() {
+                Some(question) if listed.contains(question.name()) => {
+                    let name = question.name().clone();
+                    let record = Record::from_rdata(name, 60, RData::A(LISTED));
+                    reply.add_answer(record);
+                }
+                _ => {
+                    reply.set_response_code(ResponseCode::NXDomain);
+                }
+            }
+            socket.send_to(&reply.to_bytes().unwrap(), peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+async fn start_server(name: &str, action: &str) -> (TestServer, SocketAddr) {
+    let dns = stand_in_dns().await;
+    let extra = format!(
+        "opers:\n  admin:\n    password: \"secret\"\n\
+         accounts:\n  dnsbl4:\n    password: \"secret\"\n\
+         webirc:\n  gateway:\n    password: \"secret\"\n    hosts: [\"127.0.0.0/8\"]\n\
+         dns:\n  timeout: 2\n  nameservers: [\"{}\"]\n\
+         dnsbl:\n  zones:\n    test:\n      zone: \"{}\"\n      replies:\n        \"{}\": {}\n",
+        dns, ZONE, LISTED, action,
+    );
+    TestServer::plain("dnsbl", name, &extra)
+}
+
+/// Sends `lines` and returns everything received up to the first line containing `until`,
+/// or up to the connection closing.
+async fn exchange(addr: SocketAddr, lines: &str, until: &str) -> Vec<String> {
+    let mut client = TestClient::connect(addr).await;
+    client.send(lines.trim_end()).await;
+    client.read_until_closed(until).await
+}
+
+#[tokio::test]
+async fn listed_client_is_rejected() {
+    let (_server, addr) = start_server("reject", "reject").await;
+    let lines = exchange(addr, "NICK dnsbl1\r\nUSER dnsbl1 0 * :DNSBL\r\n", " 001 ").await;
+    let error = lines.iter().find(|line| line.contains("ERROR"));
+    assert!(
+        error.is_some_and(|line| line.contains("is listed in dnsbl.test")),
+        "{:?}",
+        lines
+    );
+    assert!(!lines.iter().any(|line| line.contains(" 001 ")));
+}
+
+#[tokio::test]
+async fn listed_client_must_use_sasl() {
+    let (_server, addr) = start_server("sasl", "require-sasl").await;
+    let lines = exchange(addr, "NICK dnsbl2\r\nUSER dnsbl2 0 * :DNSBL\r\n", " 001 ").await;
+    let error = lines.iter().find(|line| line.contains("ERROR"));
+    assert!(error.is_some_and(|line| line.contains("SASL")), "{:?}", lines);
+    assert!(!lines.iter().any(|line| line.contains(" 001 ")));
+}
+
+#[tokio::test]
+async fn listed_client_may_log_in_with_sasl() {
+    let (_server, addr) = start_server("sasl-login", "require-sasl").await;
+    let payload = STANDARD.encode("\0dnsbl4\0secret");
+    let lines = exchange(
+        addr,
+        &format!(
+            "CAP REQ sasl\r\nAUTHENTICATE PLAIN\r\nAUTHENTICATE {}\r\n\
+             NICK dnsbl4\r\nUSER dnsbl4 0 * :DNSBL\r\nCAP END\r\n",
+            payload
+        ),
+        " 376 ",
+    )
+    .await;
+    assert!(lines.iter().any(|line| line.contains(" 903 ")), "{:?}", lines);
+    assert!(lines.last().unwrap().contains(" 376 "), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn marked_client_shows_in_whois_to_opers() {
+    let (_server, addr) = start_server("mark", "mark").await;
+    let lines = exchange(
+        addr,
+        "NICK dnsbl3\r\nUSER dnsbl3 0 * :DNSBL\r\nOPER admin secret\r\nWHOIS dnsbl3\r\n",
+        " 318 ",
+    )
+    .await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(" 320 ") && line.contains("is marked: Your address 127.0.0.1")),
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test]
+async fn late_webirc_address_is_checked() {
+    let (_server, addr) = start_server("webirc", "mark").await;
+    let mut client = TestClient::connect(addr).await;
+    // Wait for the gateway's own address to be looked up before passing on the user's.
+    client.read_until("Looking up your hostname").await;
+    client.read_until("hostname").await;
+    client.send("WEBIRC secret gateway user.example 192.0.2.1").await;
+    client
+        .send("NICK dnsbl5\r\nUSER dnsbl5 0 * :DNSBL\r\nOPER admin secret\r\nWHOIS dnsbl5")
+        .await;
+    let lines = client.read_until(" 318 ").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(" 320 ") && line.contains("is marked: Your address 192.0.2.1")),
+        "{:?}",
+        lines
+    );
+}
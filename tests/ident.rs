@@ -0,0 +1,70 @@
+//! Starts the server binary with its ident lookups pointed at a local stand-in ident server.
+
+mod common;
+
+use common::{free_port, TestClient, TestServer};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+fn start_server(name: &str, ident_port: u16) -> (TestServer, SocketAddr) {
+    let extra = format!("ident:\n  port: {}\n  timeout: 2\n", ident_port);
+    TestServer::plain("ident", name, &extra)
+}
+
+/// Answers a single ident query for `user`.
+async fn stand_in_ident(user: &'static str) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut query = String::new();
+        stream.read_line(&mut query).await.unwrap();
+        let reply = format!("{} : USERID : UNIX : {}\r\n", query.trim(), user);
+        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+    });
+    port
+}
+
+/// Registers as `nick` and returns the username the server shows in WHOIS, along with
+/// every line received up to it.
+async fn registered_username(addr: SocketAddr, nick: &str) -> (String, Vec<String>) {
+    let mut client = TestClient::connect(addr).await;
+    client
+        .send(&format!("NICK {0}\r\nUSER {0} 0 * :Ident\r\nWHOIS {0}", nick))
+        .await;
+    let mut lines = client.read_until(" 311 ").await;
+    let whois = lines.pop().unwrap();
+    (whois.split(' ').nth(3).unwrap().to_owned(), lines)
+}
+
+#[tokio::test]
+async fn ident_reply_is_used_as_username() {
+    let ident_port = stand_in_ident("alice").await;
+    let (_server, addr) = start_server("reply", ident_port);
+    let (user, lines) = registered_username(addr, "ident1").await;
+    assert_eq!(user, "alice");
+    assert!(lines.iter().any(|line| line.contains("*** Got Ident response")));
+}
+
+#[tokio::test]
+async fn missing_ident_gets_a_tilde() {
+    // Nothing listens on this port, so the lookup is refused straight away.
+    let ident_port = free_port().port();
+    let (_server, addr) = start_server("missing", ident_port);
+    let (user, lines) = registered_username(addr, "ident2").await;
+    assert_eq!(user, "~ident2");
+    assert!(lines.iter().any(|line| line.contains("*** No Ident response")));
+}
+
+#[tokio::test]
+async fn tilde_counts_towards_the_username_length() {
+    let ident_port = free_port().port();
+    let (_server, addr) = start_server("long", ident_port);
+    let mut client = TestClient::connect(addr).await;
+    client
+        .send("NICK ident3\r\nUSER abcdefghijkl 0 * :Ident\r\nWHOIS ident3")
+        .await;
+    let lines = client.read_until(" 311 ").await;
+    assert_eq!(lines.last().unwrap().split(' ').nth(3), Some("~abcdefghi"));
+}
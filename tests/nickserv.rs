@@ -0,0 +1,120 @@
+//! Starts the server binary with account registration and uses NickServ and REGISTER.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::fs;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain(
+        "nickserv",
+        name,
+        "registration:\n  grace_period: 1\nstore:\n  path: \"{dir}/data\"\n",
+    )
+}
+
+#[tokio::test]
+async fn register_before_connecting_and_store_the_account() {
+    let (server, addr) = start_server("register");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP LS 302\r\nNICK carol\r\nUSER carol 0 * :NickServ").await;
+    let lines = client.read_until(" LS ").await;
+    let ls = lines.last().unwrap();
+    assert!(ls.contains("draft/account-registration=before-connect"), "{}", ls);
+    client.send("REGISTER * * short").await;
+    let lines = client.read_until("FAIL").await;
+    assert!(lines.last().unwrap().contains("FAIL REGISTER WEAK_PASSWORD carol"), "{:?}", lines);
+    client.send("REGISTER * carol@example.org password1").await;
+    let lines = client.read_until("REGISTER SUCCESS").await;
+    assert!(lines.iter().any(|l| l.contains(" 900 carol!carol@") && l.contains(" carol :")), "{:?}", lines);
+    client.send("CAP END").await;
+    client.read_until(" 376 ").await;
+    client.send("WHOIS carol").await;
+    let lines = client.read_until(" 318 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 330 carol carol ")), "{:?}", lines);
+
+    let stored = fs::read_to_string(server.dir.join("data").join("log.jsonl")).unwrap();
+    assert!(stored.contains(r#""name":"carol""#) && stored.contains("carol@example.org"), "{}", stored);
+    assert!(!stored.contains("password1"), "{}", stored);
+
+    let mut other = TestClient::register(addr, "someone").await;
+    other.send("REGISTER Carol * password2").await;
+    let lines = other.read_until("FAIL").await;
+    assert!(lines.last().unwrap().contains("FAIL REGISTER ACCOUNT_EXISTS Carol"), "{:?}", lines);
+    other.send("VERIFY someone 1234").await;
+    let lines = other.read_until("FAIL").await;
+    assert!(lines.last().unwrap().contains("FAIL VERIFY INVALID_CODE someone"), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn unidentified_users_lose_registered_nicks() {
+    let (_server, addr) = start_server("grace");
+    let mut owner = TestClient::register(addr, "dave").await;
+    owner.send("PRIVMSG NickServ :REGISTER password1").await;
+    let lines = owner.read_until("NOTICE dave").await;
+    assert!(lines.last().unwrap().starts_with(":NickServ!NickServ@irc.test NOTICE dave :dave is now registered"), "{:?}", lines);
+    owner.send("NICK dave_away").await;
+    owner.read_until(" NICK dave_away").await;
+    owner.send("PRIVMSG NickServ :GROUP").await;
+    owner.read_until("dave_away is now grouped to dave").await;
+    owner.send("QUIT").await;
+
+    let mut intruder = TestClient::register(addr, "dave").await;
+    intruder.read_until("dave is registered to dave").await;
+    let lines = intruder.read_until(" NICK Guest").await;
+    assert!(lines.last().unwrap().starts_with(":dave!"), "{:?}", lines);
+    intruder.read_until("your nick has been changed").await;
+
+    let mut owner = TestClient::register(addr, "dave_away").await;
+    owner.read_until("dave_away is registered to dave").await;
+    owner.send("PRIVMSG NickServ :IDENTIFY password1").await;
+    owner.read_until("You are now identified for dave").await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    owner.send("WHOIS dave_away").await;
+    let lines = owner.read_until(" 318 ").await;
+    assert!(lines.iter().any(|l| l.contains(" 330 dave_away dave ")), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn regain_disconnects_the_ghost() {
+    let (_server, addr) = start_server("regain");
+    let mut owner = TestClient::register(addr, "erin").await;
+    owner.send("PRIVMSG NickServ :REGISTER password1").await;
+    owner.read_until("erin is now registered").await;
+
+    let mut other = TestClient::register(addr, "frank").await;
+    other.send("PRIVMSG NickServ :GHOST erin wrong").await;
+    other.read_until("Access denied for erin").await;
+    other.send("PRIVMSG NickServ :REGAIN erin password1").await;
+    let lines = other.read_until("You have regained erin").await;
+    assert!(lines.iter().any(|l| l.starts_with(":frank!") && l.contains(" NICK erin")), "{:?}", lines);
+    assert!(lines.iter().any(|l| l.contains(" 900 ")), "{:?}", lines);
+    let lines = owner.read_until("ERROR").await;
+    assert!(lines.last().unwrap().contains("Killed (GHOST command used by frank)"), "{:?}", lines);
+
+    other.send("NICK NickServ").await;
+    other.read_until(" 433 NickServ").await;
+}
+
+#[tokio::test]
+async fn dropping_logs_out_every_session() {
+    let (_server, addr) = start_server("drop");
+    let mut first = TestClient::register(addr, "erin").await;
+    first.send("PRIVMSG NickServ :REGISTER password1").await;
+    first.read_until("erin is now registered").await;
+    let mut second = TestClient::register(addr, "erin_away").await;
+    second.send("PRIVMSG NickServ :IDENTIFY erin password1").await;
+    second.read_until("You are now identified for erin").await;
+
+    first.send("PRIVMSG NickServ :DROP password1").await;
+    first.read_until("erin has been dropped").await;
+    let lines = second.read_until(" 901 ").await;
+    assert!(lines.last().unwrap().contains(" 901 erin_away!"), "{:?}", lines);
+    second.send("WHOIS erin_away").await;
+    let lines = second.read_until(" 318 ").await;
+    assert!(!lines.iter().any(|line| line.contains(" 330 ")), "{:?}", lines);
+    second.send("PRIVMSG NickServ :DROP password1").await;
+    second.read_until("You are not logged in").await;
+}
@@ -0,0 +1,75 @@
+//! Starts the server binary with short ping timers and checks when it pings and what it
+//! takes to answer.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+fn start_server(name: &str, class: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain("ping", name, &format!("classes:\n  default:\n{}", class))
+}
+
+#[tokio::test]
+async fn traffic_puts_off_the_next_ping() {
+    let (_server, addr) = start_server("interval", "    ping_interval: 2\n    ping_timeout: 2\n");
+    let mut client = TestClient::register(addr, "busy").await;
+    // Talking every half second for longer than the interval keeps the server from pinging.
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        client.send("PING :still here").await;
+        let lines = client.read_until(" PONG ").await;
+        assert!(!lines.iter().any(|line| line.contains(" PING ")), "{:?}", lines);
+    }
+    let lines = client.read_until(" PING ").await;
+    assert!(lines.last().unwrap().contains("irc.test"), "{:?}", lines);
+    client.send("PONG :irc.test").await;
+
+    // Silence after answering is met with another PING, then a timeout.
+    client.read_until(" PING ").await;
+    let lines = client.read_until_closed(" ERROR ").await;
+    assert!(
+        lines.iter().any(|line| line.contains(" ERROR ") && line.contains("ping timeout")),
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test]
+async fn timeout_shorter_than_the_interval_is_kept() {
+    let (_server, addr) = start_server("short", "    ping_interval: 3\n    ping_timeout: 1\n");
+    let mut client = TestClient::register(addr, "quiet").await;
+    client.read_until(" PING ").await;
+    let pinged = Instant::now();
+    let lines = client.read_until_closed(" ERROR ").await;
+    assert!(lines.iter().any(|line| line.contains("ping timeout")), "{:?}", lines);
+    // Noticing the timeout only at the next interval would take three seconds.
+    assert!(pinged.elapsed() < Duration::from_secs(2), "{:?}", pinged.elapsed());
+}
+
+#[tokio::test]
+async fn registration_waits_for_the_cookie() {
+    let (_server, addr) = start_server(
+        "cookie",
+        "    ping_cookie: true\n    ping_cookie_timeout: 2\n",
+    );
+    let mut client = TestClient::connect(addr).await;
+    let lines = client.read_until(" PING ").await;
+    let cookie = lines.last().unwrap().trim_end().rsplit(' ').next().unwrap();
+    let cookie = cookie.trim_start_matches(':').to_owned();
+
+    // A PONG with anything but the cookie doesn't count.
+    client.send("NICK cookie\r\nUSER cookie 0 * :Cookie").await;
+    client.send("PONG :wrong\r\nPING :probe").await;
+    let lines = client.read_until("probe").await;
+    assert!(!lines.iter().any(|line| line.contains(" 001 ")), "{:?}", lines);
+    client.send(&format!("PONG :{}", cookie)).await;
+    client.read_until(" 376 ").await;
+
+    // A client that never echoes it is dropped once the cookie times out.
+    let mut silent = TestClient::connect(addr).await;
+    silent.send("NICK silent\r\nUSER silent 0 * :Silent").await;
+    let lines = silent.read_until_closed(" 001 ").await;
+    assert!(!lines.iter().any(|line| line.contains(" 001 ")), "{:?}", lines);
+}
@@ -0,0 +1,194 @@
+//! Starts the server binary with a few accounts and logs in to them with SASL.
+
+mod common;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::{TestClient, TestServer};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+/// Long enough that the PLAIN response takes two AUTHENTICATE chunks.
+const LONG_PASSWORD: &str = concat!(
+    "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz",
+    "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz",
+    "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz",
+    "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz",
+    "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz",
+);
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    let extra = format!(
+        "accounts:\n  Alice:\n    password: \"secret\"\n  long:\n    password: \"{}\"\n",
+        LONG_PASSWORD,
+    );
+    TestServer::plain("sasl", name, &extra)
+}
+
+/// Sends PLAIN credentials the way clients do, in chunks of 400 bytes.
+async fn authenticate_plain(client: &mut TestClient, account: &str, password: &str) {
+    client.send("AUTHENTICATE PLAIN").await;
+    client.read_until("AUTHENTICATE +").await;
+    let payload = STANDARD.encode(format!("\0{}\0{}", account, password));
+    let mut rest = payload.as_str();
+    while rest.len() >= 400 {
+        let (chunk, tail) = rest.split_at(400);
+        client.send(&format!("AUTHENTICATE {}", chunk)).await;
+        rest = tail;
+    }
+    let last = if rest.is_empty() { "+" } else { rest };
+    client.send(&format!("AUTHENTICATE {}", last)).await;
+}
+
+#[tokio::test]
+async fn plain_logs_in_during_registration() {
+    let (_server, addr) = start_server("plain");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP LS 302\r\nNICK alice\r\nUSER alice 0 * :Sasl").await;
+    let lines = client.read_until(" LS ").await;
+    assert!(lines.last().unwrap().contains("sasl=PLAIN,EXTERNAL,SCRAM-SHA-256"), "{:?}", lines);
+    client.send("CAP REQ sasl").await;
+    client.read_until(" ACK ").await;
+
+    authenticate_plain(&mut client, "alice", "wrong").await;
+    client.read_until(" 904 ").await;
+    authenticate_plain(&mut client, "alice", "secret").await;
+    let lines = client.read_until(" 903 ").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(" 900 alice!alice@") && line.contains(" Alice :")),
+        "{:?}",
+        lines
+    );
+    client.send("AUTHENTICATE PLAIN").await;
+    client.read_until(" 907 ").await;
+
+    client.send("CAP END").await;
+    client.read_until(" 376 ").await;
+    client.send("WHOIS alice").await;
+    let lines = client.read_until(" 318 ").await;
+    assert!(
+        lines.iter().any(|line| line.contains(" 330 alice Alice ")),
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test]
+async fn long_responses_come_in_chunks() {
+    let (_server, addr) = start_server("chunks");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP REQ sasl").await;
+    client.read_until(" ACK ").await;
+    authenticate_plain(&mut client, "long", LONG_PASSWORD).await;
+    client.read_until(" 903 ").await;
+}
+
+#[tokio::test]
+async fn wrong_passwords_are_limited() {
+    let (_server, addr) = start_server("limit");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP REQ sasl").await;
+    client.read_until(" ACK ").await;
+    for _ in 0..4 {
+        authenticate_plain(&mut client, "alice", "wrong").await;
+        client.read_until(" 904 ").await;
+    }
+    authenticate_plain(&mut client, "alice", "wrong").await;
+    let lines = client.read_until_closed(" ERROR ").await;
+    assert!(
+        lines.last().unwrap().contains("Too many failed login attempts"),
+        "{:?}",
+        lines
+    );
+}
+
+#[tokio::test]
+async fn exchanges_can_be_aborted() {
+    let (_server, addr) = start_server("abort");
+    let mut client = TestClient::connect(addr).await;
+    client.send("AUTHENTICATE PLAIN").await;
+    client.read_until(" 904 ").await;
+    client.send("CAP REQ sasl").await;
+    client.read_until(" ACK ").await;
+
+    client.send("AUTHENTICATE UNKNOWN").await;
+    let lines = client.read_until(" 904 ").await;
+    assert!(
+        lines.iter().any(|line| line.contains(" 908 PLAIN,EXTERNAL,SCRAM-SHA-256 ")),
+        "{:?}",
+        lines
+    );
+    client.send("AUTHENTICATE PLAIN").await;
+    client.read_until("AUTHENTICATE +").await;
+    client.send("AUTHENTICATE *").await;
+    client.read_until(" 906 ").await;
+
+    // Ending negotiation aborts an exchange still going on.
+    client.send("AUTHENTICATE EXTERNAL").await;
+    client.read_until("AUTHENTICATE +").await;
+    client.send("NICK bob\r\nUSER bob 0 * :Sasl\r\nCAP END").await;
+    let lines = client.read_until(" 376 ").await;
+    assert!(lines.iter().any(|line| line.contains(" 906 ")), "{:?}", lines);
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The payload of the next AUTHENTICATE line from the server, decoded.
+async fn read_challenge(client: &mut TestClient) -> String {
+    let lines = client.read_until("AUTHENTICATE ").await;
+    let payload = lines.last().unwrap().trim_end().rsplit(' ').next().unwrap().to_owned();
+    String::from_utf8(STANDARD.decode(payload).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn scram_logs_in_without_sending_the_password() {
+    let (_server, addr) = start_server("scram");
+    let mut client = TestClient::connect(addr).await;
+    client.send("CAP REQ sasl").await;
+    client.read_until(" ACK ").await;
+    client.send("AUTHENTICATE SCRAM-SHA-256").await;
+    client.read_until("AUTHENTICATE +").await;
+
+    let client_first_bare = "n=alice,r=fyko+d2lbbFgONRv9qkxdawL";
+    let client_first = format!("n,,{}", client_first_bare);
+    client.send(&format!("AUTHENTICATE {}", STANDARD.encode(&client_first))).await;
+    let server_first = read_challenge(&mut client).await;
+    let mut attributes = server_first.split(',');
+    let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+    let salt = STANDARD.decode(attributes.next().unwrap().strip_prefix("s=").unwrap()).unwrap();
+    let iterations: u32 = attributes.next().unwrap().strip_prefix("i=").unwrap().parse().unwrap();
+    assert!(nonce.starts_with("fyko+d2lbbFgONRv9qkxdawL"));
+
+    // Hi() from RFC 5802.
+    let mut block = salt.clone();
+    block.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac(b"secret", &block);
+    let mut salted = u.clone();
+    for _ in 1..iterations {
+        u = hmac(b"secret", &u);
+        salted.iter_mut().zip(&u).for_each(|(s, u)| *s ^= u);
+    }
+    let client_key = hmac(&salted, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let signature = hmac(&stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key.iter().zip(&signature).map(|(k, s)| k ^ s).collect();
+    let client_final = format!("{},p={}", without_proof, STANDARD.encode(proof));
+    client.send(&format!("AUTHENTICATE {}", STANDARD.encode(&client_final))).await;
+
+    let server_final = read_challenge(&mut client).await;
+    let server_key = hmac(&salted, b"Server Key");
+    let verifier = STANDARD.encode(hmac(&server_key, auth_message.as_bytes()));
+    assert_eq!(server_final, format!("v={}", verifier));
+    client.send("AUTHENTICATE +").await;
+    let lines = client.read_until(" 903 ").await;
+    assert!(lines.iter().any(|line| line.contains(" 900 ")), "{:?}", lines);
+}
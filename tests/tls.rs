@@ -0,0 +1,215 @@
+//! Starts the server binary with freshly generated certificates and completes a TLS
+//! handshake against it with every compiled in backend.
+#![cfg(any(feature = "native-tls", feature = "rustls"))]
+
+mod common;
+
+use common::{connect_tcp, free_port, TestClient, TestServer};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// Generates a CA and a `localhost` certificate signed by it, the leaf key is ECDSA P-256
+/// unless `key_alg` says otherwise. Returns the CA in DER form along with the leaf
+/// certificate and key as PEM.
+fn generate_certs(key_alg: Option<&'static rcgen::SignatureAlgorithm>) -> (Vec<u8>, String, String) {
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+    if let Some(alg) = key_alg {
+        params.alg = alg;
+    }
+    let leaf = Certificate::from_params(params).unwrap();
+    (
+        ca.serialize_der().unwrap(),
+        leaf.serialize_pem_with_signer(&ca).unwrap(),
+        leaf.serialize_private_key_pem(),
+    )
+}
+
+fn start_server(name: &str, backend: &str, cert: &str, key: &str, extra: &str) -> (TestServer, SocketAddr) {
+    start_listener(name, "true", backend, cert, key, extra)
+}
+
+fn start_listener(
+    name: &str,
+    tls: &str,
+    backend: &str,
+    cert: &str,
+    key: &str,
+    extra: &str,
+) -> (TestServer, SocketAddr) {
+    let dir = TestServer::dir("tls", name);
+    fs::write(dir.join("cert.pem"), cert).unwrap();
+    fs::write(dir.join("key.pem"), key).unwrap();
+    let addr = free_port();
+    let config = format!(
+        "hostname: \"irc.test\"\nmotd: \"hi\"\nlisteners:\n  tls:\n    address: {}\n    tls: {}\n    tls_cert: \"{}\"\n    tls_key: \"{}\"\n    tls_backend: {}\n{}",
+        addr,
+        tls,
+        dir.join("cert.pem").display(),
+        dir.join("key.pem").display(),
+        backend,
+        extra,
+    );
+    (TestServer::start(dir, &config), addr)
+}
+
+fn connector(
+    ca: Vec<u8>,
+    alpn: Vec<Vec<u8>>,
+    client_cert: Option<(rustls::Certificate, rustls::PrivateKey)>,
+) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(&rustls::Certificate(ca)).unwrap();
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match client_cert {
+        Some((cert, key)) => builder.with_single_cert(vec![cert], key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn;
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn connect(
+    addr: SocketAddr,
+    ca: Vec<u8>,
+    alpn: Vec<Vec<u8>>,
+    client_cert: Option<(rustls::Certificate, rustls::PrivateKey)>,
+) -> tokio_rustls::client::TlsStream<TcpStream> {
+    let tcp = connect_tcp(addr).await;
+    let name = ServerName::try_from("localhost").unwrap();
+    connector(ca, alpn, client_cert)
+        .connect(name, tcp)
+        .await
+        .unwrap()
+}
+
+async fn first_line(stream: tokio_rustls::client::TlsStream<TcpStream>) -> String {
+    TestClient::new(stream).read_until("\n").await.remove(0)
+}
+
+#[cfg(feature = "native-tls")]
+#[tokio::test]
+async fn native_tls_handshake() {
+    let (ca, cert, key) = generate_certs(None);
+    let (_server, addr) = start_server("native", "native-tls", &cert, &key, "");
+    let stream = connect(addr, ca, Vec::new(), None).await;
+    assert!(first_line(stream).await.starts_with(":irc.test NOTICE"));
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn rustls_handshake_with_alpn() {
+    let (ca, cert, key) = generate_certs(None);
+    let (_server, addr) = start_server(
+        "rustls",
+        "rustls",
+        &cert,
+        &key,
+        "    tls_alpn: [\"irc\"]\n    tls_min_version: \"1.3\"\n",
+    );
+    let stream = connect(addr, ca, vec![b"irc".to_vec()], None).await;
+    {
+        let (_, conn) = stream.get_ref();
+        assert_eq!(conn.alpn_protocol(), Some(&b"irc"[..]));
+        assert_eq!(conn.protocol_version(), Some(rustls::ProtocolVersion::TLSv1_3));
+    }
+    assert!(first_line(stream).await.starts_with(":irc.test NOTICE"));
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn rustls_ed25519_key() {
+    let (ca, cert, key) = generate_certs(Some(&rcgen::PKCS_ED25519));
+    let (_server, addr) = start_server("rustls-ed25519", "rustls", &cert, &key, "");
+    let stream = connect(addr, ca, Vec::new(), None).await;
+    assert!(first_line(stream).await.starts_with(":irc.test NOTICE"));
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn rustls_client_certfp_in_whois() {
+    use sha2::{Digest, Sha256};
+
+    let (ca, cert, key) = generate_certs(None);
+    let (_server, addr) = start_server("rustls-certfp", "rustls", &cert, &key, "");
+    let client = Certificate::from_params(CertificateParams::new(Vec::new())).unwrap();
+    let client_der = client.serialize_der().unwrap();
+    let client_key = rustls::PrivateKey(client.serialize_private_key_der());
+    let stream = connect(
+        addr,
+        ca,
+        Vec::new(),
+        Some((rustls::Certificate(client_der.clone()), client_key)),
+    )
+    .await;
+    let mut client = TestClient::new(stream);
+    client.send("NICK fp\r\nUSER fp 0 * :fp\r\nWHOIS fp").await;
+    let whois = client.read_until(" 318 ").await;
+    let fingerprint = hex::encode(Sha256::digest(&client_der));
+    assert!(whois.iter().any(|l| l.contains(" 671 fp ")));
+    assert!(whois
+        .iter()
+        .any(|l| l.contains(" 276 fp ") && l.trim_end().ends_with(&fingerprint)));
+}
+
+#[tokio::test]
+async fn rehash_applies_to_the_next_connection() {
+    let (ca, cert, key) = generate_certs(None);
+    let backend = if cfg!(feature = "rustls") { "rustls" } else { "native-tls" };
+    let opers = "opers:\n  admin:\n    password: \"secret\"\n";
+    let (server, addr) = start_server("rehash", backend, &cert, &key, opers);
+    let mut oper = TestClient::new(connect(addr, ca, Vec::new(), None).await);
+    oper.send("NICK op\r\nUSER op 0 * :op\r\nOPER admin secret").await;
+    oper.read_until(" 381 ").await;
+
+    let (ca, cert, key) = generate_certs(None);
+    fs::write(server.dir.join("cert.pem"), cert).unwrap();
+    fs::write(server.dir.join("key.pem"), key).unwrap();
+    oper.send("REHASH").await;
+    oper.read_until("Reloaded TLS certificate").await;
+    // The accept loop was already waiting for this connection when the certificate changed.
+    let stream = connect(addr, ca, Vec::new(), None).await;
+    assert!(first_line(stream).await.starts_with(":irc.test NOTICE"));
+}
+
+#[tokio::test]
+async fn starttls_drops_pipelined_plaintext() {
+    use tokio::io::AsyncReadExt;
+
+    let (ca, cert, key) = generate_certs(None);
+    let backend = if cfg!(feature = "rustls") { "rustls" } else { "native-tls" };
+    let (_server, addr) = start_listener("starttls", "false", backend, &cert, &key, "");
+    let mut tcp = connect_tcp(addr).await;
+    tcp.write_all(b"STARTTLS\r\nBOGUS injected\r\n").await.unwrap();
+    // Read byte by byte so nothing after the 670 line is taken from the TLS stream.
+    let mut plaintext = Vec::new();
+    while !String::from_utf8_lossy(&plaintext).contains(" 670 ") || !plaintext.ends_with(b"\r\n") {
+        let byte = tokio::time::timeout(Duration::from_secs(5), tcp.read_u8())
+            .await
+            .unwrap()
+            .unwrap();
+        plaintext.push(byte);
+    }
+    let name = ServerName::try_from("localhost").unwrap();
+    let stream = connector(ca, Vec::new(), None)
+        .connect(name, tcp)
+        .await
+        .unwrap();
+    let mut client = TestClient::new(stream);
+    client.send("NICK st\r\nUSER st 0 * :st\r\nWHOIS st").await;
+    let lines = client.read_until(" 318 ").await;
+    assert!(!lines.iter().any(|l| l.contains("BOGUS")));
+    assert!(lines.iter().any(|l| l.contains(" 671 st ")));
+}
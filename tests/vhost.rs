@@ -0,0 +1,124 @@
+//! Starts the server binary with configured vhosts and changes hosts at runtime, checking
+//! what channel members are told.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use std::net::SocketAddr;
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    TestServer::plain(
+        "vhost",
+        name,
+        "vhosts:\n  - host: \"staff.example\"\n    masks: [\"boss!*@*\"]\n  \
+         - host: \"later.example\"\n    masks: [\"b*!*@*\"]\n\
+         opers:\n  plain:\n    password: \"secret\"\n  \
+         hosts:\n    password: \"secret\"\n    privileges: [set-host]\n",
+    )
+}
+
+/// Sends `before` ahead of registering as `nick`, then waits for the end of the MOTD.
+async fn connect(addr: SocketAddr, nick: &str, before: &str) -> (TestClient, Vec<String>) {
+    let mut client = TestClient::connect(addr).await;
+    client
+        .send(&format!("{}NICK {1}\r\nUSER {1} 0 * :Vhost", before, nick))
+        .await;
+    let lines = client.read_until(" 376 ").await;
+    (client, lines)
+}
+
+#[tokio::test]
+async fn configured_vhost_is_applied_on_connect() {
+    let (_server, addr) = start_server("config");
+    let (_boss, lines) = connect(addr, "boss", "").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(" 396 staff.example ")),
+        "{:?}",
+        lines
+    );
+    // Only the first of the matching vhosts counts.
+    assert!(!lines.iter().any(|line| line.contains("later.example")), "{:?}", lines);
+    let (mut other, lines) = connect(addr, "other", "").await;
+    assert!(!lines.iter().any(|line| line.contains(" 396 ")), "{:?}", lines);
+    other.send("WHOIS boss").await;
+    let lines = other.read_until(" 318 ").await;
+    assert!(lines.iter().any(|line| line.contains(" 311 boss ~boss staff.example ")));
+}
+
+#[tokio::test]
+async fn channel_members_are_told_about_host_changes() {
+    let (_server, addr) = start_server("chghost");
+    let (mut oper, _) = connect(addr, "oper", "").await;
+    let (mut target, _) = connect(addr, "target", "").await;
+    let (mut modern, lines) =
+        connect(addr, "modern", "CAP REQ :chghost\r\nCAP END\r\n").await;
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains("CAP * ACK") && line.contains("chghost")),
+        "{:?}",
+        lines
+    );
+    let (mut legacy, _) = connect(addr, "legacy", "").await;
+    for client in [&mut target, &mut modern, &mut legacy] {
+        client.send("JOIN #room").await;
+        client.read_until(" 366 ").await;
+    }
+
+    oper.send("OPER plain secret").await;
+    oper.read_until(" 381 ").await;
+    oper.send("CHGHOST target new.example").await;
+    oper.read_until(" 481 ").await;
+
+    oper.send("OPER hosts secret").await;
+    oper.read_until(" 381 ").await;
+    oper.send("CHGHOST target new.example").await;
+
+    let lines = target.read_until(" 396 ").await;
+    assert!(lines.last().unwrap().contains("new.example"));
+    let lines = modern.read_until(" CHGHOST ").await;
+    let chghost = lines.last().unwrap();
+    assert!(chghost.starts_with(":target!~target@"), "{}", chghost);
+    assert!(chghost.contains(" CHGHOST ~target new.example"), "{}", chghost);
+    let lines = legacy.read_until(" JOIN ").await;
+    assert!(lines.iter().any(|line| line.starts_with(":target!") && line.contains(" QUIT ")));
+    assert!(lines
+        .last()
+        .unwrap()
+        .starts_with(":target!~target@new.example JOIN #room"));
+    // The channel creator regains its operator status.
+    let lines = legacy.read_until(" MODE ").await;
+    assert!(lines.last().unwrap().contains("MODE #room +o target"));
+}
+
+#[tokio::test]
+async fn voice_is_restored_after_a_host_change() {
+    let (_server, addr) = start_server("voice");
+    let (mut alice, _) = connect(addr, "alice", "").await;
+    alice.send("PRIVMSG NickServ :REGISTER password1").await;
+    alice.read_until("alice is now registered").await;
+    let (mut voiced, _) = connect(addr, "voiced", "").await;
+    voiced.send("PRIVMSG NickServ :REGISTER password2").await;
+    voiced.read_until("voiced is now registered").await;
+
+    alice.send("JOIN #room").await;
+    alice.read_until(" 366 ").await;
+    alice.send("PRIVMSG ChanServ :REGISTER #room").await;
+    alice.read_until("#room is now registered").await;
+    alice.send("PRIVMSG ChanServ :ACCESS #room ADD voiced voice").await;
+    alice.read_until("voiced now gets voice in #room").await;
+    voiced.send("JOIN #room").await;
+    let lines = voiced.read_until(" 366 ").await;
+    assert!(lines.iter().any(|line| line.contains(" +voiced")), "{:?}", lines);
+
+    let (mut oper, _) = connect(addr, "oper", "").await;
+    oper.send("OPER hosts secret").await;
+    oper.read_until(" 381 ").await;
+    oper.send("CHGHOST voiced new.example").await;
+    let lines = alice.read_until(" JOIN ").await;
+    assert!(lines.last().unwrap().starts_with(":voiced!~voiced@new.example JOIN #room"));
+    let lines = alice.read_until(" MODE ").await;
+    assert!(lines.last().unwrap().contains("MODE #room +v voiced"), "{:?}", lines);
+}
@@ -0,0 +1,113 @@
+//! Starts the server binary with a WebSocket listener and talks IRC to it over frames.
+
+mod common;
+
+use common::{connect_tcp, free_port, TestServer};
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+fn start_server(name: &str) -> (TestServer, SocketAddr) {
+    start_server_with(name, "")
+}
+
+fn start_server_with(name: &str, listener: &str) -> (TestServer, SocketAddr) {
+    let addr = free_port();
+    let config = format!(
+        "hostname: \"irc.test\"\nmotd: \"hi\"\nlisteners:\n  ws:\n    address: {}\n    websocket: true\n    websocket_origins: [\"https://web.test\"]\n{}",
+        addr, listener,
+    );
+    (TestServer::start(TestServer::dir("ws", name), &config), addr)
+}
+
+async fn connect(
+    addr: SocketAddr,
+    protocol: &str,
+    origin: &str,
+) -> Result<(WebSocketStream<TcpStream>, Option<String>), Error> {
+    let tcp = connect_tcp(addr).await;
+    let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+    let headers = request.headers_mut();
+    headers.insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+    headers.insert("Origin", origin.parse().unwrap());
+    let (ws, response) = client_async(request, tcp).await?;
+    let protocol = response
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .map(|value| value.to_str().unwrap().to_owned());
+    Ok((ws, protocol))
+}
+
+#[tokio::test]
+async fn text_frames_carry_single_lines() {
+    let (_server, addr) = start_server("text");
+    let (mut ws, protocol) = connect(addr, "text.ircv3.net", "https://web.test")
+        .await
+        .unwrap();
+    assert_eq!(protocol.as_deref(), Some("text.ircv3.net"));
+    ws.send(Message::Text("NICK ws".into())).await.unwrap();
+    ws.send(Message::Text("USER ws 0 * :WebSocket\r\n".into()))
+        .await
+        .unwrap();
+    let motd = async {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Text(line) => {
+                    assert!(!line.ends_with('\n'), "{:?}", line);
+                    if line.contains(" 376 ") {
+                        break;
+                    }
+                }
+                other => panic!("expected a text frame, got {:?}", other),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), motd)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn foreign_origin_is_refused() {
+    let (_server, addr) = start_server("origin");
+    match connect(addr, "text.ircv3.net", "https://evil.test").await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("expected a 403, got {:?}", other.map(|(_, protocol)| protocol)),
+    }
+}
+
+#[tokio::test]
+async fn oversized_frames_close_the_connection() {
+    let (_server, addr) = start_server("oversized");
+    let (mut ws, _) = connect(addr, "text.ircv3.net", "https://web.test")
+        .await
+        .unwrap();
+    let line = format!("PRIVMSG x :{}", "a".repeat(64 * 1024));
+    let _ = ws.send(Message::Text(line)).await;
+    let closed = async {
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), closed)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn handshakes_past_the_limit_wait_to_be_accepted() {
+    let (_server, addr) = start_server_with("stalled", "    handshake_timeout: 2\n    max_handshakes: 1\n");
+    let _stalled = connect_tcp(addr).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // The only slot is taken until the stalled handshake times out.
+    let started = Instant::now();
+    connect(addr, "text.ircv3.net", "https://web.test").await.unwrap();
+    assert!(started.elapsed() > Duration::from_secs(1), "{:?}", started.elapsed());
+}